@@ -1,80 +1,486 @@
+#[cfg(feature = "multi-currency")]
+use std::collections::HashMap;
+
+use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 
-use crate::transaction::{Amount, ClientID};
+use crate::rounding::LEDGER_ROUNDING;
+#[cfg(feature = "serde")]
+use crate::transaction::format_amount;
+use crate::transaction::{Amount, ClientID, CurrencyCode};
 use crate::AccountingError;
 
-#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+// Balance operations shared by the account's own balance and its per-currency balances.
+// An operation either applies completely or fails without changing the balance: amounts that would
+// overflow a balance fail with Overflow, and operations that would take a balance below zero (e.g.
+// resolving more than is held) with NegativeBalance
+pub trait Funds: Send {
+    fn available(&self) -> Amount;
+    fn deposit(&mut self, amount: Amount) -> Result<(), AccountingError>;
+    fn withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError>;
+    fn dispute(&mut self, amount: Amount) -> Result<(), AccountingError>;
+    fn resolve(&mut self, amount: Amount) -> Result<(), AccountingError>;
+    // Only moves the funds, whether the account is locked is up to the ChargebackLockPolicy
+    fn chargeback(&mut self, amount: Amount) -> Result<(), AccountingError>;
+    // Disputes of withdrawals, see DisputeKind::Withdrawal
+    fn dispute_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError>;
+    fn resolve_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError>;
+    fn chargeback_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError>;
+    // Two-phase withdrawals move funds to pending_out first, and only leave the account once settled
+    fn reserve_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError>;
+    fn settle(&mut self, amount: Amount) -> Result<(), AccountingError>;
+    fn cancel_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError>;
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Account {
     pub client: ClientID,
-    available: Amount,
-    held: Amount,
-    total: Amount,
+    // The balance of amounts without a currency, see funds()
+    balance: CurrencyBalance,
     pub locked: bool,
-}
-
-impl Default for Account {
-    fn default() -> Self {
-        Account {
-            client: 0,
-            available: dec!(0),
-            held: dec!(0),
-            total: dec!(0),
-            locked: false,
-        }
-    }
+    // Cumulative deposits, only tracked when a deposit volume limit is set
+    pub(crate) deposit_volume: Amount,
+    pub(crate) volume_flagged: bool,
+    // Chargebacks applied to the account, see ChargebackLockPolicy
+    pub(crate) chargebacks: u32,
+    // Balances in explicit currencies, amounts without a currency use the balance above
+    #[cfg(feature = "multi-currency")]
+    pub balances: HashMap<CurrencyCode, CurrencyBalance>,
 }
 
 impl Account {
-    #[allow(dead_code)]
     pub fn new(client: ClientID, available: Amount, held: Amount, total: Amount) -> Self {
         Account {
             client,
-            available,
-            held,
-            total,
-            locked: false,
+            balance: CurrencyBalance {
+                available: canon(available),
+                held: canon(held),
+                total: canon(total),
+                ..Default::default()
+            },
+            ..Default::default()
         }
     }
 
-    pub fn deposit(&mut self, amount: Amount) {
-        self.available += amount;
-        self.total += amount;
+    // The balance that transactions in the given currency operate on. Without the multi-currency
+    // feature there is only one balance and the currency is ignored
+    pub fn funds(&mut self, currency: Option<CurrencyCode>) -> &mut dyn Funds {
+        match currency {
+            #[cfg(feature = "multi-currency")]
+            Some(currency) => self.balances.entry(currency).or_default(),
+            _ => &mut self.balance,
+        }
     }
 
-    pub fn withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
-        if self.available - amount >= dec!(0) {
-            self.available -= amount;
-            self.total -= amount;
-            return Ok(());
+    pub fn available(&self) -> Amount {
+        self.balance.available
+    }
+
+    pub fn held(&self) -> Amount {
+        self.balance.held
+    }
+
+    // The total is always the available and held funds plus withdrawals pending settlement, in every currency
+    pub fn check_invariant(&self) -> Result<(), AccountingError> {
+        let balances = std::iter::once((None::<&CurrencyCode>, &self.balance));
+        #[cfg(feature = "multi-currency")]
+        let balances = balances.chain(
+            self.balances
+                .iter()
+                .map(|(currency, balance)| (Some(currency), balance)),
+        );
+        for (currency, balance) in balances {
+            if Some(balance.total)
+                != checked_sum(&[balance.available, balance.held, balance.pending_out])
+            {
+                let client = match currency {
+                    Some(currency) => format!("client {} in {}", self.client, currency),
+                    None => format!("client {}", self.client),
+                };
+                return Err(AccountingError::AccountInvariantViolation(format!(
+                    "{}: total {} != available {} + held {} + pending {}",
+                    client, balance.total, balance.available, balance.held, balance.pending_out
+                )));
+            }
         }
-        Err(AccountingError::Withdrawal)
+        Ok(())
+    }
+
+    // Held funds of the balance in the given currency, see funds()
+    pub fn held_in(&self, currency: Option<CurrencyCode>) -> Amount {
+        match currency {
+            #[cfg(feature = "multi-currency")]
+            Some(currency) => self
+                .balances
+                .get(&currency)
+                .map(|balance| balance.held)
+                .unwrap_or_default(),
+            _ => self.balance.held,
+        }
+    }
+
+    pub fn total(&self) -> Amount {
+        self.balance.total
+    }
+
+    pub fn pending_out(&self) -> Amount {
+        self.balance.pending_out
+    }
+
+    // Cumulative deposits, zero unless a deposit volume limit is set
+    pub fn deposit_volume(&self) -> Amount {
+        self.deposit_volume
+    }
+
+    // Whether the deposits went over the deposit volume limit
+    pub fn volume_flagged(&self) -> bool {
+        self.volume_flagged
+    }
+
+    pub fn chargebacks(&self) -> u32 {
+        self.chargebacks
+    }
+
+    // The operations below apply to the balance without a currency, see Funds for the rules
+    pub fn deposit(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        self.balance.deposit(amount)
+    }
+
+    pub fn withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        self.balance.withdrawal(amount)
     }
 
     // Logic around existing tx etc. should be handled elsewhere
     pub fn dispute(&mut self, amount: Amount) -> Result<(), AccountingError> {
-        if self.available >= amount {
-            self.held += amount;
-            self.available -= amount;
-        } else {
+        self.balance.dispute(amount)
+    }
+
+    pub fn resolve(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        self.balance.resolve(amount)
+    }
+
+    pub fn chargeback(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        self.balance.chargeback(amount)
+    }
+
+    pub fn dispute_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        self.balance.dispute_withdrawal(amount)
+    }
+
+    pub fn resolve_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        self.balance.resolve_withdrawal(amount)
+    }
+
+    pub fn chargeback_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        self.balance.chargeback_withdrawal(amount)
+    }
+
+    pub fn reserve_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        self.balance.reserve_withdrawal(amount)
+    }
+
+    pub fn settle(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        self.balance.settle(amount)
+    }
+
+    pub fn cancel_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        self.balance.cancel_withdrawal(amount)
+    }
+
+    // Credits simple interest on the available funds for the period, returns the amount credited
+    pub fn apply_interest(
+        &mut self,
+        rate: Decimal,
+        period_days: u32,
+    ) -> Result<Amount, AccountingError> {
+        let yearly = self
+            .available()
+            .checked_mul(rate)
+            .and_then(|yearly| yearly.checked_mul(Decimal::from(period_days)))
+            .ok_or(AccountingError::Overflow)?;
+        let interest = LEDGER_ROUNDING.round(yearly / dec!(365));
+        self.deposit(interest)?;
+        Ok(interest)
+    }
+}
+
+// The csv and json output: the client, the balance without a currency and whether the account is locked.
+// Pending withdrawals, the deposit volume, chargebacks and the balances in explicit currencies are left out
+#[cfg(feature = "serde")]
+impl serde::Serialize for Account {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        // Amounts are written with four decimal places in every format
+        let mut account = serializer.serialize_struct("Account", 5)?;
+        account.serialize_field("client", &self.client)?;
+        account.serialize_field("available", &format_amount(self.balance.available))?;
+        account.serialize_field("held", &format_amount(self.balance.held))?;
+        account.serialize_field("total", &format_amount(self.balance.total))?;
+        account.serialize_field("locked", &self.locked)?;
+        account.end()
+    }
+}
+
+// Every stored amount goes through this, so two sequences of operations that end in the same
+// balance also store the same Decimal, scale included
+fn canon(amount: Amount) -> Amount {
+    LEDGER_ROUNDING.canonical(amount)
+}
+
+// The new value of a balance, which may not overflow or go below zero
+fn add(balance: Amount, amount: Amount) -> Result<Amount, AccountingError> {
+    let sum = balance
+        .checked_add(amount)
+        .ok_or(AccountingError::Overflow)?;
+    if sum < dec!(0) {
+        return Err(AccountingError::NegativeBalance);
+    }
+    Ok(canon(sum))
+}
+
+fn sub(balance: Amount, amount: Amount) -> Result<Amount, AccountingError> {
+    add(balance, -amount)
+}
+
+// Balances that can't be added up can't add up to the total either
+fn checked_sum(amounts: &[Amount]) -> Option<Amount> {
+    amounts
+        .iter()
+        .try_fold(dec!(0), |sum, amount| sum.checked_add(*amount))
+}
+
+// The funds of an account in one currency, or without a currency
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurrencyBalance {
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    // Withdrawn funds awaiting settlement, still part of the total
+    pub pending_out: Amount,
+}
+
+impl Default for CurrencyBalance {
+    fn default() -> Self {
+        CurrencyBalance {
+            available: canon(dec!(0)),
+            held: canon(dec!(0)),
+            total: canon(dec!(0)),
+            pending_out: canon(dec!(0)),
+        }
+    }
+}
+
+// Locking on chargeback is left to the account
+impl Funds for CurrencyBalance {
+    fn available(&self) -> Amount {
+        self.available
+    }
+
+    fn deposit(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        let available = add(self.available, amount)?;
+        self.total = add(self.total, amount)?;
+        self.available = available;
+        Ok(())
+    }
+
+    fn withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        if self.available < amount {
+            return Err(AccountingError::Withdrawal);
+        }
+        let available = sub(self.available, amount)?;
+        self.total = sub(self.total, amount)?;
+        self.available = available;
+        Ok(())
+    }
+
+    fn dispute(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        if self.available < amount {
             return Err(AccountingError::Dispute);
         }
+        let held = add(self.held, amount)?;
+        self.available = sub(self.available, amount)?;
+        self.held = held;
         Ok(())
     }
 
-    pub fn resolve(&mut self, amount: Amount) {
-        self.held -= amount;
-        self.available += amount;
+    fn resolve(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        let held = sub(self.held, amount)?;
+        self.available = add(self.available, amount)?;
+        self.held = held;
+        Ok(())
+    }
+
+    fn chargeback(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        let held = sub(self.held, amount)?;
+        self.total = sub(self.total, amount)?;
+        self.held = held;
+        Ok(())
+    }
+
+    // The withdrawn funds are no longer in the account, so they are held on top of the balance
+    fn dispute_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        let held = add(self.held, amount)?;
+        self.total = add(self.total, amount)?;
+        self.held = held;
+        Ok(())
+    }
+
+    // The withdrawal stands
+    fn resolve_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        let held = sub(self.held, amount)?;
+        self.total = sub(self.total, amount)?;
+        self.held = held;
+        Ok(())
+    }
+
+    // The withdrawal is reversed and the funds are returned to the client
+    fn chargeback_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        let held = sub(self.held, amount)?;
+        self.available = add(self.available, amount)?;
+        self.held = held;
+        Ok(())
+    }
+
+    fn reserve_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        if self.available < amount {
+            return Err(AccountingError::Withdrawal);
+        }
+        let available = sub(self.available, amount)?;
+        self.pending_out = add(self.pending_out, amount)?;
+        self.available = available;
+        Ok(())
+    }
+
+    fn settle(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        let pending_out = sub(self.pending_out, amount)?;
+        self.total = sub(self.total, amount)?;
+        self.pending_out = pending_out;
+        Ok(())
+    }
+
+    fn cancel_withdrawal(&mut self, amount: Amount) -> Result<(), AccountingError> {
+        let pending_out = sub(self.pending_out, amount)?;
+        self.available = add(self.available, amount)?;
+        self.pending_out = pending_out;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stored_amounts_are_canonical() {
+        let mut deposit_only = Account::default();
+        deposit_only.deposit(dec!(3.3333)).unwrap();
+
+        let mut disputed = Account::default();
+        disputed.deposit(dec!(3.3333)).unwrap();
+        disputed.dispute(dec!(3.3333)).unwrap();
+        disputed.resolve(dec!(3.3333)).unwrap();
+
+        let mut mixed_scales = Account::default();
+        mixed_scales.deposit(dec!(5)).unwrap();
+        mixed_scales.withdrawal(dec!(1.6667)).unwrap();
+
+        // Compare the stored representation, not just the value
+        for account in [&disputed, &mixed_scales] {
+            assert_eq!(
+                account.available().serialize(),
+                deposit_only.available().serialize()
+            );
+            assert_eq!(account.held().serialize(), deposit_only.held().serialize());
+            assert_eq!(
+                account.total().serialize(),
+                deposit_only.total().serialize()
+            );
+        }
+        assert_eq!(deposit_only.available().scale(), 4);
+        assert_eq!(
+            Account::new(1, dec!(1.5), dec!(0), dec!(1.5))
+                .total()
+                .serialize(),
+            Account::new(1, dec!(1.50000), dec!(0.0), dec!(1.5000))
+                .total()
+                .serialize()
+        );
+    }
+
+    #[test]
+    fn test_check_invariant() {
+        let mut account = Account::new(1, dec!(1), dec!(2), dec!(3));
+        account.withdrawal(dec!(0.5)).unwrap();
+        account.dispute(dec!(0.5)).unwrap();
+        assert!(account.check_invariant().is_ok());
+
+        let invalid = Account::new(1, dec!(1), dec!(1), dec!(5));
+        match invalid.check_invariant() {
+            Err(AccountingError::AccountInvariantViolation(message)) => {
+                assert!(message.contains("client 1"), "{}", message)
+            }
+            other => panic!("expected an invariant violation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_overflow_leaves_balance_unchanged() {
+        let mut account = Account::default();
+        account.deposit(Decimal::MAX).unwrap();
+        let before = account.clone();
+        assert!(matches!(
+            account.deposit(Decimal::MAX),
+            Err(AccountingError::Overflow)
+        ));
+        assert!(matches!(
+            account.deposit(dec!(1)),
+            Err(AccountingError::Overflow)
+        ));
+        assert!(matches!(
+            account.apply_interest(dec!(2), 365),
+            Err(AccountingError::Overflow)
+        ));
+        assert_eq!(account, before);
+
+        // Holding a withdrawal on top of a full balance overflows the total, not just held
+        account.withdrawal(dec!(1)).unwrap();
+        account.dispute(Decimal::MAX - dec!(2)).unwrap();
+        let before = account.clone();
+        assert!(matches!(
+            account.dispute_withdrawal(dec!(2)),
+            Err(AccountingError::Overflow)
+        ));
+        assert_eq!(account, before);
+        assert!(account.check_invariant().is_ok());
     }
 
-    pub fn chargeback(&mut self, amount: Amount) {
-        self.held -= amount;
-        self.total -= amount;
-        self.locked = true;
+    #[test]
+    fn test_negative_balance() {
+        let mut account = Account::new(1, dec!(5), dec!(1), dec!(6));
+        let before = account.clone();
+        assert!(matches!(
+            account.resolve(dec!(2)),
+            Err(AccountingError::NegativeBalance)
+        ));
+        assert!(matches!(
+            account.chargeback(dec!(2)),
+            Err(AccountingError::NegativeBalance)
+        ));
+        assert!(matches!(
+            account.settle(dec!(1)),
+            Err(AccountingError::NegativeBalance)
+        ));
+        // A failed chargeback doesn't lock the account either
+        assert_eq!(account, before);
     }
 
-    pub fn normalize_values(&mut self) {
-        self.available = self.available.normalize();
-        self.held = self.held.normalize();
-        self.total = self.total.normalize();
+    #[test]
+    fn test_check_invariant_near_max() {
+        let account = Account::new(1, Decimal::MAX, Decimal::MAX, Decimal::MAX);
+        assert!(matches!(
+            account.check_invariant(),
+            Err(AccountingError::AccountInvariantViolation(_))
+        ));
     }
 }
@@ -0,0 +1,150 @@
+use std::ops::AddAssign;
+
+use crate::transaction::{Amount, DisputeKind};
+#[cfg(feature = "serde")]
+use crate::{error::source, transaction::format_amount, AccountingError};
+
+// Number and sum of the money movements of one type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Volume {
+    pub count: usize,
+    pub total: Amount,
+}
+
+impl Volume {
+    fn record(&mut self, amount: Amount) {
+        self.count += 1;
+        self.total = self.total.saturating_add(amount);
+    }
+}
+
+impl AddAssign for Volume {
+    fn add_assign(&mut self, other: Self) {
+        self.count += other.count;
+        self.total = self.total.saturating_add(other.total);
+    }
+}
+
+// Volume of the money moved by the applied transactions, kept up to date while processing. Transactions
+// carry no timestamps, so everything goes into a single bucket for the run. Only amounts in the account's
+// default currency are counted, amounts in different currencies can't be added up. The sums of all clients
+// can exceed what a single balance can hold, so they saturate rather than failing the transaction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VolumeAggregates {
+    pub deposits: Volume,
+    // Counted once the funds leave the account, for a two-phase withdrawal that is when it is settled
+    pub withdrawals: Volume,
+    pub chargebacks: Volume,
+    // Money into the accounts minus money out of them. A chargeback of a deposit takes money out,
+    // one of a withdrawal returns it
+    pub net_flow: Amount,
+}
+
+impl VolumeAggregates {
+    pub fn deposit(&mut self, amount: Amount) {
+        self.deposits.record(amount);
+        self.net_flow = self.net_flow.saturating_add(amount);
+    }
+
+    pub fn withdrawal(&mut self, amount: Amount) {
+        self.withdrawals.record(amount);
+        self.net_flow = self.net_flow.saturating_sub(amount);
+    }
+
+    pub fn chargeback(&mut self, amount: Amount, kind: DisputeKind) {
+        self.chargebacks.record(amount);
+        self.net_flow = match kind {
+            DisputeKind::Deposit => self.net_flow.saturating_sub(amount),
+            DisputeKind::Withdrawal => self.net_flow.saturating_add(amount),
+        };
+    }
+
+    // Writes the aggregates as csv, one row per bucket
+    #[cfg(feature = "serde")]
+    pub fn write_csv<W: std::io::Write>(&self, writer: W) -> Result<(), AccountingError> {
+        let output_error = |e: csv::Error| AccountingError::Output(e.to_string(), source(e));
+        let mut writer = csv::Writer::from_writer(writer);
+        writer
+            .write_record([
+                "bucket",
+                "deposits",
+                "deposit_total",
+                "withdrawals",
+                "withdrawal_total",
+                "chargebacks",
+                "chargeback_total",
+                "net_flow",
+            ])
+            .map_err(output_error)?;
+        writer
+            .write_record([
+                "run".to_string(),
+                self.deposits.count.to_string(),
+                format_amount(self.deposits.total),
+                self.withdrawals.count.to_string(),
+                format_amount(self.withdrawals.total),
+                self.chargebacks.count.to_string(),
+                format_amount(self.chargebacks.total),
+                format_amount(self.net_flow),
+            ])
+            .map_err(output_error)?;
+        writer
+            .flush()
+            .map_err(|e| AccountingError::Output(e.to_string(), source(e)))
+    }
+}
+
+// Adds up the aggregates of several processors
+impl AddAssign for VolumeAggregates {
+    fn add_assign(&mut self, other: Self) {
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.chargebacks += other.chargebacks;
+        self.net_flow = self.net_flow.saturating_add(other.net_flow);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_add_up_processors() {
+        let mut first = VolumeAggregates::default();
+        first.deposit(dec!(10));
+        first.chargeback(dec!(2.5), DisputeKind::Withdrawal);
+        let mut second = VolumeAggregates::default();
+        second.deposit(Amount::MAX);
+        second.withdrawal(dec!(1));
+        first += second;
+        assert_eq!(
+            first.deposits,
+            Volume {
+                count: 2,
+                total: Amount::MAX
+            }
+        );
+        assert_eq!(first.chargebacks.total, dec!(2.5));
+        assert_eq!(first.net_flow, Amount::MAX);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_write_csv() {
+        let mut aggregates = VolumeAggregates::default();
+        aggregates.deposit(dec!(10));
+        aggregates.deposit(dec!(0.12345));
+        aggregates.withdrawal(dec!(4));
+        aggregates.chargeback(dec!(10), DisputeKind::Deposit);
+        let mut output = Vec::new();
+        aggregates.write_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "bucket,deposits,deposit_total,withdrawals,withdrawal_total,chargebacks,chargeback_total,net_flow
+run,2,10.1234,1,4.0000,1,10.0000,-3.8766
+"
+        );
+    }
+}
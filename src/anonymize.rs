@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::csv_utils::{TransactionReader, TransactionWriter};
+use crate::rounding::{RoundingConfig, RoundingStrategy};
+use crate::transaction::{Amount, ClientID, Transaction};
+use crate::transaction_processor::TransactionProcessor;
+use crate::AccountingError;
+
+// Amounts of a client are scaled by a factor within 1 +/- this many basis points
+const MAX_PERTURBATION_BPS: u64 = 2000;
+
+// Turns a production ledger into a fixture without its real client ids and balances. Client ids go
+// through a seeded permutation and the amounts of each client are scaled by a seeded factor, while
+// the order and ids of the transactions are kept. Every transaction is replayed before and after,
+// and clients where any transaction would be applied or rejected differently keep their amounts
+pub struct Anonymized {
+    pub transactions: Vec<Transaction>,
+    // Number of clients whose amounts couldn't be perturbed without changing an outcome
+    pub unperturbed_clients: usize,
+}
+
+pub async fn anonymize(
+    transactions: Vec<Transaction>,
+    seed: u64,
+) -> Result<Anonymized, AccountingError> {
+    let permutation = client_permutation(seed);
+    let mut factors: BTreeMap<ClientID, Decimal> = transactions
+        .iter()
+        .map(|tx| (tx.client_id(), amount_factor(seed, tx.client_id())))
+        .collect();
+    let expected = outcomes(&transactions).await;
+    loop {
+        let candidate: Vec<Transaction> = transactions
+            .iter()
+            .map(|tx| {
+                let client = tx.client_id();
+                remap(tx, permutation[client as usize], factors[&client])
+            })
+            .collect();
+        let diverged: BTreeSet<ClientID> = transactions
+            .iter()
+            .zip(expected.iter().zip(outcomes(&candidate).await))
+            .filter(|(_, (expected, actual))| **expected != *actual)
+            .map(|(tx, _)| tx.client_id())
+            .collect();
+        if diverged.is_empty() {
+            return Ok(Anonymized {
+                transactions: candidate,
+                unperturbed_clients: factors.values().filter(|f| **f == dec!(1)).count(),
+            });
+        }
+        // Clients are independent of each other, so unscaled amounts always replay the same
+        if diverged.iter().all(|client| factors[client] == dec!(1)) {
+            return Err(AccountingError::InvariantViolation(format!(
+                "anonymized clients {:?} replay differently with their own amounts",
+                diverged
+            )));
+        }
+        for client in diverged {
+            factors.insert(client, dec!(1));
+        }
+    }
+}
+
+// Reads every transaction of a csv file, rows that can't be parsed are left out of the fixture
+pub fn read_transactions(path: &str) -> Result<Vec<Transaction>, AccountingError> {
+    let reader = TransactionReader::new(path.to_string())
+        .map_err(|e| AccountingError::Input(e.to_string(), None))?;
+    Ok(reader.filter_map(Result::ok).collect())
+}
+
+// Writes transactions as csv that reads back into the same transactions, with amounts at four decimal places
+pub fn write_transactions<W: Write>(
+    transactions: &[Transaction],
+    out: W,
+) -> Result<(), AccountingError> {
+    let mut writer = TransactionWriter::new(out);
+    for tx in transactions {
+        writer.write_transaction(tx)?;
+    }
+    writer.flush()
+}
+
+// The error code of every transaction when replayed on new accounts, None if it was applied
+async fn outcomes(transactions: &[Transaction]) -> Vec<Option<&'static str>> {
+    let (mut processor, _sender) =
+        TransactionProcessor::new(Default::default(), Default::default());
+    let mut outcomes = Vec::with_capacity(transactions.len());
+    for tx in transactions {
+        outcomes.push(
+            processor
+                .process_transaction(tx.clone())
+                .await
+                .err()
+                .map(|e| e.code()),
+        );
+    }
+    outcomes
+}
+
+// Deposits are rounded up and withdrawals down, so a scaled client can always afford what it could before
+fn remap(tx: &Transaction, client: ClientID, factor: Decimal) -> Transaction {
+    let mut tx = tx.clone();
+    let (data, strategy) = match &mut tx {
+        Transaction::Interest(interest) => {
+            interest.client_id = client;
+            return tx;
+        }
+        Transaction::Deposit(data) => (data, Some(RoundingStrategy::AwayFromZero)),
+        Transaction::Withdrawal(data) => (data, Some(RoundingStrategy::TowardZero)),
+        Transaction::WithdrawAll(data)
+        | Transaction::Resolve(data)
+        | Transaction::Dispute(data)
+        | Transaction::Chargeback(data)
+        | Transaction::Settle(data)
+        | Transaction::Cancel(data) => (data, None),
+    };
+    data.client_id = client;
+    if let (Some(amount), Some(strategy)) = (data.amount, strategy) {
+        // Never scaled down to a zero amount
+        let amount: Amount = RoundingConfig::new(4, strategy).round(amount * factor);
+        data.amount = Some(amount.max(dec!(0.0001)));
+    }
+    tx
+}
+
+// A seeded shuffle of every possible client id, indexed by the original id
+fn client_permutation(seed: u64) -> Vec<ClientID> {
+    let mut rng = SplitMix64(seed);
+    let mut permutation: Vec<ClientID> = (0..=ClientID::MAX).collect();
+    for i in (1..permutation.len()).rev() {
+        let j = (rng.next() % (i as u64 + 1)) as usize;
+        permutation.swap(i, j);
+    }
+    permutation
+}
+
+// Depends only on the seed and the client, so adding transactions of other clients doesn't change it
+fn amount_factor(seed: u64, client: ClientID) -> Decimal {
+    let mut rng = SplitMix64(seed ^ (client as u64 + 1).wrapping_mul(0xD6E8_FEB8_6659_FD93));
+    let bps = (rng.next() % (2 * MAX_PERTURBATION_BPS + 1)) as i64 - MAX_PERTURBATION_BPS as i64;
+    dec!(1) + Decimal::new(bps, 4)
+}
+
+// Small seeded generator, fixtures only need to be reproducible, not unpredictable
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Withdrawals and disputes of exactly the available funds, which break first if amounts are scaled carelessly
+    fn ledger() -> Vec<Transaction> {
+        vec![
+            Transaction::deposit(1, 1, dec!(1.0001)),
+            Transaction::deposit(1, 2, dec!(2.3333)),
+            Transaction::withdrawal(1, 3, dec!(3.3334)),
+            Transaction::withdrawal(1, 4, dec!(0.0001)),
+            Transaction::deposit(2, 5, dec!(10)),
+            Transaction::dispute(2, 5),
+            Transaction::resolve(2, 5),
+            Transaction::withdrawal(2, 6, dec!(4.5)),
+            Transaction::withdrawal(2, 7, dec!(5.5)),
+            Transaction::deposit(3, 8, dec!(7.77)),
+            Transaction::withdrawal(3, 9, dec!(2.22)),
+            Transaction::dispute(3, 9),
+            Transaction::chargeback(3, 9),
+            Transaction::withdrawal(3, 10, dec!(7.77)),
+            Transaction::withdraw_all(3, 11),
+            Transaction::deposit(4, 12, dec!(0.0003)),
+            Transaction::interest(4, dec!(0.05), 30),
+            Transaction::withdrawal(4, 13, dec!(0.0003)),
+            Transaction::dispute(4, 12),
+            Transaction::dispute(1, 5),
+        ]
+    }
+
+    fn to_csv(transactions: &[Transaction]) -> String {
+        let mut out = Vec::new();
+        write_transactions(transactions, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_anonymize_is_deterministic() {
+        let first = anonymize(ledger(), 7).await.unwrap();
+        let second = anonymize(ledger(), 7).await.unwrap();
+        let other_seed = anonymize(ledger(), 8).await.unwrap();
+        assert_eq!(to_csv(&first.transactions), to_csv(&second.transactions));
+        assert_ne!(
+            to_csv(&first.transactions),
+            to_csv(&other_seed.transactions)
+        );
+        assert_ne!(to_csv(&first.transactions), to_csv(&ledger()));
+    }
+
+    #[tokio::test]
+    async fn test_anonymize_preserves_feasibility() {
+        let expected = outcomes(&ledger()).await;
+        for seed in 0..50 {
+            let anonymized = anonymize(ledger(), seed).await.unwrap();
+            assert_eq!(
+                outcomes(&anonymized.transactions).await,
+                expected,
+                "seed {}",
+                seed
+            );
+            // Orderings and transaction ids are kept, clients are still told apart
+            let tx_ids: Vec<_> = anonymized
+                .transactions
+                .iter()
+                .map(|tx| tx.tx_id())
+                .collect();
+            assert_eq!(
+                tx_ids,
+                ledger().iter().map(|tx| tx.tx_id()).collect::<Vec<_>>()
+            );
+            let clients: BTreeSet<ClientID> = anonymized
+                .transactions
+                .iter()
+                .map(|tx| tx.client_id())
+                .collect();
+            assert_eq!(clients.len(), 4);
+            // Client 1 overdraws by 0.0001, which rounding the deposits up would let through
+            assert!(anonymized.unperturbed_clients < 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fixture_reads_back() {
+        let anonymized = anonymize(ledger(), 3).await.unwrap();
+        let csv = to_csv(&anonymized.transactions);
+        let path =
+            std::env::temp_dir().join(format!("transactron_anonymized_{}.csv", std::process::id()));
+        std::fs::write(&path, &csv).unwrap();
+        let read_back = read_transactions(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(to_csv(&read_back), csv);
+    }
+}
@@ -0,0 +1,697 @@
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use csv_core::ReadRecordResult;
+use futures_core::Stream;
+use tokio::io::{AsyncBufRead, BufReader};
+
+use crate::client_id_map::ClientIdMap;
+use crate::csv_utils::{
+    check_dispute_column, sequence_column, sequence_number, starts_with_transaction_type,
+    ResyncPolicy, TransactionReaderConfig,
+};
+use crate::error::source;
+use crate::pipeline::TransactionSource;
+use crate::reorder::ReorderBuffer;
+use crate::transaction::Transaction;
+use crate::AccountingError;
+
+// Reads from files go through tokio's blocking pool, so they are made in larger chunks
+const READ_BUFFER: usize = 64 * 1024;
+
+// A transaction with its sequence number, when ordering by sequence
+type Sequenced = (Transaction, Option<u64>);
+
+// How much of a line is looked at for a transaction type when resyncing
+const MAX_TYPE_PREFIX: usize = 64;
+
+// Reads csv transactions without blocking the runtime, e.g. from a tokio::fs::File, stdin or a socket.
+// Rows are read like TransactionReader reads them: quoted fields may span lines, rows can be ordered by a
+// sequence column and corrupted regions are resynced. The bytes of a rejected row are kept until the next
+// one starts, so resyncing never seeks and works on streams too. The reader is a Stream of transactions,
+// or use next() directly
+pub struct AsyncTransactionReader<R> {
+    input: Input<R>,
+    // Finds where a record ends, quotes included. Its output is thrown away, a record is parsed from its bytes
+    framer: csv_core::Reader,
+    scratch: Vec<u8>,
+    scratch_ends: Vec<usize>,
+    // The bytes of the record being read, or of the one read last
+    record: Vec<u8>,
+    // Offset and line number the record starts at
+    record_start: (u64, u64),
+    in_record: bool,
+    // The record went over the size limit, the rest of its line is skipped rather than buffered
+    oversized: bool,
+    resync: Option<Resync>,
+    headers: csv::StringRecord,
+    config: TransactionReaderConfig,
+    sequence_column: Option<usize>,
+    reorder: Option<ReorderBuffer>,
+    consecutive_errors: usize,
+    skipped: Vec<Range<u64>>,
+}
+
+// The input, with the bytes handed back by unread() read again before the rest of it
+struct Input<R> {
+    reader: R,
+    replay: Vec<u8>,
+    replayed: usize,
+    // Offset and line number of the next byte
+    offset: u64,
+    line: u64,
+}
+
+impl<R: AsyncBufRead + Unpin> Input<R> {
+    // Hands the buffered bytes to `take`, which returns how many of them it used up. It is given an
+    // empty buffer at the end of the input
+    fn poll_take<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        take: impl FnOnce(&[u8]) -> (usize, T),
+    ) -> Poll<Result<T, AccountingError>> {
+        let replaying = self.replayed < self.replay.len();
+        let buf = if replaying {
+            &self.replay[self.replayed..]
+        } else {
+            ready!(Pin::new(&mut self.reader).poll_fill_buf(cx))
+                .map_err(|e| AccountingError::Input(e.to_string(), source(e)))?
+        };
+        let (used, result) = take(buf);
+        self.offset += used as u64;
+        self.line += newlines(&buf[..used]);
+        if replaying {
+            self.replayed += used;
+        } else {
+            Pin::new(&mut self.reader).consume(used);
+        }
+        Poll::Ready(Ok(result))
+    }
+
+    // Puts bytes that were taken back in front of the input
+    fn unread(&mut self, bytes: &[u8]) {
+        let mut replay = bytes.to_vec();
+        replay.extend_from_slice(&self.replay[self.replayed..]);
+        self.replay = replay;
+        self.replayed = 0;
+        self.offset -= bytes.len() as u64;
+        self.line -= newlines(bytes);
+    }
+}
+
+fn newlines(bytes: &[u8]) -> u64 {
+    bytes.iter().filter(|byte| **byte == b'\n').count() as u64
+}
+
+// Skipping a corrupted region, up to the first line after `from` that starts with a transaction type
+struct Resync {
+    from: u64,
+    // Skipping the rest of a line. The first line is the start of the rejected record, so it is always skipped
+    skip_line: bool,
+    // The start of the current line, up to the first delimiter
+    head: Vec<u8>,
+}
+
+enum ResyncStep {
+    Continue,
+    CheckHead,
+    End,
+}
+
+impl AsyncTransactionReader<Box<dyn AsyncBufRead + Send + Unpin>> {
+    // Opens the file at the given path, or reads stdin for a path of "" or "-" like TransactionReader does
+    pub async fn open(
+        path: &str,
+        config: TransactionReaderConfig,
+    ) -> Result<Self, AccountingError> {
+        let input: Box<dyn AsyncBufRead + Send + Unpin> = match path {
+            "" | "-" => Box::new(BufReader::with_capacity(READ_BUFFER, tokio::io::stdin())),
+            path => {
+                let file = tokio::fs::File::open(path)
+                    .await
+                    .map_err(|e| AccountingError::Input(format!("{}: {}", path, e), source(e)))?;
+                Box::new(BufReader::with_capacity(READ_BUFFER, file))
+            }
+        };
+        AsyncTransactionReader::new(input, config).await
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncTransactionReader<R> {
+    // Reads the header right away, an input with a header the config doesn't allow is an error
+    pub async fn new(input: R, config: TransactionReaderConfig) -> Result<Self, AccountingError> {
+        let mut reader = AsyncTransactionReader {
+            input: Input {
+                reader: input,
+                replay: Vec::new(),
+                replayed: 0,
+                offset: 0,
+                line: 1,
+            },
+            framer: csv_core::Reader::new(),
+            scratch: vec![0; 1024],
+            scratch_ends: vec![0; 64],
+            record: Vec::new(),
+            record_start: (0, 1),
+            in_record: false,
+            oversized: false,
+            resync: None,
+            headers: csv::StringRecord::new(),
+            config,
+            sequence_column: None,
+            reorder: None,
+            consecutive_errors: 0,
+            skipped: Vec::new(),
+        };
+        if std::future::poll_fn(|cx| reader.poll_record(cx)).await? {
+            let mut headers = csv::StringRecord::new();
+            parse_row(&reader.record, &mut headers)
+                .map_err(|e| AccountingError::Input(e.to_string(), source(e)))?;
+            reader.headers = headers;
+        }
+        check_dispute_column(&reader.headers, &reader.config)
+            .map_err(|message| AccountingError::Input(message, None))?;
+        reader.sequence_column = sequence_column(&reader.headers, &reader.config)
+            .map_err(|message| AccountingError::Input(message, None))?;
+        reader.reorder = reader
+            .config
+            .ordering
+            .as_ref()
+            .map(|ordering| ReorderBuffer::new(ordering.window, ordering.strict));
+        Ok(reader)
+    }
+
+    // The next transaction, or the reason its row was rejected. None once the input is exhausted
+    pub async fn next(&mut self) -> Option<Result<Transaction, AccountingError>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    // Reads the next row in input order, together with its sequence number when ordering by sequence
+    fn poll_read_next(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Sequenced>, AccountingError>> {
+        if !ready!(self.poll_record(cx))? {
+            return Poll::Ready(Ok(None));
+        }
+        let mut row = csv::StringRecord::new();
+        let read = parse_row(&self.record, &mut row);
+        let sequence = sequence_number(&row, self.sequence_column);
+        let result = self.config.row_to_transaction(
+            &self.headers,
+            read.map(|_| &row),
+            self.record_start.1,
+            self.record.len() as u64,
+        );
+        match result {
+            Ok(_) => self.consecutive_errors = 0,
+            Err(_) => {
+                self.consecutive_errors += 1;
+                let spans_lines = newlines(self.record.trim_ascii_end()) > 0;
+                if let ResyncPolicy::AfterErrors(limit) = self.config.resync_policy {
+                    if spans_lines || self.consecutive_errors >= limit {
+                        self.start_resync();
+                    }
+                }
+            }
+        }
+        Poll::Ready(result.map(|tx| Some((tx, sequence))))
+    }
+
+    // Reads the bytes of the next record into `record`, false at the end of the input. Blank lines
+    // before it are skipped
+    fn poll_record(&mut self, cx: &mut Context<'_>) -> Poll<Result<bool, AccountingError>> {
+        let limit = self.config.record_limits.max_record_bytes;
+        loop {
+            if self.resync.is_some() {
+                ready!(self.poll_resync(cx))?;
+            } else if self.oversized {
+                let done = ready!(self.input.poll_take(cx, |buf| {
+                    match buf.iter().position(|byte| *byte == b'\n') {
+                        Some(newline) => (newline + 1, true),
+                        None => (buf.len(), buf.is_empty()),
+                    }
+                }))?;
+                if done {
+                    self.oversized = false;
+                    let line = self.record_start.1;
+                    return Poll::Ready(Err(AccountingError::OversizedRecord(line, limit)));
+                }
+            } else if !self.in_record {
+                let (at_end, starts) = ready!(self.input.poll_take(cx, |buf| {
+                    let blank = buf
+                        .iter()
+                        .take_while(|byte| matches!(byte, b'\r' | b'\n'))
+                        .count();
+                    (blank, (buf.is_empty(), blank < buf.len()))
+                }))?;
+                if at_end {
+                    return Poll::Ready(Ok(false));
+                }
+                if starts {
+                    self.framer.reset();
+                    self.record.clear();
+                    self.record_start = (self.input.offset, self.input.line);
+                    self.in_record = true;
+                }
+            } else {
+                // At most one byte over the limit is read, so an oversized record is never buffered whole
+                let allowed = limit + 1 - self.record.len();
+                let done = ready!(self.input.poll_take(cx, |buf| {
+                    let input = &buf[..buf.len().min(allowed)];
+                    let (result, used, _, _) =
+                        self.framer
+                            .read_record(input, &mut self.scratch, &mut self.scratch_ends);
+                    self.record.extend_from_slice(&input[..used]);
+                    let done = matches!(result, ReadRecordResult::Record | ReadRecordResult::End);
+                    (used, done)
+                }))?;
+                if done {
+                    self.in_record = false;
+                    if self.record.len() > limit {
+                        let line = self.record_start.1;
+                        return Poll::Ready(Err(AccountingError::OversizedRecord(line, limit)));
+                    }
+                    return Poll::Ready(Ok(!self.record.is_empty()));
+                }
+                if self.record.len() > limit {
+                    self.in_record = false;
+                    self.oversized = !self.record.ends_with(b"\n");
+                    if !self.oversized {
+                        let line = self.record_start.1;
+                        return Poll::Ready(Err(AccountingError::OversizedRecord(line, limit)));
+                    }
+                }
+            }
+        }
+    }
+
+    // Hands the rejected record back to the input and skips from its start to the next line that starts
+    // with a transaction type
+    fn start_resync(&mut self) {
+        self.input.unread(&self.record);
+        self.consecutive_errors = 0;
+        self.resync = Some(Resync {
+            from: self.record_start.0,
+            skip_line: true,
+            head: Vec::new(),
+        });
+    }
+
+    fn poll_resync(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), AccountingError>> {
+        loop {
+            let resync = self.resync.as_mut().expect("resyncing");
+            let step = ready!(self.input.poll_take(cx, |buf| {
+                if buf.is_empty() {
+                    return (0, ResyncStep::End);
+                }
+                let end = match resync.skip_line {
+                    true => buf.iter().position(|byte| *byte == b'\n'),
+                    false => buf.iter().position(|byte| matches!(byte, b',' | b'\n')),
+                };
+                match end {
+                    Some(end) if resync.skip_line || buf[end] == b'\n' => {
+                        // A line without a delimiter can't be a record either
+                        resync.skip_line = false;
+                        resync.head.clear();
+                        (end + 1, ResyncStep::Continue)
+                    }
+                    Some(end) => {
+                        resync.head.extend_from_slice(&buf[..end]);
+                        (end, ResyncStep::CheckHead)
+                    }
+                    None => {
+                        if !resync.skip_line {
+                            resync.head.extend_from_slice(buf);
+                            if resync.head.len() > MAX_TYPE_PREFIX {
+                                resync.skip_line = true;
+                                resync.head.clear();
+                            }
+                        }
+                        (buf.len(), ResyncStep::Continue)
+                    }
+                }
+            }))?;
+            match step {
+                ResyncStep::Continue => continue,
+                ResyncStep::CheckHead => {
+                    let mut line = std::mem::take(&mut resync.head);
+                    line.push(b',');
+                    if !starts_with_transaction_type(&line, b',') {
+                        resync.skip_line = true;
+                        continue;
+                    }
+                    // The line is the next record, it is read again from its start
+                    self.input.unread(&line[..line.len() - 1]);
+                }
+                ResyncStep::End => (),
+            }
+            let from = self.resync.take().expect("resyncing").from;
+            self.skipped.push(from..self.input.offset);
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+// Splits the bytes of a record into trimmed fields, rows may have fewer fields than the header
+fn parse_row(record: &[u8], row: &mut csv::StringRecord) -> csv::Result<bool> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(record)
+        .read_record(row)
+}
+
+impl<R: AsyncBufRead + Unpin> TransactionSource for AsyncTransactionReader<R> {
+    fn poll_transaction(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Transaction>, AccountingError>> {
+        if self.reorder.is_none() {
+            return self
+                .poll_read_next(cx)
+                .map(|next| next.map(|tx| tx.map(|(tx, _)| tx)));
+        }
+        loop {
+            let reorder = self.reorder.as_mut().unwrap();
+            if let Some(result) = reorder.pop_ready() {
+                return Poll::Ready(result.map(Some));
+            }
+            match ready!(self.poll_read_next(cx))? {
+                Some((tx, Some(sequence))) => self.reorder.as_mut().unwrap().push(sequence, tx),
+                Some((_, None)) => return Poll::Ready(Err(AccountingError::MalformedTransaction)),
+                None => {
+                    let reorder = self.reorder.as_mut().unwrap();
+                    reorder.finish();
+                    return Poll::Ready(reorder.pop_ready().transpose());
+                }
+            }
+        }
+    }
+
+    fn skipped_ranges(&self) -> &[Range<u64>] {
+        &self.skipped
+    }
+
+    fn reorder_buffer(&self) -> Option<&ReorderBuffer> {
+        self.reorder.as_ref()
+    }
+
+    fn client_id_map(&self) -> Option<&ClientIdMap> {
+        self.config.client_id_map.as_ref()
+    }
+
+    fn take_client_id_map(&mut self) -> Option<ClientIdMap> {
+        self.config.client_id_map.take()
+    }
+
+    fn set_client_id_map(&mut self, client_id_map: ClientIdMap) {
+        self.config.client_id_map = Some(client_id_map);
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> Stream for AsyncTransactionReader<R> {
+    type Item = Result<Transaction, AccountingError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_transaction(cx).map(Result::transpose)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+    use tokio::io::{AsyncWriteExt, BufReader};
+
+    use super::*;
+    use crate::csv_utils::{RecordLimits, SequenceOrdering};
+    use crate::transaction::TxID;
+
+    async fn reader_for(input: &'static str) -> AsyncTransactionReader<&'static [u8]> {
+        AsyncTransactionReader::new(input.as_bytes(), Default::default())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read_transactions() {
+        let mut reader = reader_for(
+            "type, client, tx, amount
+  deposit ,  1 , 1 ,  2.5
+
+dispute, 1, 1
+deposit, 1, two, 1.0
+bogus, 1, 3, 1.0
+withdrawal, 2, 4, 1.5
+",
+        )
+        .await;
+        match reader.next().await {
+            Some(Ok(Transaction::Deposit(tx))) => {
+                assert_eq!((tx.client_id, tx.tx_id, tx.amount), (1, 1, Some(dec!(2.5))))
+            }
+            other => panic!("expected deposit, got {:?}", other),
+        }
+        assert!(matches!(
+            reader.next().await,
+            Some(Ok(Transaction::Dispute(_)))
+        ));
+        match reader.next().await {
+            Some(Err(AccountingError::Deserialize(message, _))) => {
+                assert!(message.starts_with("line 5:"), "{}", message)
+            }
+            other => panic!("expected a deserialize error, got {:?}", other),
+        }
+        assert!(matches!(
+            reader.next().await,
+            Some(Err(AccountingError::UnknownTransactionType(_)))
+        ));
+        assert!(matches!(
+            reader.next().await,
+            Some(Ok(Transaction::Withdrawal(_)))
+        ));
+        assert!(reader.next().await.is_none());
+        assert!(reader.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_rows() {
+        let input = format!(
+            "type,client,tx,amount\ndeposit,1,1,1.{}\ndeposit,1,2,{}\ndeposit,1,3,1.0\n",
+            "0".repeat(20_000),
+            "1".repeat(2_000),
+        );
+        // A small buffer, so the oversized row has to be skipped over several reads
+        let input = BufReader::with_capacity(64, std::io::Cursor::new(input.into_bytes()));
+        let mut reader = AsyncTransactionReader::new(input, Default::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            reader.next().await,
+            Some(Err(AccountingError::OversizedRecord(2, _)))
+        ));
+        assert!(matches!(
+            reader.next().await,
+            Some(Err(AccountingError::OversizedField(3, 1024)))
+        ));
+        assert!(matches!(
+            reader.next().await,
+            Some(Ok(Transaction::Deposit(_)))
+        ));
+        assert!(reader.next().await.is_none());
+    }
+
+    // Drives the reader through the Stream trait only, the way stream combinators do
+    #[tokio::test]
+    async fn test_stream() {
+        async fn collect<S: Stream + Unpin>(mut stream: S) -> Vec<S::Item> {
+            let mut items = Vec::new();
+            while let Some(item) =
+                std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await
+            {
+                items.push(item);
+            }
+            items
+        }
+
+        // No newline at the end, and a small buffer so lines arrive over several polls
+        let input = BufReader::with_capacity(
+            4,
+            "type,client,tx,amount\ndeposit,1,1,2.5\n\nbogus,1,2,1\nwithdrawal,1,3,1".as_bytes(),
+        );
+        let reader = AsyncTransactionReader::new(input, Default::default())
+            .await
+            .unwrap();
+        let items = collect(reader).await;
+        assert_eq!(items.len(), 3);
+        assert_eq!(
+            items[0].as_ref().ok(),
+            Some(&Transaction::deposit(1, 1, dec!(2.5)))
+        );
+        assert!(matches!(
+            items[1],
+            Err(AccountingError::UnknownTransactionType(_))
+        ));
+        assert_eq!(
+            items[2].as_ref().ok(),
+            Some(&Transaction::withdrawal(1, 3, dec!(1)))
+        );
+    }
+
+    async fn read_all<R: AsyncBufRead + Unpin>(
+        reader: &mut AsyncTransactionReader<R>,
+    ) -> (Vec<TxID>, usize) {
+        let (mut tx_ids, mut errors) = (Vec::new(), 0);
+        while let Some(result) = reader.next().await {
+            match result {
+                Ok(tx) => tx_ids.push(tx.tx_id().unwrap()),
+                Err(_) => errors += 1,
+            }
+        }
+        (tx_ids, errors)
+    }
+
+    #[tokio::test]
+    async fn test_quoted_field_spans_lines() {
+        let mut reader = reader_for(
+            "type, client, tx, amount\n\"deposit\n\", 1, 1, 2.5\ndeposit, 1, two, 1.0\n",
+        )
+        .await;
+        assert_eq!(
+            reader.next().await.unwrap().unwrap(),
+            Transaction::deposit(1, 1, dec!(2.5))
+        );
+        match reader.next().await {
+            Some(Err(AccountingError::Deserialize(message, _))) => {
+                assert!(message.starts_with("line 4:"), "{}", message)
+            }
+            other => panic!("expected a deserialize error, got {:?}", other),
+        }
+    }
+
+    const CORRUPTED_INPUT: &[u8] = b"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+\"\x00\x13\xfe garbage, \x01
+\x7f\xff\xff, ,,,
+deposit, 1, 3, 3.0
+withdrawal, 2, 4, 1.0
+";
+
+    #[tokio::test]
+    async fn test_resync_after_corrupted_block() {
+        // A small buffer, so the rejected record is handed back over several reads
+        let input = BufReader::with_capacity(8, CORRUPTED_INPUT);
+        let mut reader = AsyncTransactionReader::new(input, Default::default())
+            .await
+            .unwrap();
+        assert_eq!(read_all(&mut reader).await, (vec![1, 2, 3, 4], 1));
+        let offset_of = |needle: &[u8]| {
+            CORRUPTED_INPUT
+                .windows(needle.len())
+                .position(|window| window == needle)
+                .unwrap() as u64
+        };
+        let (garbage_start, garbage_end) = (offset_of(b"\"\x00"), offset_of(b"deposit, 1, 3"));
+        assert_eq!(reader.skipped_ranges().len(), 1);
+        assert_eq!(reader.skipped_ranges()[0], garbage_start..garbage_end);
+
+        // The stray quote swallows every row after it
+        let config = TransactionReaderConfig {
+            resync_policy: ResyncPolicy::Disabled,
+            ..Default::default()
+        };
+        let mut reader = AsyncTransactionReader::new(CORRUPTED_INPUT, config)
+            .await
+            .unwrap();
+        assert_eq!(read_all(&mut reader).await, (vec![1, 2], 1));
+        assert!(reader.skipped_ranges().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_order_by_sequence() {
+        let config = TransactionReaderConfig {
+            ordering: Some(SequenceOrdering {
+                column: "sequence".to_string(),
+                window: 10,
+                strict: false,
+            }),
+            ..Default::default()
+        };
+        let input: &[u8] = b"type, client, tx, amount, sequence\nwithdrawal, 1, 2, 1.0, 2\nwithdrawal, 2, 4, 1.0, 8\ndeposit, 1, 1, 1.0, 1\ndeposit, 2, 3, 1.0, 7\ndeposit, 1, 5, 1.0, 3\n";
+        let mut reader = AsyncTransactionReader::new(input, config.clone())
+            .await
+            .unwrap();
+        assert_eq!(read_all(&mut reader).await, (vec![1, 2, 5, 3, 4], 0));
+        assert_eq!(reader.reorder_buffer().unwrap().peak(), 5);
+
+        let input: &[u8] = b"type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+        assert!(matches!(
+            AsyncTransactionReader::new(input, config).await,
+            Err(AccountingError::Input(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_quoted_field() {
+        // The quoted field spans lines, it is still cut off at the record limit
+        let input = format!(
+            "type,client,tx,amount\ndeposit,1,1,\"1.{}\n{}\"\ndeposit,1,2,2.0\n",
+            "9".repeat(20_000),
+            "9".repeat(100_000),
+        );
+        let input = BufReader::with_capacity(64, std::io::Cursor::new(input.into_bytes()));
+        let mut reader = AsyncTransactionReader::new(input, Default::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            reader.next().await,
+            Some(Err(AccountingError::OversizedRecord(2, _)))
+        ));
+        assert!(reader.record.len() <= RecordLimits::default().max_record_bytes + 1);
+        assert_eq!(read_all(&mut reader).await, (vec![2], 1));
+    }
+
+    #[tokio::test]
+    async fn test_dispute_column_requires_trust() {
+        let input: &[u8] = b"type,client,tx,amount,under_dispute\ndeposit,1,1,1.0,true\n";
+        assert!(matches!(
+            AsyncTransactionReader::new(input, Default::default()).await,
+            Err(AccountingError::Input(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rows_arrive_over_time() {
+        let (writer, reader) = tokio::io::duplex(16);
+        let writer = tokio::spawn(async move {
+            let mut writer = writer;
+            for chunk in [
+                "type,client,",
+                "tx,amount\ndeposit,1,1,",
+                "5\n",
+                "withdrawal,1,2,2\n",
+            ] {
+                writer.write_all(chunk.as_bytes()).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        let mut reader = AsyncTransactionReader::new(BufReader::new(reader), Default::default())
+            .await
+            .unwrap();
+        let mut transactions = Vec::new();
+        while let Some(tx) = reader.next().await {
+            transactions.push(tx.unwrap());
+        }
+        writer.await.unwrap();
+        assert_eq!(
+            transactions,
+            vec![
+                Transaction::deposit(1, 1, dec!(5)),
+                Transaction::withdrawal(1, 2, dec!(2))
+            ]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::csv_utils::read_error;
+use crate::transaction::ClientID;
+use crate::AccountingError;
+
+// Maps external client identifiers (e.g. partner UUIDs) onto the u16 client ids used internally
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientIdMap {
+    ids: BTreeMap<String, ClientID>,
+    assigned: BTreeSet<ClientID>,
+    // Allocate the next free client id for unseen external ids instead of rejecting them
+    pub auto_assign: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MappingRecord {
+    external_id: String,
+    client: ClientID,
+}
+
+impl ClientIdMap {
+    pub fn new(auto_assign: bool) -> Self {
+        ClientIdMap {
+            auto_assign,
+            ..Default::default()
+        }
+    }
+
+    // Reads a csv file with external_id,client rows
+    pub fn load<P: AsRef<Path>>(path: P, auto_assign: bool) -> Result<Self, AccountingError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .map_err(read_error)?;
+        let mut map = ClientIdMap::new(auto_assign);
+        for record in reader.deserialize() {
+            let record: MappingRecord = record.map_err(read_error)?;
+            map.insert(record.external_id, record.client)?;
+        }
+        Ok(map)
+    }
+
+    // Adds an explicit assignment. Two external ids sharing a client id would merge their accounts, so that is rejected
+    pub fn insert(&mut self, external_id: String, client: ClientID) -> Result<(), AccountingError> {
+        if self.ids.get(&external_id) == Some(&client) {
+            return Ok(());
+        }
+        if self.ids.contains_key(&external_id) || !self.assigned.insert(client) {
+            return Err(AccountingError::DuplicateClientIdMapping(external_id));
+        }
+        self.ids.insert(external_id, client);
+        Ok(())
+    }
+
+    pub fn get(&self, external_id: &str) -> Option<ClientID> {
+        self.ids.get(external_id).copied()
+    }
+
+    // Looks up the client id for an external id, assigning the next free one if auto assignment is enabled
+    pub fn resolve(&mut self, external_id: &str) -> Result<ClientID, AccountingError> {
+        if let Some(client) = self.get(external_id) {
+            return Ok(client);
+        }
+        if !self.auto_assign {
+            return Err(AccountingError::UnmappedClientId(external_id.to_string()));
+        }
+        let client = match self.assigned.last() {
+            None => ClientID::MIN,
+            Some(&highest) if highest < ClientID::MAX => highest + 1,
+            // Only fall back to searching for gaps once the top of the range is taken
+            Some(_) => (ClientID::MIN..=ClientID::MAX)
+                .find(|client| !self.assigned.contains(client))
+                .ok_or(AccountingError::ClientIdSpaceExhausted)?,
+        };
+        self.insert(external_id.to_string(), client)?;
+        Ok(client)
+    }
+
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for (external_id, client) in &self.ids {
+            writer.serialize(MappingRecord {
+                external_id: external_id.clone(),
+                client: *client,
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), csv::Error> {
+        self.write(File::create(path)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_explicit_mapping() {
+        let path = std::env::temp_dir().join(format!(
+            "transactron_client_id_map_{}.csv",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "external_id,client\n7f3c2a9e-5b1d-4c2a-9d3e-1a2b3c4d5e6f,1\npartner-b,42\n",
+        )
+        .unwrap();
+
+        let mut map = ClientIdMap::load(&path, false).unwrap();
+        assert_eq!(
+            map.resolve("7f3c2a9e-5b1d-4c2a-9d3e-1a2b3c4d5e6f").unwrap(),
+            1
+        );
+        assert_eq!(map.resolve("partner-b").unwrap(), 42);
+        assert!(matches!(
+            map.resolve("unknown"),
+            Err(AccountingError::UnmappedClientId(id)) if id == "unknown"
+        ));
+        assert!(matches!(
+            map.insert("partner-c".to_string(), 42),
+            Err(AccountingError::DuplicateClientIdMapping(_))
+        ));
+    }
+
+    #[test]
+    fn test_auto_assignment_is_stable_across_runs() {
+        let mut first_run = ClientIdMap::new(true);
+        first_run.insert("reserved".to_string(), 0).unwrap();
+        assert_eq!(first_run.resolve("a").unwrap(), 1);
+        assert_eq!(first_run.resolve("b").unwrap(), 2);
+        assert_eq!(first_run.resolve("a").unwrap(), 1);
+
+        let path = std::env::temp_dir().join(format!(
+            "transactron_client_id_map_stable_{}.csv",
+            std::process::id()
+        ));
+        first_run.save(&path).unwrap();
+
+        // The second run sees the ids in a different order, plus a new one
+        let mut second_run = ClientIdMap::load(&path, true).unwrap();
+        assert_eq!(second_run, first_run);
+        assert_eq!(second_run.resolve("c").unwrap(), 3);
+        assert_eq!(second_run.resolve("b").unwrap(), 2);
+        assert_eq!(second_run.resolve("a").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_client_id_space_exhausted() {
+        let mut map = ClientIdMap::new(true);
+        for i in 0..=ClientID::MAX as u32 {
+            map.resolve(&i.to_string()).unwrap();
+        }
+        assert!(matches!(
+            map.resolve("one too many"),
+            Err(AccountingError::ClientIdSpaceExhausted)
+        ));
+        // Known ids still resolve
+        assert_eq!(map.resolve("65535").unwrap(), 65535);
+    }
+}
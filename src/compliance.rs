@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::account::Account;
+use crate::transaction::{Amount, ClientID, Transaction};
+use crate::AccountingError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComplianceResult {
+    pub passed: bool,
+    pub reason: Option<String>,
+    // A failed rule only stops the transaction if it is blocking
+    pub block: bool,
+}
+
+impl ComplianceResult {
+    pub fn pass() -> Self {
+        ComplianceResult {
+            passed: true,
+            reason: None,
+            block: false,
+        }
+    }
+
+    pub fn block(reason: String) -> Self {
+        ComplianceResult {
+            passed: false,
+            reason: Some(reason),
+            block: true,
+        }
+    }
+}
+
+pub trait ComplianceRule: Send + Sync {
+    fn check(&self, tx: &Transaction, account: &Account) -> ComplianceResult;
+}
+
+// Clones share the rules, and with them any state the rules keep
+#[derive(Default, Clone)]
+pub struct ComplianceChecker {
+    rules: Vec<Arc<dyn ComplianceRule>>,
+}
+
+impl ComplianceChecker {
+    pub fn new(rules: Vec<Box<dyn ComplianceRule>>) -> Self {
+        ComplianceChecker {
+            rules: rules.into_iter().map(Arc::from).collect(),
+        }
+    }
+
+    // Runs every rule against the transaction, fails on the first blocking rule
+    pub fn check(&self, tx: &Transaction, account: &Account) -> Result<(), AccountingError> {
+        for rule in &self.rules {
+            let result = rule.check(tx, account);
+            if !result.passed && result.block {
+                return Err(AccountingError::ComplianceBlock(
+                    result.reason.unwrap_or_default(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The amount moved by a deposit or withdrawal, None for transactions that don't move funds on their own
+fn transaction_amount(tx: &Transaction, account: &Account) -> Option<Amount> {
+    match tx {
+        Transaction::Deposit(tx_data) | Transaction::Withdrawal(tx_data) => tx_data.amount,
+        Transaction::WithdrawAll(_) => Some(account.available()),
+        _ => None,
+    }
+}
+
+// Blocks any single deposit or withdrawal above the limit
+pub struct MaxTransactionAmount {
+    pub limit: Amount,
+}
+
+impl ComplianceRule for MaxTransactionAmount {
+    fn check(&self, tx: &Transaction, account: &Account) -> ComplianceResult {
+        match transaction_amount(tx, account) {
+            Some(amount) if amount > self.limit => ComplianceResult::block(format!(
+                "transaction amount {} exceeds the limit of {}",
+                amount, self.limit
+            )),
+            _ => ComplianceResult::pass(),
+        }
+    }
+}
+
+// Blocks deposits and withdrawals once a client's combined volume would exceed the limit.
+// Transactions carry no timestamps, so the volume accumulates until reset() is called at the start of a new day
+pub struct MaxDailyVolume {
+    limit: Amount,
+    volume: Mutex<BTreeMap<ClientID, Amount>>,
+}
+
+impl MaxDailyVolume {
+    pub fn new(limit: Amount) -> Self {
+        MaxDailyVolume {
+            limit,
+            volume: Default::default(),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.volume.lock().unwrap().clear();
+    }
+}
+
+impl ComplianceRule for MaxDailyVolume {
+    fn check(&self, tx: &Transaction, account: &Account) -> ComplianceResult {
+        let amount = match transaction_amount(tx, account) {
+            Some(amount) => amount,
+            None => return ComplianceResult::pass(),
+        };
+        let mut volume = self.volume.lock().unwrap();
+        let client_volume = volume.entry(tx.client_id()).or_default();
+        if *client_volume + amount > self.limit {
+            return ComplianceResult::block(format!(
+                "daily volume of {} for client {} would exceed the limit of {}",
+                *client_volume + amount,
+                tx.client_id(),
+                self.limit
+            ));
+        }
+        *client_volume += amount;
+        ComplianceResult::pass()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_max_transaction_amount() {
+        let checker =
+            ComplianceChecker::new(vec![Box::new(MaxTransactionAmount { limit: dec!(100) })]);
+        let account = Account::new(1, dec!(500), dec!(0), dec!(500));
+
+        assert!(checker
+            .check(&Transaction::deposit(1, 1, dec!(100)), &account)
+            .is_ok());
+        assert!(matches!(
+            checker.check(&Transaction::deposit(1, 2, dec!(100.0001)), &account),
+            Err(AccountingError::ComplianceBlock(_))
+        ));
+        // Withdrawing everything is checked against the available funds
+        let withdraw_all = Transaction::withdraw_all(1, 3);
+        assert!(checker.check(&withdraw_all, &account).is_err());
+        // Disputes don't carry an amount of their own
+        let dispute = Transaction::dispute(1, 1);
+        assert!(checker.check(&dispute, &account).is_ok());
+    }
+
+    #[test]
+    fn test_max_daily_volume() {
+        let rule = MaxDailyVolume::new(dec!(10));
+        let account = Account::default();
+
+        assert!(
+            rule.check(&Transaction::deposit(1, 1, dec!(6)), &account)
+                .passed
+        );
+        assert!(
+            rule.check(&Transaction::deposit(2, 2, dec!(6)), &account)
+                .passed
+        );
+        let result = rule.check(&Transaction::deposit(1, 3, dec!(5)), &account);
+        assert!(!result.passed && result.block);
+        // Blocked transactions don't count towards the volume
+        assert!(
+            rule.check(&Transaction::deposit(1, 4, dec!(4)), &account)
+                .passed
+        );
+        assert!(
+            !rule
+                .check(&Transaction::deposit(1, 5, dec!(0.0001)), &account)
+                .passed
+        );
+
+        rule.reset();
+        assert!(
+            rule.check(&Transaction::deposit(1, 6, dec!(10)), &account)
+                .passed
+        );
+    }
+}
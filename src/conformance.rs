@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::sync::RwLock;
+
+use crate::account::Account;
+use crate::csv_utils::{write_output, OutputFormat};
+use crate::error::source;
+use crate::state::LedgerState;
+use crate::transaction::{ClientID, TransactionData, TxID};
+use crate::transaction_processor::TransactionProcessor;
+use crate::AccountingError;
+
+// Every scenario is a directory under the conformance root with these files. Adding a scenario
+// only takes a new directory
+const INPUT: &str = "input.csv";
+const EXPECTED_OUTPUT: &str = "expected_output.csv";
+// Number of rows that are rejected or fail to apply
+const EXPECTED_REJECTS: &str = "expected_rejects.txt";
+
+pub struct ScenarioResult {
+    pub name: String,
+    // Why the scenario failed, None if it passed
+    pub failure: Option<String>,
+}
+
+// Runs every scenario under the root, in the order of their directory names
+pub async fn run_all(root: &Path) -> Result<Vec<ScenarioResult>, AccountingError> {
+    let input_error = |e: std::io::Error| AccountingError::Input(e.to_string(), source(e));
+    let mut scenarios = std::fs::read_dir(root)
+        .map_err(input_error)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()
+        .map_err(input_error)?;
+    scenarios.retain(|path| path.is_dir());
+    scenarios.sort();
+
+    let mut results = Vec::new();
+    for scenario in scenarios {
+        results.push(ScenarioResult {
+            name: scenario
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            failure: run_scenario(&scenario).await?,
+        });
+    }
+    Ok(results)
+}
+
+async fn run_scenario(scenario: &Path) -> Result<Option<String>, AccountingError> {
+    let read = |file: &str| {
+        std::fs::read_to_string(scenario.join(file))
+            .map_err(|e| AccountingError::Input(format!("{}: {}", file, e), source(e)))
+    };
+    let expected_output = read(EXPECTED_OUTPUT)?;
+    let expected_rejects = read(EXPECTED_REJECTS)?
+        .trim()
+        .parse::<usize>()
+        .map_err(|e| AccountingError::Input(format!("{}: {}", EXPECTED_REJECTS, e), source(e)))?;
+
+    let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+    let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+    let (processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+    let stats = processor.process_csv_file(&scenario.join(INPUT)).await?;
+
+    let mut output = Vec::new();
+    write_output(
+        accounts.read().await.values().cloned().collect(),
+        &mut output,
+        OutputFormat::Csv,
+    )?;
+    let output = String::from_utf8_lossy(&output);
+
+    let lines = |csv: &str| -> Vec<String> {
+        csv.lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect()
+    };
+    if lines(&output) != lines(&expected_output) {
+        return Ok(Some(format!(
+            "expected output:\n{}\nactual output:\n{}",
+            expected_output.trim_end(),
+            output.trim_end()
+        )));
+    }
+    let rejects = stats.rejected + stats.failed;
+    if rejects != expected_rejects {
+        return Ok(Some(format!(
+            "expected {} rejected rows, got {}",
+            expected_rejects, rejects
+        )));
+    }
+    // Not part of a scenario's files, the held balances must always match the disputes
+    let (accounts, ledger) = (accounts.read().await, ledger.read().await);
+    let mismatches = LedgerState::new(&accounts, &ledger).reconcile_held();
+    if !mismatches.is_empty() {
+        let mismatches: Vec<String> = mismatches.iter().map(|m| m.to_string()).collect();
+        return Ok(Some(format!(
+            "held balances don't match the disputes:\n{}",
+            mismatches.join("\n")
+        )));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_conformance_suite() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("conformance");
+        let results = run_all(&root).await.unwrap();
+        assert!(!results.is_empty());
+        for result in results {
+            if let Some(failure) = result.failure {
+                panic!("scenario {} failed: {}", result.name, failure);
+            }
+        }
+    }
+}
@@ -1,133 +1,1743 @@
+use std::collections::BTreeSet;
 use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::str::FromStr;
+use std::task::{Context, Poll};
 
+use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 
-use crate::transaction::{Amount, ClientID, Transaction, TransactionData, TxID};
+use crate::client_id_map::ClientIdMap;
+use crate::error::source;
+use crate::pipeline::TransactionSource;
+use crate::reorder::ReorderBuffer;
+use crate::rounding::LEDGER_ROUNDING;
+use crate::transaction::{
+    format_amount, validate_amount_precision, Amount, ClientID, CurrencyCode, Transaction, TxID,
+};
 use crate::Account;
 use crate::AccountingError;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ZeroAmountPolicy {
+    // Zero-amount deposits and withdrawals are passed on to the processor as no-ops
+    Allow,
+    #[default]
+    Reject,
+}
+
+// What happens to deposit and withdrawal amounts with more than four decimal places
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrecisionPolicy {
+    #[default]
+    Reject,
+    // Rounded half to even to four places, like amounts in the output
+    Round,
+}
+
+// Recovery from corrupted regions of the input, e.g. binary data spliced in by a broken upload.
+// Resyncing skips ahead to the next line that starts with a transaction type. It is triggered by the
+// given number of consecutive errors, or right away by a rejected record spanning several lines,
+// which happens when a stray quote makes the csv parser swallow the rows that follow it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResyncPolicy {
+    Disabled,
+    AfterErrors(usize),
+}
+
+impl Default for ResyncPolicy {
+    fn default() -> Self {
+        ResyncPolicy::AfterErrors(3)
+    }
+}
+
+// Bounds on the size of a single row, so a pathological input such as a 2 GB quoted field is rejected
+// before it is buffered whole. Oversized rows are rejected with their line and the reader carries on after them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLimits {
+    pub max_field_bytes: usize,
+    pub max_record_bytes: usize,
+}
+
+impl Default for RecordLimits {
+    fn default() -> Self {
+        RecordLimits {
+            max_field_bytes: 1024,
+            max_record_bytes: 16 * 1024,
+        }
+    }
+}
+
+// Processes each client's rows in the order given by a sequence number column rather than in
+// file order. See ReorderBuffer for how many rows are held back and how gaps are handled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceOrdering {
+    pub column: String,
+    pub window: usize,
+    pub strict: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TransactionReaderConfig {
+    pub zero_amount_policy: ZeroAmountPolicy,
+    pub precision_policy: PrecisionPolicy,
+    // When set the client column holds external ids, which are mapped onto client ids
+    pub client_id_map: Option<ClientIdMap>,
+    pub resync_policy: ResyncPolicy,
+    pub ordering: Option<SequenceOrdering>,
+    // Loads the dispute state of deposits and withdrawals from an under_dispute column, as written by
+    // ledger exports. Inputs with the column are rejected unless this is set
+    pub trust_dispute_column: bool,
+    pub record_limits: RecordLimits,
+    // Json input comes from our own services and is held to a strict schema, see JsonTransactionReader.
+    // When set it is parsed as tolerantly as csv instead
+    pub lenient_json: bool,
+}
+
+// The values of the type column, used to find the start of a valid row when resyncing
+pub(crate) const TRANSACTION_TYPES: [&str; 9] = [
+    "deposit",
+    "withdrawal",
+    "withdraw_all",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "settle",
+    "cancel",
+    "interest",
+];
+
+// Types are matched case-insensitively, and a few spellings partners commonly use are accepted as well
+fn normalize_transaction_type(transaction_type: &str) -> String {
+    let transaction_type = transaction_type.trim().to_ascii_lowercase();
+    match transaction_type.as_str() {
+        "withdraw" => "withdrawal".to_string(),
+        "charge_back" | "charge-back" => "chargeback".to_string(),
+        _ => transaction_type,
+    }
+}
+
+// Where the csv is read from. Streams such as stdin can't be seeked, so resyncing is only
+// available for files
+enum Input {
+    File(BufReader<File>),
+    Stream(BufReader<Box<dyn Read + Send>>),
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Input::File(input) => input.read(buf),
+            Input::Stream(input) => input.read(buf),
+        }
+    }
+}
+
+impl BufRead for Input {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        match self {
+            Input::File(input) => input.fill_buf(),
+            Input::Stream(input) => input.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amount: usize) {
+        match self {
+            Input::File(input) => input.consume(amount),
+            Input::Stream(input) => input.consume(amount),
+        }
+    }
+}
+
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Input::File(input) => input.seek(pos),
+            Input::Stream(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the input can't be seeked",
+            )),
+        }
+    }
+}
+
+// Size of the csv parser's read buffer. The parser reads at most this far past the record it is working on
+const READ_BUFFER: usize = 8 * 1024;
+
+// Keeps the csv parser from reading more than the record limit past the start of a record, so an oversized
+// record fails with bounded memory instead of being buffered until it ends
+struct Bounded {
+    input: Input,
+    // Offset of the next byte read from the input, and of the record being parsed
+    offset: u64,
+    record_start: u64,
+    limit: u64,
+    // Set when a read was refused, until the rest of the record is skipped
+    exceeded: bool,
+}
+
+impl Bounded {
+    fn start_record(&mut self, offset: u64) {
+        self.record_start = offset;
+        self.exceeded = false;
+    }
+
+    // Skips the rest of the current line without buffering it, returns the offset of the next line
+    fn skip_line(&mut self) -> std::io::Result<u64> {
+        self.offset += skip_line(&mut self.input)?;
+        self.exceeded = false;
+        Ok(self.offset)
+    }
+}
+
+impl Read for Bounded {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let allowed =
+            (self.record_start + self.limit + READ_BUFFER as u64).saturating_sub(self.offset);
+        if allowed == 0 {
+            self.exceeded = true;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "record is over the size limit",
+            ));
+        }
+        let len = buf.len().min(allowed as usize);
+        let read = self.input.read(&mut buf[..len])?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for Bounded {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        // A stream can only stay where it is, which is all that's needed to carry on after a skipped record
+        if let (Input::Stream(_), SeekFrom::Start(offset)) = (&self.input, pos) {
+            if offset == self.offset {
+                return Ok(offset);
+            }
+        }
+        self.offset = self.input.seek(pos)?;
+        Ok(self.offset)
+    }
+}
+
+// Consumes input up to and including the next newline, without holding more than a buffer of it in memory
+pub(crate) fn skip_line<R: BufRead>(input: &mut R) -> std::io::Result<u64> {
+    let mut skipped = 0;
+    loop {
+        let buf = input.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(skipped);
+        }
+        let (len, done) = match buf.iter().position(|byte| *byte == b'\n') {
+            Some(newline) => (newline + 1, true),
+            None => (buf.len(), false),
+        };
+        input.consume(len);
+        skipped += len as u64;
+        if done {
+            return Ok(skipped);
+        }
+    }
+}
+
+// Column holding the dispute state of a ledger export, see TransactionReaderConfig::trust_dispute_column
+const DISPUTE_COLUMN: &str = "under_dispute";
+
+// Inputs with the dispute column are rejected as a whole rather than loaded without their dispute state
+pub(crate) fn check_dispute_column(
+    headers: &csv::StringRecord,
+    config: &TransactionReaderConfig,
+) -> Result<(), String> {
+    if headers.iter().any(|header| header == DISPUTE_COLUMN) && !config.trust_dispute_column {
+        return Err(format!(
+            "the input has an {} column, which is only loaded when the dispute column is trusted",
+            DISPUTE_COLUMN
+        ));
+    }
+    Ok(())
+}
+
+// Index of the column that orders the rows, when ordering by sequence
+pub(crate) fn sequence_column(
+    headers: &csv::StringRecord,
+    config: &TransactionReaderConfig,
+) -> Result<Option<usize>, String> {
+    let Some(ordering) = &config.ordering else {
+        return Ok(None);
+    };
+    match headers.iter().position(|header| header == ordering.column) {
+        Some(column) => Ok(Some(column)),
+        None => Err(format!("no column named {} in the input", ordering.column)),
+    }
+}
+
+pub(crate) fn sequence_number(row: &csv::StringRecord, column: Option<usize>) -> Option<u64> {
+    column
+        .and_then(|column| row.get(column))
+        .and_then(|sequence| sequence.parse::<u64>().ok())
+}
+
 pub struct TransactionReader {
-    bufreader: csv::Reader<BufReader<File>>,
+    bufreader: csv::Reader<Bounded>,
+    headers: csv::StringRecord,
+    config: TransactionReaderConfig,
+    consecutive_errors: usize,
+    // Byte ranges of the input skipped by resyncing
+    skipped: Vec<Range<u64>>,
+    // Index of the sequence column and the rows waiting for their turn, when ordering by sequence
+    sequence_column: Option<usize>,
+    reorder: Option<ReorderBuffer>,
+    // Separator of the fields, also used to recognize the start of a row when resyncing
+    delimiter: u8,
 }
 
 impl TransactionReader {
-    // Creates and returns a buffered csv reader, avoids loading the entire input file into memory
     pub fn new(csv_path: String) -> Result<Self, Box<dyn Error>> {
-        let file = File::open(csv_path)?;
-        let buffered_reader = BufReader::new(file);
-        let csv_reader = csv::ReaderBuilder::new()
+        TransactionReader::with_config(csv_path, TransactionReaderConfig::default())
+    }
+
+    // Creates and returns a buffered csv reader, avoids loading the entire input file into memory.
+    // A path of "-" or "" reads from stdin instead
+    pub fn with_config(
+        csv_path: String,
+        config: TransactionReaderConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        TransactionReaderBuilder::new()
+            .config(config)
+            .build(csv_path)
+    }
+
+    // Reads the csv from any stream, e.g. stdin or a buffer in memory. Streams are read once from
+    // start to end, so corrupted regions are rejected row by row rather than resynced
+    pub fn from_reader<R: Read + Send + 'static>(
+        reader: R,
+        config: TransactionReaderConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        TransactionReaderBuilder::new()
+            .config(config)
+            .build_from_reader(reader)
+    }
+
+    fn from_input(
+        input: Input,
+        config: TransactionReaderConfig,
+        delimiter: u8,
+    ) -> Result<Self, Box<dyn Error>> {
+        let limit = config.record_limits.max_record_bytes as u64;
+        let mut csv_reader = csv::ReaderBuilder::new()
             .trim(csv::Trim::All)
-            .delimiter(b',')
+            .delimiter(delimiter)
             .has_headers(true)
             .flexible(true)
-            .from_reader(buffered_reader);
+            .buffer_capacity(READ_BUFFER)
+            .from_reader(Bounded {
+                input,
+                offset: 0,
+                record_start: 0,
+                limit,
+                exceeded: false,
+            });
+        // Read the headers up front so the reader's position is always at the start of a record
+        let headers = csv_reader.headers()?.clone();
+        check_dispute_column(&headers, &config)?;
+        let sequence_column = sequence_column(&headers, &config)?;
+        let reorder = config
+            .ordering
+            .as_ref()
+            .map(|ordering| ReorderBuffer::new(ordering.window, ordering.strict));
         Ok(TransactionReader {
             bufreader: csv_reader,
+            headers,
+            config,
+            consecutive_errors: 0,
+            skipped: Vec::new(),
+            sequence_column,
+            reorder,
+            delimiter,
         })
     }
 
     pub fn get_next_record(&mut self) -> Result<Option<Transaction>, AccountingError> {
-        if let Some(record) = self.bufreader.deserialize().next() {
-            let event: Record = match record {
-                Ok(r) => r,
-                Err(e) => return Err(AccountingError::Deserialize(e.to_string())),
-            };
+        if self.reorder.is_none() {
+            return self.read_next().map(|tx| tx.map(|(tx, _)| tx));
+        }
+        loop {
+            let reorder = self.reorder.as_mut().unwrap();
+            if let Some(result) = reorder.pop_ready() {
+                return result.map(Some);
+            }
+            match self.read_next()? {
+                Some((tx, Some(sequence))) => self.reorder.as_mut().unwrap().push(sequence, tx),
+                Some((_, None)) => return Err(AccountingError::MalformedTransaction),
+                None => {
+                    let reorder = self.reorder.as_mut().unwrap();
+                    reorder.finish();
+                    return reorder.pop_ready().transpose();
+                }
+            }
+        }
+    }
 
-            if let Some(tx) = TransactionReader::record_to_transaction(event) {
-                return Ok(Some(tx));
-            } else {
-                return Err(AccountingError::MalformedTransaction);
+    // Reads the next row in file order, together with its sequence number when ordering by sequence
+    fn read_next(&mut self) -> Result<Option<(Transaction, Option<u64>)>, AccountingError> {
+        let start = self.bufreader.position().clone();
+        self.bufreader.get_mut().start_record(start.byte());
+        let mut row = csv::StringRecord::new();
+        let read = self.bufreader.read_record(&mut row);
+        if self.bufreader.get_ref().exceeded {
+            return Err(self.skip_oversized(&start));
+        }
+        if !matches!(read, Ok(false)) {
+            let sequence = sequence_number(&row, self.sequence_column);
+            let result = self.config.row_to_transaction(
+                &self.headers,
+                read.map(|_| &row),
+                start.line(),
+                self.bufreader.position().byte() - start.byte(),
+            );
+            match result {
+                Ok(_) => self.consecutive_errors = 0,
+                Err(_) => {
+                    self.consecutive_errors += 1;
+                    let spans_lines = self.bufreader.position().line() > start.line() + 1;
+                    if let ResyncPolicy::AfterErrors(limit) = self.config.resync_policy {
+                        if spans_lines || self.consecutive_errors >= limit {
+                            self.resync(start.byte())?;
+                        }
+                    }
+                }
             }
+            return result.map(|tx| Some((tx, sequence)));
         }
         // No more transactions should not be an error, so return Ok(None)
         Ok(None)
     }
 
+    // Moves the reader past the line of a record that went over the size limit. Everything read so far
+    // belongs to the record, so the rest of its line is skipped straight from the input
+    fn skip_oversized(&mut self, start: &csv::Position) -> AccountingError {
+        let error = AccountingError::OversizedRecord(
+            start.line(),
+            self.config.record_limits.max_record_bytes,
+        );
+        let offset = match self.bufreader.get_mut().skip_line() {
+            Ok(offset) => offset,
+            Err(e) => return AccountingError::Input(e.to_string(), source(e)),
+        };
+        let mut position = csv::Position::new();
+        position.set_byte(offset).set_line(start.line() + 1);
+        if let Err(e) = self.bufreader.seek(position) {
+            return AccountingError::Input(e.to_string(), source(e));
+        }
+        error
+    }
+
+    // Moves the reader to the first line after `from` that starts with a transaction type,
+    // or to the end of the input if there is none
+    fn resync(&mut self, from: u64) -> Result<(), AccountingError> {
+        let input_error = |e: std::io::Error| AccountingError::Input(e.to_string(), source(e));
+        let input = &mut self.bufreader.get_mut().input;
+        input.seek(SeekFrom::Start(from)).map_err(input_error)?;
+        let mut line = Vec::new();
+        // The first line is the start of the rejected record, so it is always skipped
+        let mut offset = from + input.read_until(b'\n', &mut line).map_err(input_error)? as u64;
+        loop {
+            line.clear();
+            let read = input.read_until(b'\n', &mut line).map_err(input_error)?;
+            if read == 0 || starts_with_transaction_type(&line, self.delimiter) {
+                break;
+            }
+            offset += read as u64;
+        }
+
+        let mut position = csv::Position::new();
+        position.set_byte(offset);
+        self.bufreader
+            .seek(position)
+            .map_err(|e| AccountingError::Input(e.to_string(), source(e)))?;
+        self.consecutive_errors = 0;
+        self.skipped.push(from..offset);
+        Ok(())
+    }
+}
+
+// Creates a TransactionReader for csv that isn't comma separated, such as the tab or semicolon
+// separated files some banking partners send
+#[derive(Debug, Clone)]
+pub struct TransactionReaderBuilder {
+    config: TransactionReaderConfig,
+    delimiter: u8,
+}
+
+impl Default for TransactionReaderBuilder {
+    fn default() -> Self {
+        TransactionReaderBuilder {
+            config: TransactionReaderConfig::default(),
+            delimiter: b',',
+        }
+    }
+}
+
+impl TransactionReaderBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn config(mut self, config: TransactionReaderConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    // A single byte, e.g. b'\t' or b';'
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    // Opens the file at the given path, or reads stdin for a path of "-" or ""
+    pub fn build(self, csv_path: String) -> Result<TransactionReader, Box<dyn Error>> {
+        if csv_path.is_empty() || csv_path == "-" {
+            return self.build_from_reader(std::io::stdin());
+        }
+        let file = File::open(csv_path)?;
+        // Named pipes and devices can't be seeked either, so they are read like stdin. Reads block
+        // until the writer has more data or closes its end
+        if !file.metadata()?.is_file() {
+            return self.build_from_reader(file);
+        }
+        TransactionReader::from_input(
+            Input::File(BufReader::new(file)),
+            self.config,
+            self.delimiter,
+        )
+    }
+
+    // See TransactionReader::from_reader
+    pub fn build_from_reader<R: Read + Send + 'static>(
+        mut self,
+        reader: R,
+    ) -> Result<TransactionReader, Box<dyn Error>> {
+        self.config.resync_policy = ResyncPolicy::Disabled;
+        let input = Input::Stream(BufReader::new(Box::new(reader)));
+        TransactionReader::from_input(input, self.config, self.delimiter)
+    }
+}
+
+impl TransactionSource for TransactionReader {
+    fn poll_transaction(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Transaction>, AccountingError>> {
+        Poll::Ready(self.get_next_record())
+    }
+
+    fn skipped_ranges(&self) -> &[Range<u64>] {
+        &self.skipped
+    }
+
+    fn reorder_buffer(&self) -> Option<&ReorderBuffer> {
+        self.reorder.as_ref()
+    }
+
+    fn client_id_map(&self) -> Option<&ClientIdMap> {
+        self.config.client_id_map.as_ref()
+    }
+
+    fn take_client_id_map(&mut self) -> Option<ClientIdMap> {
+        self.config.client_id_map.take()
+    }
+
+    fn set_client_id_map(&mut self, client_id_map: ClientIdMap) {
+        self.config.client_id_map = Some(client_id_map);
+    }
+}
+
+// Yields every record, including the rejected ones as errors, and ends with the input
+impl Iterator for TransactionReader {
+    type Item = Result<Transaction, AccountingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.get_next_record().transpose()
+    }
+}
+
+// Turning records into transactions only depends on the config, so every input format shares it
+impl TransactionReaderConfig {
+    // Checks the size of a csv row and turns it into a transaction. Both csv readers go through this, so they
+    // reject the same rows with the same errors. `line` is where the row starts, `bytes` the input it took up
+    pub(crate) fn row_to_transaction(
+        &mut self,
+        headers: &csv::StringRecord,
+        row: csv::Result<&csv::StringRecord>,
+        line: u64,
+        bytes: u64,
+    ) -> Result<Transaction, AccountingError> {
+        let deserialize_error = |e: csv::Error| {
+            AccountingError::Deserialize(
+                format!("line {}: {}", line, deserialize_message(&e)),
+                source(e),
+            )
+        };
+        let row = row.map_err(deserialize_error)?;
+        let limits = self.record_limits;
+        let result = match row.deserialize::<Record>(Some(headers)) {
+            Ok(_) if bytes > limits.max_record_bytes as u64 => Err(
+                AccountingError::OversizedRecord(line, limits.max_record_bytes),
+            ),
+            Ok(_) if row.iter().any(|field| field.len() > limits.max_field_bytes) => Err(
+                AccountingError::OversizedField(line, limits.max_field_bytes),
+            ),
+            Ok(record) => self.record_to_transaction(record),
+            Err(e) => Err(deserialize_error(e)),
+        };
+        result.map_err(|e| match e {
+            AccountingError::MalformedTransaction => {
+                AccountingError::MalformedRecord(line, describe_row(headers, row))
+            }
+            e => e,
+        })
+    }
+
     // Transforms the Record struct into the Transaction enum with inner TransactionData
-    fn record_to_transaction(record: Record) -> Option<Transaction> {
-        if let Some(transaction_type) = record.transaction_type {
-            match transaction_type.as_str() {
-                "deposit" => {
-                    record.amount?;
-                    record.client?;
-                    record.tx?;
-                    if record.amount.unwrap() <= dec!(0) {
-                        return None;
-                    }
-                    Some(Transaction::Deposit(TransactionData {
-                        client_id: record.client.unwrap(),
-                        tx_id: record.tx.unwrap(),
-                        amount: Some(record.amount.unwrap()),
-                        under_dispute: false,
-                    }))
-                }
-                "withdrawal" => {
-                    record.amount?;
-                    record.client?;
-                    record.tx?;
-                    if record.amount.unwrap() <= dec!(0) {
-                        return None;
-                    }
-                    Some(Transaction::Withdrawal(TransactionData {
-                        client_id: record.client.unwrap(),
-                        tx_id: record.tx.unwrap(),
-                        amount: Some(record.amount.unwrap()),
-                        under_dispute: false,
-                    }))
-                }
-                "dispute" => {
-                    record.client?;
-                    record.tx?;
-                    Some(Transaction::Dispute(TransactionData {
-                        client_id: record.client.unwrap(),
-                        tx_id: record.tx.unwrap(),
-                        amount: None,
-                        under_dispute: false,
-                    }))
-                }
-                "resolve" => {
-                    record.client?;
-                    record.tx?;
-                    Some(Transaction::Resolve(TransactionData {
-                        client_id: record.client.unwrap(),
-                        tx_id: record.tx.unwrap(),
-                        amount: None,
-                        under_dispute: false,
-                    }))
-                }
-                "chargeback" => {
-                    record.client?;
-                    record.tx?;
-                    Some(Transaction::Chargeback(TransactionData {
-                        client_id: record.client.unwrap(),
-                        tx_id: record.tx.unwrap(),
-                        amount: None,
-                        under_dispute: false,
-                    }))
+    pub(crate) fn record_to_transaction(
+        &mut self,
+        mut record: Record,
+    ) -> Result<Transaction, AccountingError> {
+        // Unknown types are reported as sent, rather than as a malformed record
+        if let Some(transaction_type) = record.transaction_type.as_mut() {
+            let normalized = normalize_transaction_type(transaction_type);
+            if !TRANSACTION_TYPES.contains(&normalized.as_str()) {
+                return Err(AccountingError::UnknownTransactionType(
+                    transaction_type.clone(),
+                ));
+            }
+            *transaction_type = normalized;
+        }
+        // Csv inputs with the column are rejected up front, other formats carry it per record
+        if record.under_dispute.is_some() && !self.trust_dispute_column {
+            return Err(AccountingError::MalformedTransaction);
+        }
+        let amount = match record.amount.as_deref() {
+            Some(amount) => Some(self.parse_amount(amount, record.tx)?),
+            None => None,
+        };
+        // Told apart from malformed records, so callers can decide to skip them or abort
+        if let (Some(amount), Some("deposit" | "withdrawal")) =
+            (amount, record.transaction_type.as_deref())
+        {
+            self.check_amount(amount)?;
+        }
+        let client = match record.client.as_deref() {
+            Some(client) => Some(self.resolve_client(client)?),
+            None => None,
+        };
+        #[cfg(feature = "multi-currency")]
+        let currency = match record.currency.as_deref() {
+            Some(currency) => Some(currency.parse::<CurrencyCode>()?),
+            None => None,
+        };
+        #[cfg(not(feature = "multi-currency"))]
+        let currency = None;
+        self.build_transaction(record, client, amount, currency)
+            .ok_or(AccountingError::MalformedTransaction)
+    }
+
+    // Amounts are parsed here rather than by serde so that excess precision can be rejected or rounded
+    fn parse_amount(&self, amount: &str, tx: Option<TxID>) -> Result<Amount, AccountingError> {
+        let amount = Amount::from_str(amount)
+            .map_err(|e| AccountingError::Deserialize(e.to_string(), source(e)))?;
+        match (validate_amount_precision(amount, tx), self.precision_policy) {
+            (Err(AccountingError::PrecisionExceeded(..)), PrecisionPolicy::Round) => {
+                Ok(LEDGER_ROUNDING.round(amount))
+            }
+            (result, _) => result.map(|()| amount),
+        }
+    }
+
+    fn resolve_client(&mut self, client: &str) -> Result<ClientID, AccountingError> {
+        match self.client_id_map.as_mut() {
+            Some(client_id_map) => client_id_map.resolve(client),
+            None => client
+                .parse::<ClientID>()
+                .map_err(|_| AccountingError::MalformedTransaction),
+        }
+    }
+
+    fn build_transaction(
+        &self,
+        record: Record,
+        client: Option<ClientID>,
+        amount: Option<Amount>,
+        currency: Option<CurrencyCode>,
+    ) -> Option<Transaction> {
+        let client = client?;
+        let transaction = match record.transaction_type?.as_str() {
+            "deposit" => Transaction::deposit(client, record.tx?, amount?)
+                .under_dispute(record.under_dispute.unwrap_or_default()),
+            "withdrawal" => Transaction::withdrawal(client, record.tx?, amount?)
+                .under_dispute(record.under_dispute.unwrap_or_default()),
+            "withdraw_all" => Transaction::withdraw_all(client, record.tx?),
+            "dispute" => Transaction::dispute(client, record.tx?),
+            "resolve" => Transaction::resolve(client, record.tx?),
+            "chargeback" => Transaction::chargeback(client, record.tx?),
+            "settle" => Transaction::settle(client, record.tx?),
+            "cancel" => Transaction::cancel(client, record.tx?),
+            "interest" => {
+                let rate = record.rate.filter(|rate| *rate >= dec!(0))?;
+                Transaction::interest(client, rate, record.period_days?)
+            }
+            _ => return None,
+        };
+        Some(transaction.in_currency(currency))
+    }
+
+    fn check_amount(&self, amount: Amount) -> Result<(), AccountingError> {
+        if amount < dec!(0) {
+            return Err(AccountingError::NegativeAmount);
+        }
+        if amount.is_zero() && self.zero_amount_policy == ZeroAmountPolicy::Reject {
+            return Err(AccountingError::ZeroAmount);
+        }
+        Ok(())
+    }
+}
+
+// The csv error without its position, which is reported as the line of the row instead
+fn deserialize_message(error: &csv::Error) -> String {
+    match error.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+        _ => error.to_string(),
+    }
+}
+
+// The fields of a row as name=value pairs, e.g. type=deposit client=7 tx=99 amount=<missing>
+fn describe_row(headers: &csv::StringRecord, row: &csv::StringRecord) -> String {
+    headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| {
+            let value = row.get(i).filter(|value| !value.is_empty());
+            format!("{}={}", header, value.unwrap_or("<missing>"))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub(crate) fn starts_with_transaction_type(line: &[u8], delimiter: u8) -> bool {
+    line.iter()
+        .position(|byte| *byte == delimiter)
+        .is_some_and(|end| {
+            let transaction_type = String::from_utf8_lossy(&line[..end]);
+            TRANSACTION_TYPES.contains(&normalize_transaction_type(&transaction_type).as_str())
+        })
+}
+
+// Format of the account report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    // A single json array of accounts
+    Json,
+    // One json object per line
+    JsonLines,
+}
+
+pub fn print_output(output: Vec<Account>, format: OutputFormat) -> Result<(), AccountingError> {
+    write_output(output, std::io::stdout(), format)
+}
+
+// Writes the report next to the target first and renames it into place, so a crash mid-write
+// leaves either the previous file or the complete report, never a truncated one
+pub fn write_output_file<P: AsRef<std::path::Path>>(
+    output: Vec<Account>,
+    path: P,
+    format: OutputFormat,
+) -> Result<(), AccountingError> {
+    let path = path.as_ref();
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+    let output_error = |e: std::io::Error| {
+        AccountingError::Output(format!("{}: {}", path.display(), e), source(e))
+    };
+
+    let file = std::fs::File::create(&tmp_path).map_err(output_error)?;
+    let result =
+        write_output(output, &file, format).and_then(|_| file.sync_all().map_err(output_error));
+    if let Err(e) = result.and_then(|_| std::fs::rename(&tmp_path, path).map_err(output_error)) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// A row as written by TransactionWriter, with the columns TransactionReader reads
+#[derive(serde::Serialize)]
+struct TransactionRow {
+    #[serde(rename = "type")]
+    transaction_type: &'static str,
+    client: ClientID,
+    tx: Option<TxID>,
+    amount: Option<String>,
+    rate: Option<String>,
+    period_days: Option<u32>,
+    #[cfg(feature = "multi-currency")]
+    currency: Option<String>,
+}
+
+// Writes transactions as csv that TransactionReader reads back into the same transactions, with
+// amounts at four decimal places. The header is written with the first transaction
+pub struct TransactionWriter<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> TransactionWriter<W> {
+    pub fn new(out: W) -> Self {
+        TransactionWriter {
+            writer: csv::Writer::from_writer(out),
+        }
+    }
+
+    pub fn write_transaction(&mut self, tx: &Transaction) -> Result<(), AccountingError> {
+        let mut row = TransactionRow {
+            transaction_type: tx.kind(),
+            client: tx.client_id(),
+            tx: tx.tx_id(),
+            amount: None,
+            rate: None,
+            period_days: None,
+            #[cfg(feature = "multi-currency")]
+            currency: None,
+        };
+        match tx {
+            Transaction::Interest(interest) => {
+                row.rate = Some(interest.rate.to_string());
+                row.period_days = Some(interest.period_days);
+            }
+            Transaction::Deposit(data)
+            | Transaction::Withdrawal(data)
+            | Transaction::WithdrawAll(data)
+            | Transaction::Resolve(data)
+            | Transaction::Dispute(data)
+            | Transaction::Chargeback(data)
+            | Transaction::Settle(data)
+            | Transaction::Cancel(data) => {
+                row.amount = data.amount.map(format_amount);
+                #[cfg(feature = "multi-currency")]
+                {
+                    row.currency = data.currency.map(|currency| currency.to_string());
                 }
-                _ => None,
             }
-        } else {
-            None
         }
+        self.writer
+            .serialize(row)
+            .map_err(|e| AccountingError::Output(e.to_string(), source(e)))
+    }
+
+    pub fn flush(&mut self) -> Result<(), AccountingError> {
+        self.writer
+            .flush()
+            .map_err(|e| AccountingError::Output(e.to_string(), source(e)))
     }
 }
-pub fn print_output(output: Vec<Account>) {
-    let mut writer = csv::Writer::from_writer(std::io::stdout());
-    for mut account in output {
-        account.normalize_values();
-        writer.serialize(account).unwrap();
+
+// Amounts are written as strings with four decimal places in every format, see format_amount
+pub fn write_output<W: std::io::Write>(
+    output: Vec<Account>,
+    mut out: W,
+    format: OutputFormat,
+) -> Result<(), AccountingError> {
+    fn output_error<E: Error + Send + Sync + 'static>(e: E) -> AccountingError {
+        AccountingError::Output(e.to_string(), source(e))
+    }
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut out);
+            for account in output {
+                writer.serialize(account).map_err(output_error)?;
+            }
+            writer.flush().map_err(output_error)?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut out, &output).map_err(output_error)?;
+            writeln!(out).map_err(output_error)?;
+        }
+        OutputFormat::JsonLines => {
+            for account in output {
+                serde_json::to_writer(&mut out, &account).map_err(output_error)?;
+                writeln!(out).map_err(output_error)?;
+            }
+        }
+    }
+    out.flush().map_err(output_error)?;
+    Ok(())
+}
+
+// Reads a csv file with a client column, e.g. the clients of a withdrawal deny-list
+pub fn read_client_list<P: AsRef<std::path::Path>>(
+    path: P,
+) -> Result<BTreeSet<ClientID>, AccountingError> {
+    #[derive(serde::Deserialize)]
+    struct ClientRecord {
+        client: ClientID,
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(read_error)?;
+    reader
+        .deserialize()
+        .map(|record| {
+            record
+                .map(|record: ClientRecord| record.client)
+                .map_err(read_error)
+        })
+        .collect()
+}
+
+// A file that can't be opened or read is an input error, only a row that can't be parsed is a
+// deserialize error
+pub(crate) fn read_error(e: csv::Error) -> AccountingError {
+    if e.is_io_error() {
+        AccountingError::Input(e.to_string(), source(e))
+    } else {
+        AccountingError::Deserialize(e.to_string(), source(e))
     }
-    writer.flush().unwrap();
 }
 
 #[derive(serde::Deserialize, Debug)]
-struct Record {
+pub(crate) struct Record {
     #[serde(rename = "type")]
-    transaction_type: Option<String>,
-    client: Option<ClientID>,
-    tx: Option<TxID>,
-    amount: Option<Amount>,
+    pub(crate) transaction_type: Option<String>,
+    // Parsed as a string so it can also hold external ids when a client id map is used
+    pub(crate) client: Option<String>,
+    pub(crate) tx: Option<TxID>,
+    pub(crate) amount: Option<String>,
+    // Only used by interest rows, files without these columns are fine
+    pub(crate) rate: Option<Decimal>,
+    pub(crate) period_days: Option<u32>,
+    // Only present in ledger exports, see TransactionReaderConfig::trust_dispute_column
+    pub(crate) under_dispute: Option<bool>,
+    #[cfg(feature = "multi-currency")]
+    pub(crate) currency: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reorder::SequenceGap;
+
+    fn reader_for(
+        name: &str,
+        contents: &str,
+        config: TransactionReaderConfig,
+    ) -> TransactionReader {
+        let path =
+            std::env::temp_dir().join(format!("transactron_{}_{}.csv", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        TransactionReader::with_config(path.to_string_lossy().into_owned(), config).unwrap()
+    }
+
+    #[test]
+    fn test_zero_amount_rejected_by_default() {
+        let mut reader = reader_for(
+            "zero_amount_rejected",
+            "type, client, tx, amount\ndeposit, 1, 1, 0\nwithdrawal, 1, 2, 0.0\n",
+            TransactionReaderConfig::default(),
+        );
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::ZeroAmount)
+        ));
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::ZeroAmount)
+        ));
+        assert!(matches!(reader.get_next_record(), Ok(None)));
+    }
+
+    #[test]
+    fn test_iterate_records() {
+        let reader = reader_for(
+            "iterate_records",
+            "type, client, tx, amount\ndeposit, 1, 1, 5\nbogus, 1, 2, 1\nwithdrawal, 2, 3, 1.5\ndeposit, 1, 4, 0\n",
+            TransactionReaderConfig::default(),
+        );
+        let results: Vec<_> = reader.collect();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 2);
+        assert!(matches!(
+            results[1],
+            Err(AccountingError::UnknownTransactionType(_))
+        ));
+        assert!(matches!(results[3], Err(AccountingError::ZeroAmount)));
+
+        let transactions: Vec<_> = results.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(
+            transactions
+                .iter()
+                .map(|tx| (tx.kind(), tx.client_id(), tx.tx_id()))
+                .collect::<Vec<_>>(),
+            vec![("deposit", 1, Some(1)), ("withdrawal", 2, Some(3))]
+        );
+        match &transactions[1] {
+            Transaction::Withdrawal(tx) => assert_eq!(tx.amount, Some(dec!(1.5))),
+            other => panic!("expected withdrawal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_transaction_writer_round_trip() {
+        let transactions = vec![
+            Transaction::deposit(1, 1, dec!(2.5)),
+            Transaction::deposit(2, 2, dec!(0.0001)),
+            Transaction::withdrawal(1, 3, dec!(1)),
+            Transaction::withdraw_all(2, 4),
+            Transaction::dispute(1, 1),
+            Transaction::resolve(1, 1),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+            Transaction::settle(1, 3),
+            Transaction::cancel(1, 3),
+            Transaction::interest(2, dec!(0.05), 30),
+        ];
+        let mut csv = Vec::new();
+        let mut writer = TransactionWriter::new(&mut csv);
+        for tx in &transactions {
+            writer.write_transaction(tx).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        assert!(csv.starts_with(b"type,client,tx,amount,rate,period_days"));
+
+        let reader =
+            TransactionReader::from_reader(std::io::Cursor::new(csv), Default::default()).unwrap();
+        let read_back: Vec<Transaction> = reader.map(Result::unwrap).collect();
+        assert_eq!(read_back, transactions);
+    }
+
+    #[test]
+    fn test_transaction_type_case_and_aliases() {
+        let mut reader = reader_for(
+            "transaction_type_case",
+            "type, client, tx, amount\nDeposit, 1, 1, 5\n  WITHDRAWAL  , 1, 2, 1\nwithdraw, 1, 3, 1\nDispute, 1, 1,\nCharge-Back, 1, 1,\ncharge_back, 1, 1,\nWithdraw_All, 1, 4,\n",
+            TransactionReaderConfig::default(),
+        );
+        let kinds: Vec<_> = std::iter::from_fn(|| reader.get_next_record().unwrap())
+            .map(|tx| tx.kind())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "deposit",
+                "withdrawal",
+                "withdrawal",
+                "dispute",
+                "chargeback",
+                "chargeback",
+                "withdraw_all"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_transaction_type() {
+        let mut reader = reader_for(
+            "unknown_transaction_type",
+            "type, client, tx, amount\nrefund, 1, 1, 5\nDeposit, 1, 2, 5\n",
+            TransactionReaderConfig::default(),
+        );
+        match reader.get_next_record() {
+            Err(AccountingError::UnknownTransactionType(transaction_type)) => {
+                assert_eq!(transaction_type, "refund")
+            }
+            other => panic!("expected an unknown type, got {:?}", other),
+        }
+        assert!(matches!(
+            reader.get_next_record(),
+            Ok(Some(Transaction::Deposit(_)))
+        ));
+    }
+
+    #[test]
+    fn test_amount_precision() {
+        let mut reader = reader_for(
+            "amount_precision",
+            "type, client, tx, amount\ndeposit, 1, 1, 1.23456\ndeposit, 1, 2, 1.2345\nwithdrawal, 1, 3, 1.23456\nwithdrawal, 1, 4, 1.2345\n",
+            TransactionReaderConfig::default(),
+        );
+        for tx_id in [1, 3] {
+            match reader.get_next_record() {
+                Err(AccountingError::PrecisionExceeded(Some(tx), 5)) if tx == tx_id => (),
+                other => panic!("expected tx {} to be rejected, got {:?}", tx_id, other),
+            }
+            match reader.get_next_record() {
+                Ok(Some(Transaction::Deposit(tx) | Transaction::Withdrawal(tx))) => {
+                    assert_eq!(tx.amount, Some(dec!(1.2345)))
+                }
+                other => panic!("expected tx {} to pass, got {:?}", tx_id + 1, other),
+            }
+        }
+        assert!(matches!(reader.get_next_record(), Ok(None)));
+    }
+
+    #[test]
+    fn test_round_excess_precision() {
+        let mut reader = reader_for(
+            "round_excess_precision",
+            "type, client, tx, amount\ndeposit, 1, 1, 1.23455\nwithdrawal, 1, 2, 0.000049\ndeposit, 1, 3, 1.2345\n",
+            TransactionReaderConfig {
+                precision_policy: PrecisionPolicy::Round,
+                ..Default::default()
+            },
+        );
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Deposit(tx))) => assert_eq!(tx.amount, Some(dec!(1.2346))),
+            other => panic!("expected a rounded deposit, got {:?}", other),
+        }
+        // Rounded to nothing, which is rejected like any other zero amount
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::ZeroAmount)
+        ));
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Deposit(tx))) => assert_eq!(tx.amount, Some(dec!(1.2345))),
+            other => panic!("expected deposit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_negative_amount_rejected() {
+        let mut reader = reader_for(
+            "negative_amount",
+            "type, client, tx, amount\nwithdrawal, 1, 1, -2.5\ndeposit, 1, 2\ndispute, 1, 2, -1\n",
+            TransactionReaderConfig::default(),
+        );
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::NegativeAmount)
+        ));
+        // A missing amount is still a malformed record, and only deposits and withdrawals are checked
+        match reader.get_next_record() {
+            Err(AccountingError::MalformedRecord(line, fields)) => {
+                assert_eq!(line, 3);
+                assert_eq!(fields, "type=deposit client=1 tx=2 amount=<missing>");
+            }
+            other => panic!("expected a malformed record, got {:?}", other),
+        }
+        assert!(matches!(
+            reader.get_next_record(),
+            Ok(Some(Transaction::Dispute(_)))
+        ));
+    }
+
+    #[test]
+    fn test_zero_amount_allowed() {
+        let mut reader = reader_for(
+            "zero_amount_allowed",
+            "type, client, tx, amount\ndeposit, 1, 1, 0\nwithdrawal, 1, 2, 0.0\ndeposit, 1, 3, -1\n",
+            TransactionReaderConfig {
+                zero_amount_policy: ZeroAmountPolicy::Allow,
+                ..Default::default()
+            },
+        );
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Deposit(tx))) => assert_eq!(tx.amount, Some(dec!(0))),
+            other => panic!("expected zero deposit, got {:?}", other),
+        }
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Withdrawal(tx))) => assert_eq!(tx.amount, Some(dec!(0))),
+            other => panic!("expected zero withdrawal, got {:?}", other),
+        }
+        // Negative amounts are still rejected
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::NegativeAmount)
+        ));
+    }
+
+    #[test]
+    fn test_interest_row() {
+        let mut reader = reader_for(
+            "interest_row",
+            "type, client, tx, amount, rate, period_days\ninterest, 1, , , 0.05, 30\ninterest, 1, , , -0.05, 30\ndeposit, 1, 1, 2.0\n",
+            TransactionReaderConfig::default(),
+        );
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Interest(interest))) => {
+                assert_eq!(interest.client_id, 1);
+                assert_eq!(interest.rate, dec!(0.05));
+                assert_eq!(interest.period_days, 30);
+            }
+            other => panic!("expected interest, got {:?}", other),
+        }
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::MalformedRecord(3, _))
+        ));
+        assert!(matches!(
+            reader.get_next_record(),
+            Ok(Some(Transaction::Deposit(_)))
+        ));
+    }
+
+    #[cfg(feature = "multi-currency")]
+    #[test]
+    fn test_currency_column() {
+        let mut reader = reader_for(
+            "currency_column",
+            "type, client, tx, amount, currency\ndeposit, 1, 1, 2.0, USD\ndeposit, 1, 2, 1.0\ndeposit, 1, 3, 1.0, usd\n",
+            TransactionReaderConfig::default(),
+        );
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Deposit(tx))) => {
+                assert_eq!(tx.currency, Some(CurrencyCode(*b"USD")))
+            }
+            other => panic!("expected deposit, got {:?}", other),
+        }
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Deposit(tx))) => assert_eq!(tx.currency, None),
+            other => panic!("expected deposit, got {:?}", other),
+        }
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::MalformedRecord(4, _))
+        ));
+    }
+
+    const EXPORT: &str = "type, client, tx, amount, under_dispute
+deposit, 1, 1, 5.0, true
+deposit, 1, 2, 1.0, false
+withdrawal, 1, 3, 2.0, true
+dispute, 1, 2,,
+";
+
+    #[test]
+    fn test_dispute_column_requires_trust() {
+        let path = std::env::temp_dir().join(format!(
+            "transactron_dispute_column_{}.csv",
+            std::process::id()
+        ));
+        std::fs::write(&path, EXPORT).unwrap();
+        let error = TransactionReader::new(path.to_string_lossy().into_owned())
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("under_dispute"));
+    }
+
+    #[test]
+    fn test_trusted_dispute_column() {
+        let config = TransactionReaderConfig {
+            trust_dispute_column: true,
+            ..Default::default()
+        };
+        let mut reader = TransactionReader::from_reader(EXPORT.as_bytes(), config).unwrap();
+        let mut disputed = Vec::new();
+        while let Some(tx) = reader.get_next_record().unwrap() {
+            match tx {
+                Transaction::Deposit(tx) | Transaction::Withdrawal(tx) => {
+                    disputed.push((tx.tx_id, tx.under_dispute()))
+                }
+                Transaction::Dispute(tx) => assert!(!tx.under_dispute()),
+                other => panic!("unexpected transaction {:?}", other),
+            }
+        }
+        assert_eq!(disputed, vec![(1, true), (2, false), (3, true)]);
+    }
+
+    #[test]
+    fn test_client_id_mapping() {
+        let mut reader = reader_for(
+            "client_id_mapping",
+            "type, client, tx, amount\ndeposit, partner-a, 1, 1.0\ndeposit, partner-b, 2, 1.0\ndispute, partner-a, 1,\ndeposit, 3, 3, 1.0\n",
+            TransactionReaderConfig {
+                client_id_map: Some(ClientIdMap::new(true)),
+                ..Default::default()
+            },
+        );
+        let mut clients = Vec::new();
+        while let Some(tx) = reader.get_next_record().unwrap() {
+            clients.push(tx.client_id());
+        }
+        // Numeric external ids are mapped too rather than used as is
+        assert_eq!(clients, vec![0, 1, 0, 2]);
+        assert_eq!(reader.client_id_map().unwrap().get("3"), Some(2));
+
+        // Without a map the client column must be a client id
+        let mut reader = reader_for(
+            "client_id_unmapped",
+            "type, client, tx, amount\ndeposit, partner-a, 1, 1.0\n",
+            TransactionReaderConfig::default(),
+        );
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::MalformedRecord(2, _))
+        ));
+    }
+
+    fn ordered_reader(
+        name: &str,
+        contents: &str,
+        window: usize,
+        strict: bool,
+    ) -> TransactionReader {
+        reader_for(
+            name,
+            contents,
+            TransactionReaderConfig {
+                ordering: Some(SequenceOrdering {
+                    column: "sequence".to_string(),
+                    window,
+                    strict,
+                }),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_order_by_sequence() {
+        // Each client's withdrawal comes before the deposit funding it in file order
+        let mut reader = ordered_reader(
+            "order_by_sequence",
+            "type, client, tx, amount, sequence\nwithdrawal, 1, 2, 1.0, 2\nwithdrawal, 2, 4, 1.0, 8\ndeposit, 1, 1, 1.0, 1\ndeposit, 2, 3, 1.0, 7\ndeposit, 1, 5, 1.0, 3\n",
+            10,
+            false,
+        );
+        let mut order = Vec::new();
+        while let Some(tx) = reader.get_next_record().unwrap() {
+            order.push((tx.kind(), tx.tx_id().unwrap()));
+        }
+        assert_eq!(
+            order,
+            vec![
+                ("deposit", 1),
+                ("withdrawal", 2),
+                ("deposit", 5),
+                ("deposit", 3),
+                ("withdrawal", 4),
+            ]
+        );
+        let reorder = reader.reorder_buffer().unwrap();
+        assert!(reorder.gaps().is_empty());
+        assert_eq!(reorder.peak(), 5);
+    }
+
+    #[test]
+    fn test_order_by_sequence_gap() {
+        let input = "type, client, tx, amount, sequence\ndeposit, 1, 1, 1.0, 1\ndeposit, 1, 2, 1.0, 3\ndeposit, 1, 3, 1.0, 4\ndeposit, 1, 4, 1.0,\n";
+        let mut reader = ordered_reader("order_by_sequence_gap", input, 1, false);
+        let mut results = Vec::new();
+        while let Some(result) = reader.get_next_record().transpose() {
+            results.push(result.map(|tx| tx.tx_id().unwrap()).map_err(|e| e.code()));
+        }
+        // The row without a sequence number is rejected, the gap is skipped once the window is full
+        assert_eq!(
+            results,
+            vec![Ok(1), Ok(2), Ok(3), Err("malformed_transaction")]
+        );
+        assert_eq!(
+            reader.reorder_buffer().unwrap().gaps(),
+            &[SequenceGap {
+                client: 1,
+                missing: 2..3
+            }]
+        );
+
+        let mut reader = ordered_reader("order_by_sequence_gap_strict", input, 1, true);
+        let mut results = Vec::new();
+        while let Some(result) = reader.get_next_record().transpose() {
+            results.push(result.map(|tx| tx.tx_id().unwrap()).map_err(|e| e.code()));
+        }
+        assert_eq!(
+            results,
+            vec![
+                Ok(1),
+                Err("sequence_gap"),
+                Err("sequence_gap"),
+                Err("malformed_transaction"),
+            ]
+        );
+    }
+
+    const CORRUPTED_INPUT: &[u8] = b"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+\"\x00\x13\xfe garbage, \x01
+\x7f\xff\xff, ,,,
+deposit, 1, 3, 3.0
+withdrawal, 2, 4, 1.0
+";
+
+    fn corrupted_reader(name: &str, resync_policy: ResyncPolicy) -> TransactionReader {
+        let path =
+            std::env::temp_dir().join(format!("transactron_{}_{}.csv", name, std::process::id()));
+        std::fs::write(&path, CORRUPTED_INPUT).unwrap();
+        TransactionReader::with_config(
+            path.to_string_lossy().into_owned(),
+            TransactionReaderConfig {
+                resync_policy,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    fn read_all(reader: &mut TransactionReader) -> (Vec<TxID>, usize) {
+        let (mut tx_ids, mut errors) = (Vec::new(), 0);
+        loop {
+            match reader.get_next_record() {
+                Ok(Some(Transaction::Deposit(tx) | Transaction::Withdrawal(tx))) => {
+                    tx_ids.push(tx.tx_id)
+                }
+                Ok(Some(other)) => panic!("unexpected transaction {:?}", other),
+                Ok(None) => return (tx_ids, errors),
+                Err(_) => errors += 1,
+            }
+        }
+    }
+
+    #[test]
+    fn test_resync_after_corrupted_block() {
+        let mut reader = corrupted_reader("resync", ResyncPolicy::default());
+        assert_eq!(read_all(&mut reader), (vec![1, 2, 3, 4], 1));
+        let offset_of = |needle: &[u8]| {
+            CORRUPTED_INPUT
+                .windows(needle.len())
+                .position(|window| window == needle)
+                .unwrap() as u64
+        };
+        let (garbage_start, garbage_end) = (offset_of(b"\"\x00"), offset_of(b"deposit, 1, 3"));
+        assert_eq!(reader.skipped_ranges().len(), 1);
+        assert_eq!(reader.skipped_ranges()[0], garbage_start..garbage_end);
+    }
+
+    #[test]
+    fn test_no_resync() {
+        // The stray quote swallows every row after it
+        let mut reader = corrupted_reader("no_resync", ResyncPolicy::Disabled);
+        assert_eq!(read_all(&mut reader), (vec![1, 2], 1));
+        assert!(reader.skipped_ranges().is_empty());
+    }
+
+    // A quoted amount that goes on for the given number of bytes, or forever, followed by a valid row
+    struct HugeField {
+        prefix: &'static [u8],
+        len: Option<u64>,
+        suffix: &'static [u8],
+        served: u64,
+    }
+
+    impl Read for HugeField {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let prefix_len = self.prefix.len() as u64;
+            let field_end = self.len.map_or(u64::MAX, |len| prefix_len + len);
+            let n = if self.served < prefix_len {
+                let rest = &self.prefix[self.served as usize..];
+                let n = rest.len().min(buf.len());
+                buf[..n].copy_from_slice(&rest[..n]);
+                n
+            } else if self.served < field_end {
+                let n = buf
+                    .len()
+                    .min((field_end - self.served).min(usize::MAX as u64) as usize);
+                buf[..n].fill(b'9');
+                n
+            } else {
+                let rest = &self.suffix[(self.served - field_end) as usize..];
+                let n = rest.len().min(buf.len());
+                buf[..n].copy_from_slice(&rest[..n]);
+                n
+            };
+            self.served += n as u64;
+            Ok(n)
+        }
+    }
+
+    fn huge_field(len: Option<u64>) -> HugeField {
+        HugeField {
+            prefix: b"type, client, tx, amount\ndeposit, 1, 1, \"1.",
+            len,
+            suffix: b"\"\ndeposit, 1, 2, 2.0\n",
+            served: 0,
+        }
+    }
+
+    #[test]
+    fn test_oversized_record_is_not_buffered() {
+        let mut reader =
+            TransactionReader::from_reader(huge_field(None), Default::default()).unwrap();
+        let start = reader.bufreader.position().clone();
+        reader.bufreader.get_mut().start_record(start.byte());
+        let mut row = csv::StringRecord::new();
+        // The field never ends, the read is refused once the limit and one buffer past it are read
+        assert!(reader.bufreader.read_record(&mut row).is_err());
+        let limit = RecordLimits::default().max_record_bytes as u64;
+        assert!(reader.bufreader.get_ref().offset <= start.byte() + limit + READ_BUFFER as u64);
+        assert!(row.as_slice().len() as u64 <= limit + READ_BUFFER as u64);
+    }
+
+    #[test]
+    fn test_oversized_record_is_skipped() {
+        let mut reader =
+            TransactionReader::from_reader(huge_field(Some(10 << 20)), Default::default()).unwrap();
+        match reader.get_next_record() {
+            Err(AccountingError::OversizedRecord(line, limit)) => {
+                assert_eq!((line, limit), (2, 16 * 1024))
+            }
+            other => panic!("expected an oversized record, got {:?}", other),
+        }
+        assert_eq!(read_all(&mut reader), (vec![2], 0));
+
+        // Files carry on after it the same way
+        let path =
+            std::env::temp_dir().join(format!("transactron_oversized_{}.csv", std::process::id()));
+        let mut contents = Vec::new();
+        huge_field(Some(100_000))
+            .read_to_end(&mut contents)
+            .unwrap();
+        std::fs::write(&path, contents).unwrap();
+        let mut reader = TransactionReader::new(path.to_string_lossy().into_owned()).unwrap();
+        assert_eq!(read_all(&mut reader), (vec![2], 1));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_oversized_field() {
+        let config = TransactionReaderConfig {
+            record_limits: RecordLimits {
+                max_field_bytes: 8,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut reader = reader_for(
+            "oversized_field",
+            "type, client, tx, amount\ndeposit, 1, 1, 1.000000000\ndeposit, 1, 2, 1.0000\n",
+            config,
+        );
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::OversizedField(2, 8))
+        ));
+        assert_eq!(read_all(&mut reader), (vec![2], 0));
+    }
+
+    #[test]
+    fn test_read_from_stream() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\nbogus, 1, 2, 1.0\nwithdrawal, 1, 3, 0.5\ndeposit, 2, 4, 2.0\n";
+        let mut reader =
+            TransactionReader::from_reader(std::io::Cursor::new(input), Default::default())
+                .unwrap();
+        assert_eq!(read_all(&mut reader), (vec![1, 3, 4], 1));
+        // The end of the stream stays the end
+        assert!(matches!(reader.get_next_record(), Ok(None)));
+    }
+
+    #[test]
+    fn test_tab_delimited() {
+        // Commas are part of the field rather than separating it, so the amount doesn't parse
+        let input = "type\tclient\ttx\tamount\ndeposit\t1\t1\t1.5\ndeposit\t1\t2\t1,5\nwithdrawal\t1\t3\t0.5\n";
+        let mut reader = TransactionReaderBuilder::new()
+            .delimiter(b'\t')
+            .build_from_reader(std::io::Cursor::new(input))
+            .unwrap();
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Deposit(tx))) => {
+                assert_eq!((tx.client_id, tx.tx_id, tx.amount), (1, 1, Some(dec!(1.5))))
+            }
+            other => panic!("expected deposit, got {:?}", other),
+        }
+        assert_eq!(read_all(&mut reader), (vec![3], 1));
+    }
+
+    #[test]
+    fn test_semicolon_delimited_resync() {
+        let input: Vec<u8> = CORRUPTED_INPUT
+            .iter()
+            .map(|byte| if *byte == b',' { b';' } else { *byte })
+            .collect();
+        let path =
+            std::env::temp_dir().join(format!("transactron_semicolon_{}.csv", std::process::id()));
+        std::fs::write(&path, &input).unwrap();
+        let mut reader = TransactionReaderBuilder::new()
+            .delimiter(b';')
+            .config(TransactionReaderConfig {
+                resync_policy: ResyncPolicy::default(),
+                ..Default::default()
+            })
+            .build(path.to_string_lossy().into_owned())
+            .unwrap();
+        // Rows are only recognized by their delimiter when resyncing past the corrupted block
+        assert_eq!(read_all(&mut reader), (vec![1, 2, 3, 4], 1));
+        assert_eq!(reader.skipped_ranges().len(), 1);
+
+        // Read with the default delimiter every row is a single field
+        let mut reader = TransactionReader::new(path.to_string_lossy().into_owned()).unwrap();
+        assert!(read_all(&mut reader).0.is_empty());
+    }
+
+    #[test]
+    fn test_parse_from_memory() {
+        // Padded fields, a header with spaces and dispute rows without the amount column
+        let input: &[u8] = b"type , client , tx , amount
+  deposit ,  1 , 1 ,  2.5
+dispute, 1, 1
+deposit, 1, two, 1.0
+resolve, 1, 1,
+withdrawal, 1
+";
+        let mut reader =
+            TransactionReader::from_reader(std::io::Cursor::new(input), Default::default())
+                .unwrap();
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Deposit(tx))) => {
+                assert_eq!((tx.client_id, tx.tx_id, tx.amount), (1, 1, Some(dec!(2.5))))
+            }
+            other => panic!("expected deposit, got {:?}", other),
+        }
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Dispute(tx))) => {
+                assert_eq!((tx.client_id, tx.tx_id, tx.amount), (1, 1, None))
+            }
+            other => panic!("expected dispute, got {:?}", other),
+        }
+        match reader.get_next_record() {
+            Err(e @ AccountingError::Deserialize(..)) => {
+                assert_eq!(
+                    e.to_string(),
+                    "Error: Could not deserialize record: line 4: field 2: invalid digit found in string"
+                );
+                // The csv error is kept as the source, with the position the message leaves out
+                let source = std::error::Error::source(&e).unwrap();
+                let csv_error = source.downcast_ref::<csv::Error>().unwrap();
+                assert_eq!(
+                    csv_error.position().map(|position| position.line()),
+                    Some(4)
+                );
+            }
+            other => panic!("expected a deserialize error, got {:?}", other),
+        }
+        assert!(matches!(
+            reader.get_next_record(),
+            Ok(Some(Transaction::Resolve(_)))
+        ));
+        // A withdrawal needs its amount
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::MalformedRecord(6, _))
+        ));
+        assert!(matches!(reader.get_next_record(), Ok(None)));
+    }
+
+    #[test]
+    fn test_stream_is_not_resynced() {
+        let config = TransactionReaderConfig {
+            resync_policy: ResyncPolicy::default(),
+            ..Default::default()
+        };
+        let mut reader = TransactionReader::from_reader(CORRUPTED_INPUT, config).unwrap();
+        assert_eq!(read_all(&mut reader), (vec![1, 2], 1));
+        assert!(reader.skipped_ranges().is_empty());
+    }
+
+    #[test]
+    fn test_write_output_file() {
+        use rust_decimal_macros::dec;
+
+        let dir = std::env::temp_dir().join(format!("transactron_output_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("accounts.csv");
+        std::fs::write(&path, "stale report").unwrap();
+
+        write_output_file(
+            vec![Account::new(1, dec!(1.5), dec!(0), dec!(1.5))],
+            &path,
+            OutputFormat::Csv,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+        // Only the report is left behind, not the temp file it was written to
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert!(matches!(
+            write_output_file(
+                vec![],
+                dir.join("missing").join("accounts.csv"),
+                OutputFormat::Csv
+            ),
+            Err(AccountingError::Output(..))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_client_list() {
+        let path = std::env::temp_dir().join(format!(
+            "transactron_client_list_{}.csv",
+            std::process::id()
+        ));
+        std::fs::write(&path, "client\n3\n1\n").unwrap();
+        assert_eq!(
+            read_client_list(&path).unwrap(),
+            BTreeSet::from_iter([1, 3])
+        );
+
+        // A bad row is a parse error, a file that can't be read stops the run
+        std::fs::write(&path, "client\n3\nnot a client\n").unwrap();
+        let error = read_client_list(&path).unwrap_err();
+        assert!(matches!(error, AccountingError::Deserialize(..)) && error.is_parse());
+        std::fs::remove_file(&path).unwrap();
+        let error = read_client_list(&path).unwrap_err();
+        assert!(matches!(error, AccountingError::Input(..)) && error.is_io());
+    }
+
+    #[test]
+    fn test_json_output_round_trip() {
+        use rust_decimal_macros::dec;
+
+        let mut locked = Account::new(2, dec!(0.33335), dec!(1), dec!(1.33335));
+        locked.locked = true;
+        let accounts = vec![Account::new(1, dec!(1.5), dec!(0), dec!(1.5)), locked];
+
+        let parse = |value: &serde_json::Value, field: &str| {
+            value[field].as_str().unwrap().parse::<Amount>().unwrap()
+        };
+        let check = |values: Vec<serde_json::Value>| {
+            assert_eq!(values.len(), accounts.len());
+            for (value, account) in values.iter().zip(&accounts) {
+                assert_eq!(value["client"], account.client);
+                assert_eq!(parse(value, "available"), account.available());
+                assert_eq!(parse(value, "held"), account.held());
+                assert_eq!(parse(value, "total"), account.total());
+                assert_eq!(value["locked"], account.locked);
+            }
+        };
+
+        let mut json = Vec::new();
+        write_output(accounts.clone(), &mut json, OutputFormat::Json).unwrap();
+        check(serde_json::from_slice(&json).unwrap());
+        // Amounts are strings with four places, like in the csv output
+        assert!(String::from_utf8(json)
+            .unwrap()
+            .contains(r#""available":"1.5000""#));
+
+        let mut json_lines = Vec::new();
+        write_output(accounts.clone(), &mut json_lines, OutputFormat::JsonLines).unwrap();
+        check(
+            String::from_utf8(json_lines)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect(),
+        );
+    }
 }
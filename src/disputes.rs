@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+
+use crate::account::Account;
+use crate::transaction::{Amount, ClientID, CurrencyCode, DisputeKind, TransactionData, TxID};
+use crate::AccountingError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisputeInfo {
+    pub tx_id: TxID,
+    pub client: ClientID,
+    pub amount: Amount,
+    pub currency: Option<CurrencyCode>,
+}
+
+// All transactions currently under dispute, ordered by transaction id
+pub fn open_disputes(transactions: &BTreeMap<TxID, TransactionData>) -> Vec<DisputeInfo> {
+    transactions
+        .values()
+        .filter(|tx| tx.under_dispute())
+        .filter_map(|tx| {
+            Some(DisputeInfo {
+                tx_id: tx.tx_id,
+                client: tx.client_id,
+                amount: tx.amount?,
+                currency: tx.currency,
+            })
+        })
+        .collect()
+}
+
+// The amount held by open disputes per client. Only disputes in the account's default currency are
+// counted, so the sums match the held column of the output
+pub fn held_by_client(
+    transactions: &BTreeMap<TxID, TransactionData>,
+) -> BTreeMap<ClientID, Amount> {
+    let mut held = BTreeMap::new();
+    for dispute in open_disputes(transactions) {
+        if dispute.currency.is_none() {
+            *held.entry(dispute.client).or_default() += dispute.amount;
+        }
+    }
+    held
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeOutcome {
+    Resolve,
+    Chargeback,
+}
+
+// Projects what the client's account would look like if the open dispute ended with the given
+// outcome. Works on a copy, neither the account nor the ledger entry is changed.
+// Returns the account before and after
+pub fn project_outcome(
+    accounts: &BTreeMap<ClientID, Account>,
+    transactions: &BTreeMap<TxID, TransactionData>,
+    client: ClientID,
+    tx_id: TxID,
+    outcome: DisputeOutcome,
+) -> Result<(Account, Account), AccountingError> {
+    let tx = transactions
+        .get(&tx_id)
+        .filter(|tx| tx.under_dispute())
+        .ok_or(AccountingError::NotUnderDispute)?;
+    if tx.client_id != client {
+        return Err(AccountingError::DisputeClientMismatch);
+    }
+    let (before, amount) = match (accounts.get(&client), tx.amount) {
+        (Some(account), Some(amount)) => (account, amount),
+        _ => return Err(AccountingError::NotUnderDispute),
+    };
+
+    let mut after = before.clone();
+    let funds = after.funds(tx.currency);
+    match (outcome, tx.kind) {
+        (DisputeOutcome::Resolve, DisputeKind::Deposit) => funds.resolve(amount)?,
+        (DisputeOutcome::Resolve, DisputeKind::Withdrawal) => funds.resolve_withdrawal(amount)?,
+        (DisputeOutcome::Chargeback, DisputeKind::Deposit) => funds.chargeback(amount)?,
+        (DisputeOutcome::Chargeback, DisputeKind::Withdrawal) => {
+            funds.chargeback_withdrawal(amount)?
+        }
+    }
+    // Locks like the default ChargebackLockPolicy::Always
+    if outcome == DisputeOutcome::Chargeback {
+        after.chargebacks += 1;
+        after.locked = true;
+    }
+    Ok((before.clone(), after))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use rust_decimal_macros::dec;
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::account::Account;
+    use crate::transaction::{DisputeState, Transaction};
+    use crate::transaction_processor::TransactionProcessor;
+
+    #[tokio::test]
+    async fn test_open_disputes_and_held_by_client() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        let processor = tokio::spawn(async move { processor.process().await });
+        for transaction in [
+            Transaction::deposit(1, 1, dec!(10)),
+            Transaction::deposit(1, 2, dec!(2.5)),
+            Transaction::deposit(1, 3, dec!(4)),
+            Transaction::deposit(2, 4, dec!(7.1234)),
+            Transaction::deposit(2, 5, dec!(1)),
+            Transaction::deposit(3, 6, dec!(3)),
+            // Client 1 has two open disputes and a resolved one
+            Transaction::dispute(1, 1),
+            Transaction::dispute(1, 2),
+            Transaction::dispute(1, 3),
+            Transaction::resolve(1, 3),
+            // Client 2 has one open dispute, client 3 only a resolved one
+            Transaction::dispute(2, 4),
+            Transaction::dispute(3, 6),
+            Transaction::resolve(3, 6),
+        ] {
+            sender.send(transaction).unwrap();
+        }
+        drop(sender);
+        processor.await.unwrap();
+
+        let transactions = ledger.read().await;
+        let open = open_disputes(&transactions);
+        assert_eq!(
+            open.iter().map(|d| (d.tx_id, d.client)).collect::<Vec<_>>(),
+            vec![(1, 1), (2, 1), (4, 2)]
+        );
+        assert_eq!(open[2].amount, dec!(7.1234));
+
+        let held = held_by_client(&transactions);
+        assert_eq!(held, BTreeMap::from([(1, dec!(12.5)), (2, dec!(7.1234))]));
+        // The aggregate agrees with the held funds of every account
+        for account in accounts.read().await.values() {
+            assert_eq!(
+                held.get(&account.client).copied().unwrap_or_default(),
+                account.held()
+            );
+        }
+    }
+
+    #[test]
+    fn test_project_outcome() {
+        let mut disputed = TransactionData::new(1, 1, Some(dec!(4)));
+        disputed.dispute_state = DisputeState::Disputed;
+        let transactions = BTreeMap::from([
+            (1, disputed),
+            (2, TransactionData::new(1, 2, Some(dec!(6)))),
+        ]);
+        let accounts = BTreeMap::from([(1, Account::new(1, dec!(6), dec!(4), dec!(10)))]);
+
+        let (before, after) =
+            project_outcome(&accounts, &transactions, 1, 1, DisputeOutcome::Resolve).unwrap();
+        assert_eq!(before, accounts[&1]);
+        assert_eq!(after, Account::new(1, dec!(10), dec!(0), dec!(10)));
+
+        let (_, after) =
+            project_outcome(&accounts, &transactions, 1, 1, DisputeOutcome::Chargeback).unwrap();
+        let mut charged_back = Account::new(1, dec!(6), dec!(0), dec!(6));
+        charged_back.locked = true;
+        charged_back.chargebacks = 1;
+        assert_eq!(after, charged_back);
+        // Nothing is persisted
+        assert!(transactions[&1].under_dispute());
+        assert_eq!(accounts[&1], Account::new(1, dec!(6), dec!(4), dec!(10)));
+    }
+
+    #[test]
+    fn test_project_outcome_refusals() {
+        let mut disputed = TransactionData::new(1, 1, Some(dec!(4)));
+        disputed.dispute_state = DisputeState::Disputed;
+        let transactions = BTreeMap::from([
+            (1, disputed),
+            (2, TransactionData::new(1, 2, Some(dec!(6)))),
+        ]);
+        let accounts = BTreeMap::from([(1, Account::new(1, dec!(6), dec!(4), dec!(10)))]);
+
+        for tx_id in [2, 3] {
+            assert!(matches!(
+                project_outcome(&accounts, &transactions, 1, tx_id, DisputeOutcome::Resolve),
+                Err(AccountingError::NotUnderDispute)
+            ));
+        }
+        assert!(matches!(
+            project_outcome(&accounts, &transactions, 2, 1, DisputeOutcome::Chargeback),
+            Err(AccountingError::DisputeClientMismatch)
+        ));
+    }
+}
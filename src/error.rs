@@ -1,6 +1,50 @@
+use std::ops::Deref;
+use std::sync::Arc;
+
 use thiserror::Error;
 
+use crate::transaction::{DisputeState, TxID};
+
+// The underlying error of an AccountingError, e.g. a csv::Error or std::io::Error, shared so errors stay
+// Clone. Error::source hands out the wrapped error itself, so it can be downcast to its own type
+#[derive(Clone)]
+pub struct ErrorSource(Arc<dyn std::error::Error + Send + Sync>);
+
+impl ErrorSource {
+    pub fn new<E: std::error::Error + Send + Sync + 'static>(error: E) -> Self {
+        ErrorSource(Arc::new(error))
+    }
+}
+
+impl Deref for ErrorSource {
+    type Target = dyn std::error::Error + Send + Sync;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl std::fmt::Debug for ErrorSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::fmt::Display for ErrorSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+pub(crate) fn source<E: std::error::Error + Send + Sync + 'static>(
+    error: E,
+) -> Option<ErrorSource> {
+    Some(ErrorSource::new(error))
+}
+
+// Messages and codes are stable, new variants may be added
 #[derive(Debug, Error, Clone)]
+#[non_exhaustive]
 pub enum AccountingError {
     #[error("Error: Insufficient funds for withdrawal")]
     Withdrawal,
@@ -9,15 +53,785 @@ pub enum AccountingError {
     #[error("Error: Insufficient funds for dispute")]
     Dispute,
     #[error("Error: Could not deserialize record: {0}")]
-    Deserialize(String),
+    Deserialize(String, #[source] Option<ErrorSource>),
     #[error("Error: malformed transaction")]
     MalformedTransaction,
     #[error("Error: Could not send tx data to worker: {0}")]
-    TokioChannel(String),
+    TokioChannel(#[source] ErrorSource),
     #[error("Error: The transaction already exists in the ledger")]
     TransactionAlreadyExists,
     #[error("Error: Account is locked")]
     AccountLocked,
     #[error("Error: Processor future returned error: {0}")]
-    HandleAwait(String),
+    HandleAwait(#[source] ErrorSource),
+    #[error("Error: No available funds to withdraw")]
+    NoFundsAvailable,
+    #[error("Error: Transaction blocked by compliance rule: {0}")]
+    ComplianceBlock(String),
+    #[error("Error: Transaction currency does not match the referenced transaction")]
+    CurrencyMismatch,
+    #[error("Error: No client id mapping for external id {0}")]
+    UnmappedClientId(String),
+    #[error("Error: Conflicting client id mapping for external id {0}")]
+    DuplicateClientIdMapping(String),
+    #[error("Error: All client ids have been assigned")]
+    ClientIdSpaceExhausted,
+    #[error("Error: Withdrawal is not pending settlement")]
+    SettlementNotPending,
+    #[error("Error: Pending withdrawal belongs to another client")]
+    SettlementClientMismatch,
+    #[error("Error: A withdrawal pending settlement can't be disputed")]
+    DisputePendingWithdrawal,
+    #[error("Error: Could not open input: {0}")]
+    Input(String, #[source] Option<ErrorSource>),
+    #[error("Error: Transaction is not under dispute")]
+    NotUnderDispute,
+    #[error("Error: Disputed transaction belongs to another client")]
+    DisputeClientMismatch,
+    #[error("Error: Client is over the deposit volume limit")]
+    DepositVolumeExceeded,
+    #[error("Error: Sequence number was already passed or is duplicated")]
+    StaleSequence,
+    #[error("Error: Client's sequence has a gap")]
+    SequenceGap,
+    #[error("Error: Withdrawals are suspended for this client")]
+    WithdrawalsSuspended,
+    #[error("Error: Could not write output: {0}")]
+    Output(String, #[source] Option<ErrorSource>),
+    #[error("Error: Resolved transaction is not under dispute")]
+    ResolveNotDisputed,
+    #[error("Error: Charged back transaction is not under dispute")]
+    ChargebackNotDisputed,
+    #[error("Error: Transaction {0} is already under dispute")]
+    DisputeAlreadyActive(TxID),
+    #[error("Error: Transaction {0} does not exist in the ledger")]
+    TransactionNotFound(TxID),
+    #[error("Error: Ledger invariant violated: {0}")]
+    InvariantViolation(String),
+    #[error("Error: Deposit or withdrawal of zero")]
+    ZeroAmount,
+    #[error("Error: Deposit or withdrawal of a negative amount")]
+    NegativeAmount,
+    #[error("Error: Unknown transaction type {0:?}")]
+    UnknownTransactionType(String),
+    #[error("Error: Account balances don't add up: {0}")]
+    AccountInvariantViolation(String),
+    #[error("Error: Record on line {0} is over the limit of {1} bytes")]
+    OversizedRecord(u64, usize),
+    #[error("Error: Record on line {0} has a field over the limit of {1} bytes")]
+    OversizedField(u64, usize),
+    #[error("Error: Transaction {0} can't go from {1} to {2}")]
+    InvalidDisputeTransition(TxID, DisputeState, DisputeState),
+    #[error("Error: Amount overflows the account balance")]
+    Overflow,
+    #[error("Error: Operation would leave a negative balance")]
+    NegativeBalance,
+    // Without a tx id for amounts that aren't read from a row, e.g. in ISO 20022 messages
+    #[error(
+        "Error: Amount{} has {1} decimal places, at most four are allowed",
+        .0.map(|tx| format!(" of transaction {}", tx)).unwrap_or_default()
+    )]
+    PrecisionExceeded(Option<TxID>, u32),
+    #[error("Error: line {0}: malformed transaction: {1}")]
+    MalformedRecord(u64, String),
+}
+
+impl AccountingError {
+    // Stable name of the error kind, used to group errors in logs and summaries
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountingError::Withdrawal => "withdrawal",
+            AccountingError::Deposit => "deposit",
+            AccountingError::Dispute => "dispute",
+            AccountingError::Deserialize(..) => "deserialize",
+            AccountingError::MalformedTransaction => "malformed_transaction",
+            AccountingError::TokioChannel(_) => "tokio_channel",
+            AccountingError::TransactionAlreadyExists => "transaction_already_exists",
+            AccountingError::AccountLocked => "account_locked",
+            AccountingError::HandleAwait(_) => "handle_await",
+            AccountingError::NoFundsAvailable => "no_funds_available",
+            AccountingError::ComplianceBlock(_) => "compliance_block",
+            AccountingError::CurrencyMismatch => "currency_mismatch",
+            AccountingError::UnmappedClientId(_) => "unmapped_client_id",
+            AccountingError::DuplicateClientIdMapping(_) => "duplicate_client_id_mapping",
+            AccountingError::ClientIdSpaceExhausted => "client_id_space_exhausted",
+            AccountingError::SettlementNotPending => "settlement_not_pending",
+            AccountingError::SettlementClientMismatch => "settlement_client_mismatch",
+            AccountingError::DisputePendingWithdrawal => "dispute_pending_withdrawal",
+            AccountingError::Input(..) => "input",
+            AccountingError::NotUnderDispute => "not_under_dispute",
+            AccountingError::DisputeClientMismatch => "dispute_client_mismatch",
+            AccountingError::DepositVolumeExceeded => "deposit_volume_exceeded",
+            AccountingError::StaleSequence => "stale_sequence",
+            AccountingError::SequenceGap => "sequence_gap",
+            AccountingError::WithdrawalsSuspended => "withdrawals_suspended",
+            AccountingError::Output(..) => "output",
+            AccountingError::ResolveNotDisputed => "resolve_not_disputed",
+            AccountingError::ChargebackNotDisputed => "chargeback_not_disputed",
+            AccountingError::DisputeAlreadyActive(_) => "dispute_already_active",
+            AccountingError::TransactionNotFound(_) => "transaction_not_found",
+            AccountingError::InvariantViolation(_) => "invariant_violation",
+            AccountingError::ZeroAmount => "zero_amount",
+            AccountingError::NegativeAmount => "negative_amount",
+            AccountingError::AccountInvariantViolation(_) => "account_invariant_violation",
+            AccountingError::UnknownTransactionType(_) => "unknown_transaction_type",
+            AccountingError::OversizedRecord(..) => "oversized_record",
+            AccountingError::OversizedField(..) => "oversized_field",
+            AccountingError::InvalidDisputeTransition(..) => "invalid_dispute_transition",
+            AccountingError::Overflow => "overflow",
+            AccountingError::NegativeBalance => "negative_balance",
+            AccountingError::PrecisionExceeded(..) => "precision_exceeded",
+            AccountingError::MalformedRecord(..) => "malformed_record",
+        }
+    }
+
+    // The registry entry of this error, see ERRORS
+    pub fn info(&self) -> &'static ErrorInfo {
+        lookup(self.code()).expect("every error code is in the registry")
+    }
+
+    // Reading the input or writing the output failed
+    pub fn is_io(&self) -> bool {
+        matches!(
+            self,
+            AccountingError::Input(..) | AccountingError::Output(..)
+        )
+    }
+
+    // A row of the input can't be turned into a transaction
+    pub fn is_parse(&self) -> bool {
+        matches!(
+            self,
+            AccountingError::Deserialize(..)
+                | AccountingError::MalformedTransaction
+                | AccountingError::MalformedRecord(..)
+                | AccountingError::Deposit
+                | AccountingError::PrecisionExceeded(..)
+                | AccountingError::UnknownTransactionType(_)
+                | AccountingError::OversizedRecord(..)
+                | AccountingError::OversizedField(..)
+                | AccountingError::ZeroAmount
+                | AccountingError::NegativeAmount
+                | AccountingError::UnmappedClientId(_)
+                | AccountingError::ClientIdSpaceExhausted
+                | AccountingError::StaleSequence
+                | AccountingError::SequenceGap
+        )
+    }
+
+    // A valid transaction the ledger refuses, e.g. for insufficient funds or a locked account
+    pub fn is_business_rule(&self) -> bool {
+        matches!(
+            self,
+            AccountingError::Withdrawal
+                | AccountingError::Dispute
+                | AccountingError::TransactionAlreadyExists
+                | AccountingError::AccountLocked
+                | AccountingError::NoFundsAvailable
+                | AccountingError::ComplianceBlock(_)
+                | AccountingError::CurrencyMismatch
+                | AccountingError::SettlementNotPending
+                | AccountingError::SettlementClientMismatch
+                | AccountingError::DisputePendingWithdrawal
+                | AccountingError::NotUnderDispute
+                | AccountingError::DisputeClientMismatch
+                | AccountingError::DepositVolumeExceeded
+                | AccountingError::WithdrawalsSuspended
+                | AccountingError::ResolveNotDisputed
+                | AccountingError::ChargebackNotDisputed
+                | AccountingError::DisputeAlreadyActive(_)
+                | AccountingError::TransactionNotFound(_)
+                | AccountingError::InvalidDisputeTransition(..)
+                | AccountingError::Overflow
+                | AccountingError::NegativeBalance
+        )
+    }
+
+    // Whether the run carries on with only the offending row rejected. Everything else, such as io errors
+    // or a broken invariant, stops the run
+    pub fn is_recoverable(&self) -> bool {
+        self.is_parse() || self.is_business_rule()
+    }
+}
+
+// What operators need to know about an error code, printed by `transactron explain <code>`
+#[derive(Debug)]
+pub struct ErrorInfo {
+    pub code: &'static str,
+    pub variant: &'static str,
+    pub description: &'static str,
+    // When the error is returned
+    pub triggers: &'static str,
+    // Whether the run carries on, with only the offending row rejected
+    pub recoverable: bool,
+    // Command line flags that change when the error is returned
+    pub flags: &'static [&'static str],
+}
+
+// One entry per variant, in declaration order. test_registry_covers_every_variant keeps it in sync with the enum
+pub static ERRORS: &[ErrorInfo] = &[
+    ErrorInfo {
+        code: "withdrawal",
+        variant: "Withdrawal",
+        description: "Insufficient funds for a withdrawal.",
+        triggers: "A withdrawal of more than the client's available funds.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "deposit",
+        variant: "Deposit",
+        description: "Deposit without an amount.",
+        triggers: "Not returned by the processor any more, deposits without an amount are malformed rows.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "dispute",
+        variant: "Dispute",
+        description: "Insufficient funds for a dispute.",
+        triggers: "A dispute of a deposit whose amount is more than the client's available funds, so it can't be held.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "deserialize",
+        variant: "Deserialize",
+        description: "A row or line of the input could not be parsed.",
+        triggers: "Rows with the wrong number of fields, values of the wrong type, invalid json lines or json lines outside the strict schema.",
+        recoverable: true,
+        flags: &["--no-resync", "--format", "--lenient-json"],
+    },
+    ErrorInfo {
+        code: "malformed_transaction",
+        variant: "MalformedTransaction",
+        description: "A row is missing fields its transaction type requires, or has ones it may not have.",
+        triggers: "E.g. a deposit without an amount or tx id, a client id that isn't a number, or an under_dispute column without --trust-dispute-column.",
+        recoverable: true,
+        flags: &["--trust-dispute-column", "--client-id-map"],
+    },
+    ErrorInfo {
+        code: "tokio_channel",
+        variant: "TokioChannel",
+        description: "A transaction could not be handed to the processor.",
+        triggers: "The processor stopped while the input was still being read.",
+        recoverable: false,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "transaction_already_exists",
+        variant: "TransactionAlreadyExists",
+        description: "The tx id of a deposit or withdrawal is already in the ledger.",
+        triggers: "A deposit, withdrawal or withdraw_all reusing the tx id of an earlier one.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "account_locked",
+        variant: "AccountLocked",
+        description: "The account is locked by a chargeback.",
+        triggers: "Deposits, withdrawals and other money movements of a client after a chargeback. Disputes of earlier transactions still go through.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "handle_await",
+        variant: "HandleAwait",
+        description: "The processor task failed.",
+        triggers: "The processor panicked or was cancelled.",
+        recoverable: false,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "no_funds_available",
+        variant: "NoFundsAvailable",
+        description: "Nothing is available to withdraw.",
+        triggers: "A withdraw_all of a client without available funds.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "compliance_block",
+        variant: "ComplianceBlock",
+        description: "A compliance rule blocked the transaction.",
+        triggers: "A transaction failing one of the processor's compliance rules, the message names the rule.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "currency_mismatch",
+        variant: "CurrencyMismatch",
+        description: "A dispute, resolve or chargeback names another currency than the transaction it references.",
+        triggers: "Only with the multi-currency feature.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "unmapped_client_id",
+        variant: "UnmappedClientId",
+        description: "An external client id has no mapping.",
+        triggers: "Rows with an external id missing from the client id map, unless ids are auto-assigned.",
+        recoverable: true,
+        flags: &["--client-id-map", "--auto-assign-client-ids"],
+    },
+    ErrorInfo {
+        code: "duplicate_client_id_mapping",
+        variant: "DuplicateClientIdMapping",
+        description: "The client id map file maps an external id twice.",
+        triggers: "Loading a map file with conflicting rows for the same external id.",
+        recoverable: false,
+        flags: &["--client-id-map"],
+    },
+    ErrorInfo {
+        code: "client_id_space_exhausted",
+        variant: "ClientIdSpaceExhausted",
+        description: "All client ids have been assigned.",
+        triggers: "Auto-assigning an id to an unseen external id when every u16 client id is taken.",
+        recoverable: true,
+        flags: &["--auto-assign-client-ids"],
+    },
+    ErrorInfo {
+        code: "settlement_not_pending",
+        variant: "SettlementNotPending",
+        description: "A settle or cancel references a withdrawal that isn't pending.",
+        triggers: "Settling or cancelling a withdrawal twice, or one made without --two-phase-withdrawals.",
+        recoverable: true,
+        flags: &["--two-phase-withdrawals"],
+    },
+    ErrorInfo {
+        code: "settlement_client_mismatch",
+        variant: "SettlementClientMismatch",
+        description: "The pending withdrawal belongs to another client.",
+        triggers: "A settle or cancel whose client id differs from the withdrawal it references.",
+        recoverable: true,
+        flags: &["--two-phase-withdrawals"],
+    },
+    ErrorInfo {
+        code: "dispute_pending_withdrawal",
+        variant: "DisputePendingWithdrawal",
+        description: "A withdrawal pending settlement can't be disputed.",
+        triggers: "Disputing a two-phase withdrawal before it is settled, it should be cancelled instead.",
+        recoverable: true,
+        flags: &["--two-phase-withdrawals", "--trust-dispute-column"],
+    },
+    ErrorInfo {
+        code: "input",
+        variant: "Input",
+        description: "The input could not be opened or read.",
+        triggers: "A missing or unreadable input, client list or conformance file.",
+        recoverable: false,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "not_under_dispute",
+        variant: "NotUnderDispute",
+        description: "The transaction is not under dispute.",
+        triggers: "Looking up the dispute of a transaction that isn't disputed in the disputes module.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "dispute_client_mismatch",
+        variant: "DisputeClientMismatch",
+        description: "The referenced transaction belongs to another client.",
+        triggers: "A dispute, resolve or chargeback whose client id differs from the transaction it references.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "deposit_volume_exceeded",
+        variant: "DepositVolumeExceeded",
+        description: "The client is over the deposit volume limit.",
+        triggers: "Deposits of a flagged client, only when the limit is enforced.",
+        recoverable: true,
+        flags: &["--deposit-volume-limit", "--enforce-volume-limit"],
+    },
+    ErrorInfo {
+        code: "stale_sequence",
+        variant: "StaleSequence",
+        description: "A sequence number was already passed or is duplicated.",
+        triggers: "Rows without a sequence number, or with one the client has already moved past.",
+        recoverable: true,
+        flags: &["--order-by-column", "--reorder-window"],
+    },
+    ErrorInfo {
+        code: "sequence_gap",
+        variant: "SequenceGap",
+        description: "The client's sequence has a gap.",
+        triggers: "Later rows of a client whose sequence skipped ahead, only with --strict-sequence.",
+        recoverable: true,
+        flags: &["--order-by-column", "--reorder-window", "--strict-sequence"],
+    },
+    ErrorInfo {
+        code: "withdrawals_suspended",
+        variant: "WithdrawalsSuspended",
+        description: "Withdrawals are suspended for the client.",
+        triggers: "Withdrawals and settles of a client on the deny-list, or missing from the allow-list.",
+        recoverable: true,
+        flags: &["--withdrawal-denylist", "--withdrawal-allowlist"],
+    },
+    ErrorInfo {
+        code: "output",
+        variant: "Output",
+        description: "The report could not be written.",
+        triggers: "An output path that can't be created or renamed into place, or a closed stdout.",
+        recoverable: false,
+        flags: &["--output", "--output-format"],
+    },
+    ErrorInfo {
+        code: "resolve_not_disputed",
+        variant: "ResolveNotDisputed",
+        description: "The resolved transaction is not under dispute.",
+        triggers: "A resolve without an earlier dispute, or after the dispute was resolved.",
+        recoverable: true,
+        flags: &["--trust-dispute-column"],
+    },
+    ErrorInfo {
+        code: "chargeback_not_disputed",
+        variant: "ChargebackNotDisputed",
+        description: "The charged back transaction is not under dispute.",
+        triggers: "A chargeback without an earlier dispute, or after the dispute was resolved.",
+        recoverable: true,
+        flags: &["--trust-dispute-column"],
+    },
+    ErrorInfo {
+        code: "dispute_already_active",
+        variant: "DisputeAlreadyActive",
+        description: "The transaction is already under dispute.",
+        triggers: "A second dispute of the same transaction before it is resolved or charged back.",
+        recoverable: true,
+        flags: &["--trust-dispute-column"],
+    },
+    ErrorInfo {
+        code: "transaction_not_found",
+        variant: "TransactionNotFound",
+        description: "The referenced transaction does not exist in the ledger.",
+        triggers: "A dispute, resolve, chargeback, settle or cancel of an unknown tx id.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "invariant_violation",
+        variant: "InvariantViolation",
+        description: "The held balances don't match the transactions under dispute.",
+        triggers: "Reconciling the ledger at the end of the run finds a mismatch, only fails the run with --strict-invariants.",
+        recoverable: false,
+        flags: &["--strict-invariants"],
+    },
+    ErrorInfo {
+        code: "zero_amount",
+        variant: "ZeroAmount",
+        description: "A deposit or withdrawal of zero.",
+        triggers: "Amounts of 0, however many decimal places are written.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "negative_amount",
+        variant: "NegativeAmount",
+        description: "A deposit or withdrawal of a negative amount.",
+        triggers: "Amounts below zero.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "unknown_transaction_type",
+        variant: "UnknownTransactionType",
+        description: "The type column holds an unknown transaction type.",
+        triggers: "Types other than the known ones and their aliases, matched case-insensitively. Json types must match exactly.",
+        recoverable: true,
+        flags: &["--lenient-json"],
+    },
+    ErrorInfo {
+        code: "account_invariant_violation",
+        variant: "AccountInvariantViolation",
+        description: "An account's total is not its available, held and pending funds.",
+        triggers: "Account::check_invariant on an account whose balances don't add up.",
+        recoverable: false,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "oversized_record",
+        variant: "OversizedRecord",
+        description: "A row is longer than the record size limit.",
+        triggers: "Rows over --max-record-bytes (16 KiB by default), e.g. an endless quoted field. The rest of the line is skipped without being buffered.",
+        recoverable: true,
+        flags: &["--max-record-bytes"],
+    },
+    ErrorInfo {
+        code: "oversized_field",
+        variant: "OversizedField",
+        description: "A field of a row is longer than the field size limit.",
+        triggers: "Fields over --max-field-bytes (1 KiB by default).",
+        recoverable: true,
+        flags: &["--max-field-bytes"],
+    },
+    ErrorInfo {
+        code: "invalid_dispute_transition",
+        variant: "InvalidDisputeTransition",
+        description: "The transaction has been charged back, which ends its dispute lifecycle.",
+        triggers: "A dispute, resolve or chargeback of a transaction that was already charged back.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "overflow",
+        variant: "Overflow",
+        description: "The transaction would take a balance past the largest representable amount.",
+        triggers: "E.g. repeated deposits close to the maximum decimal value. The balance is left unchanged.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "negative_balance",
+        variant: "NegativeBalance",
+        description: "The transaction would take a balance below zero.",
+        triggers: "Releasing more than is held or pending, which means balance operations were applied out of order.",
+        recoverable: true,
+        flags: &[],
+    },
+    ErrorInfo {
+        code: "precision_exceeded",
+        variant: "PrecisionExceeded",
+        description: "An amount has more than four decimal places. The error names the tx id when the amount belongs to an input row.",
+        triggers: "Rows or ISO 20022 messages with amounts such as 0.00001, trailing zeros count as written. With --round-excess-precision csv and json rows are rounded instead.",
+        recoverable: true,
+        flags: &["--round-excess-precision"],
+    },
+    ErrorInfo {
+        code: "malformed_record",
+        variant: "MalformedRecord",
+        description: "A csv row is missing fields its transaction type requires, or has ones it may not have. The error names the line and lists the row's fields.",
+        triggers: "The same rows as malformed_transaction, when read from csv, e.g. type=deposit client=7 tx=99 amount=<missing>.",
+        recoverable: true,
+        flags: &["--trust-dispute-column", "--client-id-map"],
+    },
+];
+
+// Codes that have been renamed, with the code they are reported as now. Rejections are only logged with
+// the new code, lookup accepts both
+pub static RENAMED_CODES: &[(&str, &str)] = &[
+    ("excessive_precision", "precision_exceeded"),
+    ("invalid_precision", "precision_exceeded"),
+];
+
+// Codes are also accepted in upper case and with an E_ prefix, e.g. E_INSUFFICIENT_FUNDS
+pub fn lookup(code: &str) -> Option<&'static ErrorInfo> {
+    let code = normalize_code(code);
+    let code = renamed_to(&code).unwrap_or(&code);
+    ERRORS.iter().find(|info| info.code == code)
+}
+
+// The current code of a renamed one, None for codes that were never renamed
+pub fn renamed_to(code: &str) -> Option<&'static str> {
+    let code = normalize_code(code);
+    RENAMED_CODES
+        .iter()
+        .find(|(old, _)| *old == code)
+        .map(|(_, new)| *new)
+}
+
+// Codes sharing a word with the given one, for when lookup finds nothing
+pub fn suggestions(code: &str) -> Vec<&'static str> {
+    let code = normalize_code(code);
+    ERRORS
+        .iter()
+        .filter(|info| {
+            code.split('_').any(|word| {
+                word.len() > 2 && info.code.split('_').any(|other| other.starts_with(word))
+            })
+        })
+        .map(|info| info.code)
+        .collect()
+}
+
+fn normalize_code(code: &str) -> String {
+    let code = code.trim().to_ascii_lowercase().replace('-', "_");
+    match code.strip_prefix("e_") {
+        Some(code) => code.to_string(),
+        None => code,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn every_variant() -> Vec<AccountingError> {
+        let variants = vec![
+            AccountingError::Withdrawal,
+            AccountingError::Deposit,
+            AccountingError::Dispute,
+            AccountingError::Deserialize(String::new(), None),
+            AccountingError::MalformedTransaction,
+            AccountingError::TokioChannel(ErrorSource::new(std::fmt::Error)),
+            AccountingError::TransactionAlreadyExists,
+            AccountingError::AccountLocked,
+            AccountingError::HandleAwait(ErrorSource::new(std::fmt::Error)),
+            AccountingError::NoFundsAvailable,
+            AccountingError::ComplianceBlock(String::new()),
+            AccountingError::CurrencyMismatch,
+            AccountingError::UnmappedClientId(String::new()),
+            AccountingError::DuplicateClientIdMapping(String::new()),
+            AccountingError::ClientIdSpaceExhausted,
+            AccountingError::SettlementNotPending,
+            AccountingError::SettlementClientMismatch,
+            AccountingError::DisputePendingWithdrawal,
+            AccountingError::Input(String::new(), None),
+            AccountingError::NotUnderDispute,
+            AccountingError::DisputeClientMismatch,
+            AccountingError::DepositVolumeExceeded,
+            AccountingError::StaleSequence,
+            AccountingError::SequenceGap,
+            AccountingError::WithdrawalsSuspended,
+            AccountingError::Output(String::new(), None),
+            AccountingError::ResolveNotDisputed,
+            AccountingError::ChargebackNotDisputed,
+            AccountingError::DisputeAlreadyActive(1),
+            AccountingError::TransactionNotFound(1),
+            AccountingError::InvariantViolation(String::new()),
+            AccountingError::ZeroAmount,
+            AccountingError::NegativeAmount,
+            AccountingError::UnknownTransactionType(String::new()),
+            AccountingError::AccountInvariantViolation(String::new()),
+            AccountingError::OversizedRecord(1, 1),
+            AccountingError::OversizedField(1, 1),
+            AccountingError::InvalidDisputeTransition(
+                1,
+                DisputeState::ChargedBack,
+                DisputeState::Disputed,
+            ),
+            AccountingError::Overflow,
+            AccountingError::NegativeBalance,
+            AccountingError::PrecisionExceeded(Some(1), 5),
+            AccountingError::MalformedRecord(1, String::new()),
+        ];
+        // Adding a variant breaks this match until it is listed above as well
+        for variant in &variants {
+            match variant {
+                AccountingError::Withdrawal
+                | AccountingError::Deposit
+                | AccountingError::Dispute
+                | AccountingError::Deserialize(..)
+                | AccountingError::MalformedTransaction
+                | AccountingError::TokioChannel(_)
+                | AccountingError::TransactionAlreadyExists
+                | AccountingError::AccountLocked
+                | AccountingError::HandleAwait(_)
+                | AccountingError::NoFundsAvailable
+                | AccountingError::ComplianceBlock(_)
+                | AccountingError::CurrencyMismatch
+                | AccountingError::UnmappedClientId(_)
+                | AccountingError::DuplicateClientIdMapping(_)
+                | AccountingError::ClientIdSpaceExhausted
+                | AccountingError::SettlementNotPending
+                | AccountingError::SettlementClientMismatch
+                | AccountingError::DisputePendingWithdrawal
+                | AccountingError::Input(..)
+                | AccountingError::NotUnderDispute
+                | AccountingError::DisputeClientMismatch
+                | AccountingError::DepositVolumeExceeded
+                | AccountingError::StaleSequence
+                | AccountingError::SequenceGap
+                | AccountingError::WithdrawalsSuspended
+                | AccountingError::Output(..)
+                | AccountingError::ResolveNotDisputed
+                | AccountingError::ChargebackNotDisputed
+                | AccountingError::DisputeAlreadyActive(_)
+                | AccountingError::TransactionNotFound(_)
+                | AccountingError::InvariantViolation(_)
+                | AccountingError::ZeroAmount
+                | AccountingError::NegativeAmount
+                | AccountingError::UnknownTransactionType(_)
+                | AccountingError::AccountInvariantViolation(_)
+                | AccountingError::OversizedRecord(..)
+                | AccountingError::OversizedField(..)
+                | AccountingError::InvalidDisputeTransition(..)
+                | AccountingError::Overflow
+                | AccountingError::NegativeBalance
+                | AccountingError::PrecisionExceeded(..)
+                | AccountingError::MalformedRecord(..) => (),
+            }
+        }
+        variants
+    }
+
+    #[test]
+    fn test_registry_covers_every_variant() {
+        let variants = every_variant();
+        assert_eq!(variants.len(), ERRORS.len());
+        for (variant, info) in variants.iter().zip(ERRORS) {
+            assert_eq!(variant.code(), info.code);
+            assert!(
+                format!("{:?}", variant).starts_with(info.variant),
+                "{}",
+                info.variant
+            );
+            assert!(std::ptr::eq(variant.info(), info));
+        }
+    }
+
+    #[test]
+    fn test_classification_matches_registry() {
+        for variant in every_variant() {
+            let classes = [
+                variant.is_io(),
+                variant.is_parse(),
+                variant.is_business_rule(),
+            ];
+            assert!(classes.iter().filter(|class| **class).count() <= 1);
+            assert_eq!(
+                variant.is_recoverable(),
+                variant.info().recoverable,
+                "{}",
+                variant.code()
+            );
+        }
+    }
+
+    #[test]
+    fn test_source_chaining() {
+        use std::error::Error;
+
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        let error = AccountingError::Input(format!("input.csv: {}", io), source(io));
+        assert_eq!(
+            error.to_string(),
+            "Error: Could not open input: input.csv: no such file"
+        );
+        let source = error.source().unwrap();
+        assert_eq!(
+            source.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        // Cloning shares the source
+        assert!(error.clone().source().is_some());
+        assert!(AccountingError::Input("no path".to_string(), None)
+            .source()
+            .is_none());
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup("account_locked").unwrap().variant, "AccountLocked");
+        assert_eq!(lookup("E_ACCOUNT_LOCKED").unwrap().variant, "AccountLocked");
+        assert!(lookup("insufficient_funds").is_none());
+        assert_eq!(
+            lookup("E_EXCESSIVE_PRECISION").unwrap().code,
+            "precision_exceeded"
+        );
+        assert_eq!(renamed_to("invalid_precision"), Some("precision_exceeded"));
+        assert_eq!(renamed_to("precision_exceeded"), None);
+        // Every renamed code points at a code in the registry, and isn't reused by one
+        for (old, new) in RENAMED_CODES {
+            assert!(ERRORS.iter().all(|info| info.code != *old));
+            assert!(lookup(new).is_some());
+        }
+        assert_eq!(
+            suggestions("E_INSUFFICIENT_FUNDS"),
+            vec!["no_funds_available"]
+        );
+        assert!(suggestions("dispute").contains(&"dispute_client_mismatch"));
+        assert!(suggestions("xyz").is_empty());
+    }
 }
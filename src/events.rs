@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use rust_decimal_macros::dec;
+use tokio::sync::{broadcast, watch};
+
+use crate::account::Account;
+use crate::transaction::{Amount, ClientID, TxID};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedgerEvent {
+    // The first deposit for a client id has been applied
+    AccountCreated {
+        client: ClientID,
+        first_tx: TxID,
+    },
+    // The client's cumulative deposits went over the deposit volume limit with this deposit
+    DepositVolumeExceeded {
+        client: ClientID,
+        tx: TxID,
+    },
+    // A withdrawal was rejected because the client's withdrawals are suspended
+    WithdrawalSuspended {
+        client: ClientID,
+        tx: TxID,
+    },
+    // A disputed transaction was charged back, whether or not it locked the account
+    ChargebackApplied {
+        client: ClientID,
+        tx: TxID,
+    },
+    // The chargeback of the given transaction locked the account, see ChargebackLockPolicy
+    AccountLocked {
+        client: ClientID,
+        tx: TxID,
+    },
+    // A transaction was applied and given the next global sequence number, only published with sequencing.
+    // Interest has no tx id
+    TransactionAccepted {
+        client: ClientID,
+        tx: Option<TxID>,
+        seq: u64,
+    },
+}
+
+// The balances of an account after a transaction was applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountSnapshot {
+    pub client: ClientID,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+impl From<&Account> for AccountSnapshot {
+    fn from(account: &Account) -> Self {
+        AccountSnapshot {
+            client: account.client,
+            available: account.available(),
+            held: account.held(),
+            total: account.total(),
+            locked: account.locked,
+        }
+    }
+}
+
+// Broadcasts ledger events to any number of subscribers. A single bus is shared between all
+// processors working on the same accounts, so events that must only fire once do so globally
+pub struct EventBus {
+    sender: broadcast::Sender<LedgerEvent>,
+    opened_accounts: Mutex<BTreeSet<ClientID>>,
+    // Balance watches of single clients, only clients someone is watching have an entry
+    watches: Mutex<BTreeMap<ClientID, watch::Sender<AccountSnapshot>>>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus {
+            sender,
+            opened_accounts: Default::default(),
+            watches: Default::default(),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LedgerEvent> {
+        self.sender.subscribe()
+    }
+
+    pub fn accounts_created(&self) -> usize {
+        self.opened_accounts.lock().unwrap().len()
+    }
+
+    // Watches the balances of a single client, the receiver sees a new snapshot after every transaction
+    // applied to the account. `current` is the account as it is now, if it exists, and is only used when
+    // nobody is watching the client yet
+    pub fn watch(
+        &self,
+        client: ClientID,
+        current: Option<&Account>,
+    ) -> watch::Receiver<AccountSnapshot> {
+        let mut watches = self.watches.lock().unwrap();
+        // Drop the watches whose receivers are all gone, including clients without any activity since
+        watches.retain(|_, sender| sender.receiver_count() > 0);
+        watches
+            .entry(client)
+            .or_insert_with(|| {
+                let snapshot = match current {
+                    Some(account) => account.into(),
+                    None => AccountSnapshot::from(&Account::new(client, dec!(0), dec!(0), dec!(0))),
+                };
+                watch::channel(snapshot).0
+            })
+            .subscribe()
+    }
+
+    pub fn watched_clients(&self) -> usize {
+        self.watches.lock().unwrap().len()
+    }
+
+    // Called after any transaction has been applied to the account
+    pub fn account_updated(&self, account: &Account) {
+        let mut watches = self.watches.lock().unwrap();
+        if let Some(sender) = watches.get(&account.client) {
+            // Fails once the last receiver is gone, the watch isn't needed anymore then
+            if sender.send(account.into()).is_err() {
+                watches.remove(&account.client);
+            }
+        }
+    }
+
+    // Called after a deposit has been applied. Only deposits open accounts, so rejected rows that
+    // merely reference an unknown client never produce an AccountCreated event
+    pub fn deposit_applied(&self, client: ClientID, tx: TxID) {
+        if self.opened_accounts.lock().unwrap().insert(client) {
+            self.emit(LedgerEvent::AccountCreated {
+                client,
+                first_tx: tx,
+            });
+        }
+    }
+
+    pub fn deposit_volume_exceeded(&self, client: ClientID, tx: TxID) {
+        self.emit(LedgerEvent::DepositVolumeExceeded { client, tx });
+    }
+
+    pub fn withdrawal_suspended(&self, client: ClientID, tx: TxID) {
+        self.emit(LedgerEvent::WithdrawalSuspended { client, tx });
+    }
+
+    pub fn chargeback_applied(&self, client: ClientID, tx: TxID) {
+        self.emit(LedgerEvent::ChargebackApplied { client, tx });
+    }
+
+    pub fn account_locked(&self, client: ClientID, tx: TxID) {
+        self.emit(LedgerEvent::AccountLocked { client, tx });
+    }
+
+    pub fn transaction_accepted(&self, client: ClientID, tx: Option<TxID>, seq: u64) {
+        self.emit(LedgerEvent::TransactionAccepted { client, tx, seq });
+    }
+
+    fn emit(&self, event: LedgerEvent) {
+        // Sending only fails when nobody is subscribed, which is fine
+        let _ = self.sender.send(event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watch_account() {
+        let events = EventBus::new(16);
+        let mut account = Account::new(1, dec!(1), dec!(0), dec!(1));
+        let mut receiver = events.watch(1, Some(&account));
+        assert_eq!(receiver.borrow_and_update().total, dec!(1));
+
+        account.deposit(dec!(2)).unwrap();
+        events.account_updated(&account);
+        // Other clients don't touch the watch
+        events.account_updated(&Account::new(2, dec!(5), dec!(0), dec!(5)));
+        assert!(receiver.has_changed().unwrap());
+        assert_eq!(
+            *receiver.borrow_and_update(),
+            AccountSnapshot {
+                client: 1,
+                available: dec!(3),
+                held: dec!(0),
+                total: dec!(3),
+                locked: false,
+            }
+        );
+        assert!(!receiver.has_changed().unwrap());
+        assert_eq!(events.watched_clients(), 1);
+    }
+
+    #[test]
+    fn test_watch_is_dropped_with_last_receiver() {
+        let events = EventBus::new(16);
+        let first = events.watch(1, None);
+        let second = events.watch(1, None);
+        assert_eq!(events.watched_clients(), 1);
+        drop(first);
+        drop(second);
+
+        // Dropped on the next update of the client
+        events.account_updated(&Account::new(1, dec!(1), dec!(0), dec!(1)));
+        assert_eq!(events.watched_clients(), 0);
+
+        // Or when watching another client, for clients without activity
+        drop(events.watch(2, None));
+        let receiver = events.watch(3, None);
+        assert_eq!(events.watched_clients(), 1);
+        // Watching a client again starts a new watch
+        drop(receiver);
+        let receiver = events.watch(3, None);
+        assert_eq!(receiver.borrow().total, dec!(0));
+        assert_eq!(events.watched_clients(), 1);
+    }
+}
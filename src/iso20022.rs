@@ -0,0 +1,182 @@
+// Support for ISO 20022 payment messages as an alternative to csv input
+pub mod pain001 {
+    use std::io::{BufReader, Read};
+
+    use quick_xml::events::Event;
+    use quick_xml::Reader;
+    use rust_decimal_macros::dec;
+
+    use crate::transaction::{Amount, AmountExt, ClientID, CurrencyCode, Transaction, TxID};
+    use crate::AccountingError;
+
+    // The fields of a single CdtTrfTxInf element, collected while walking the document
+    #[derive(Default)]
+    struct CreditTransfer {
+        end_to_end_id: Option<String>,
+        amount: Option<String>,
+        currency: Option<String>,
+        creditor_account: Option<String>,
+    }
+
+    // Parses a pain.001 customer credit transfer initiation. Every CdtTrfTxInf becomes one transaction:
+    // a withdrawal if the debtor account of its payment information block is one of our client ids,
+    // otherwise a deposit if the creditor account is. The EndToEndId is used as the transaction id
+    pub fn parse_credit_transfer<R: Read>(reader: R) -> Result<Vec<Transaction>, AccountingError> {
+        let mut reader = Reader::from_reader(BufReader::new(reader));
+        reader.config_mut().trim_text(true);
+
+        let mut transactions = Vec::new();
+        let mut path: Vec<String> = Vec::new();
+        let mut debtor_account: Option<String> = None;
+        let mut transfer: Option<CreditTransfer> = None;
+        let mut buf = Vec::new();
+        loop {
+            match reader.read_event_into(&mut buf).map_err(xml_error)? {
+                Event::Start(e) => {
+                    let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                    match name.as_str() {
+                        "PmtInf" => debtor_account = None,
+                        "CdtTrfTxInf" => transfer = Some(CreditTransfer::default()),
+                        "InstdAmt" => {
+                            if let (Some(transfer), Some(currency)) = (
+                                transfer.as_mut(),
+                                e.try_get_attribute("Ccy").map_err(xml_error)?,
+                            ) {
+                                transfer.currency = Some(
+                                    currency.unescape_value().map_err(xml_error)?.into_owned(),
+                                );
+                            }
+                        }
+                        _ => (),
+                    }
+                    path.push(name);
+                }
+                Event::Text(e) => {
+                    let text = e.unescape().map_err(xml_error)?.into_owned();
+                    if ends_with(&path, &["PmtInf", "DbtrAcct", "Id", "Othr", "Id"]) {
+                        debtor_account = Some(text);
+                    } else if let Some(transfer) = transfer.as_mut() {
+                        if ends_with(&path, &["PmtId", "EndToEndId"]) {
+                            transfer.end_to_end_id = Some(text);
+                        } else if ends_with(&path, &["Amt", "InstdAmt"]) {
+                            transfer.amount = Some(text);
+                        } else if ends_with(&path, &["CdtrAcct", "Id", "Othr", "Id"]) {
+                            transfer.creditor_account = Some(text);
+                        }
+                    }
+                }
+                Event::End(e) => {
+                    if e.local_name().as_ref() == b"CdtTrfTxInf" {
+                        if let Some(transfer) = transfer.take() {
+                            transactions.push(to_transaction(transfer, debtor_account.as_deref())?);
+                        }
+                    }
+                    path.pop();
+                }
+                Event::Eof => break,
+                _ => (),
+            }
+            buf.clear();
+        }
+        Ok(transactions)
+    }
+
+    fn to_transaction(
+        transfer: CreditTransfer,
+        debtor_account: Option<&str>,
+    ) -> Result<Transaction, AccountingError> {
+        let tx_id = transfer
+            .end_to_end_id
+            .as_deref()
+            .and_then(|id| id.parse::<TxID>().ok())
+            .ok_or(AccountingError::MalformedTransaction)?;
+        let amount = Amount::from_str_strict(
+            transfer
+                .amount
+                .as_deref()
+                .ok_or(AccountingError::MalformedTransaction)?,
+        )?;
+        if amount <= dec!(0) {
+            return Err(AccountingError::MalformedTransaction);
+        }
+        let currency = match transfer.currency.as_deref() {
+            Some(currency) => Some(currency.parse::<CurrencyCode>()?),
+            None => None,
+        };
+
+        let debtor = debtor_account.and_then(|id| id.parse::<ClientID>().ok());
+        let creditor = transfer
+            .creditor_account
+            .as_deref()
+            .and_then(|id| id.parse::<ClientID>().ok());
+        match (debtor, creditor) {
+            (Some(client_id), _) => {
+                Ok(Transaction::withdrawal(client_id, tx_id, amount).in_currency(currency))
+            }
+            (None, Some(client_id)) => {
+                Ok(Transaction::deposit(client_id, tx_id, amount).in_currency(currency))
+            }
+            (None, None) => Err(AccountingError::MalformedTransaction),
+        }
+    }
+
+    fn ends_with(path: &[String], suffix: &[&str]) -> bool {
+        path.len() >= suffix.len()
+            && path[path.len() - suffix.len()..]
+                .iter()
+                .zip(suffix)
+                .all(|(name, expected)| name == expected)
+    }
+
+    fn xml_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> AccountingError {
+        AccountingError::Deserialize(e.to_string(), crate::error::source(e))
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_parse_credit_transfer() {
+            let transactions =
+                parse_credit_transfer(include_bytes!("../test_pain001.xml").as_slice()).unwrap();
+            assert_eq!(transactions.len(), 3);
+            match &transactions[0] {
+                Transaction::Withdrawal(tx) => {
+                    assert_eq!((tx.client_id, tx.tx_id), (1, 1001));
+                    assert_eq!(tx.amount, Some(dec!(150.25)));
+                    assert_eq!(tx.currency, Some(CurrencyCode(*b"EUR")));
+                }
+                other => panic!("expected withdrawal, got {:?}", other),
+            }
+            match &transactions[1] {
+                Transaction::Withdrawal(tx) => {
+                    assert_eq!((tx.client_id, tx.tx_id), (1, 1002));
+                    assert_eq!(tx.amount, Some(dec!(20)));
+                }
+                other => panic!("expected withdrawal, got {:?}", other),
+            }
+            // The debtor of the second payment information block is external, the creditor is ours
+            match &transactions[2] {
+                Transaction::Deposit(tx) => {
+                    assert_eq!((tx.client_id, tx.tx_id), (7, 1003));
+                    assert_eq!(tx.amount, Some(dec!(99.9999)));
+                }
+                other => panic!("expected deposit, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_parse_credit_transfer_rejects_excess_precision() {
+            let xml = r#"<Document><CstmrCdtTrfInitn><PmtInf>
+                <DbtrAcct><Id><Othr><Id>1</Id></Othr></Id></DbtrAcct>
+                <CdtTrfTxInf><PmtId><EndToEndId>1</EndToEndId></PmtId>
+                <Amt><InstdAmt Ccy="EUR">1.00001</InstdAmt></Amt></CdtTrfTxInf>
+            </PmtInf></CstmrCdtTrfInitn></Document>"#;
+            assert!(matches!(
+                parse_credit_transfer(xml.as_bytes()),
+                Err(AccountingError::PrecisionExceeded(None, 5))
+            ));
+        }
+    }
+}
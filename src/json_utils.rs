@@ -0,0 +1,442 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use rust_decimal::Decimal;
+use serde_json::Value;
+
+use crate::client_id_map::ClientIdMap;
+use crate::csv_utils::{skip_line, Record, TransactionReaderConfig, TRANSACTION_TYPES};
+use crate::error::source;
+use crate::pipeline::TransactionSource;
+use crate::transaction::{Transaction, TxID};
+use crate::AccountingError;
+
+// Reads transactions from newline-delimited json, one object per line with the same fields as the
+// csv columns, e.g. {"type":"deposit","client":1,"tx":5,"amount":"2.5"}. Blank lines are skipped.
+// The input comes from our own services, so unlike csv it must stick to the schema: no unknown fields,
+// amounts as strings, client and tx as integers and the type spelled exactly. With
+// TransactionReaderConfig::lenient_json it is read as tolerantly as csv
+pub struct JsonTransactionReader {
+    input: BufReader<Box<dyn Read + Send>>,
+    // Number of the line read last, starting at 1
+    line: usize,
+    config: TransactionReaderConfig,
+}
+
+impl JsonTransactionReader {
+    pub fn new(path: String) -> Result<Self, Box<dyn Error>> {
+        JsonTransactionReader::with_config(path, TransactionReaderConfig::default())
+    }
+
+    // A path of "-" or "" reads from stdin, like the csv reader
+    pub fn with_config(
+        path: String,
+        config: TransactionReaderConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        if path.is_empty() || path == "-" {
+            return JsonTransactionReader::from_reader(std::io::stdin(), config);
+        }
+        JsonTransactionReader::from_reader(File::open(path)?, config)
+    }
+
+    pub fn from_reader<R: Read + Send + 'static>(
+        reader: R,
+        config: TransactionReaderConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        if config.ordering.is_some() {
+            return Err("ordering by a sequence column is only supported for csv input".into());
+        }
+        let reader: Box<dyn Read + Send> = Box::new(reader);
+        Ok(JsonTransactionReader {
+            input: BufReader::new(reader),
+            line: 0,
+            config,
+        })
+    }
+
+    pub fn get_next_record(&mut self) -> Result<Option<Transaction>, AccountingError> {
+        let limits = self.config.record_limits;
+        loop {
+            // Reads at most one byte past the limit, so an oversized line is never buffered whole
+            let mut line = Vec::new();
+            let read = (&mut self.input)
+                .take(limits.max_record_bytes as u64 + 1)
+                .read_until(b'\n', &mut line)
+                .map_err(|e| self.deserialize_error(e))?;
+            if read == 0 {
+                // No more transactions should not be an error, so return Ok(None)
+                return Ok(None);
+            }
+            self.line += 1;
+            if line.strip_suffix(b"\n").unwrap_or(&line).len() > limits.max_record_bytes {
+                if line.last() != Some(&b'\n') {
+                    skip_line(&mut self.input).map_err(|e| self.deserialize_error(e))?;
+                }
+                return Err(AccountingError::OversizedRecord(
+                    self.line as u64,
+                    limits.max_record_bytes,
+                ));
+            }
+            let line = String::from_utf8(line).map_err(|e| self.deserialize_error(e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = match self.config.lenient_json {
+                true => parse_lenient_record(&line, limits.max_field_bytes),
+                false => parse_strict_record(&line, limits.max_field_bytes),
+            };
+            let record = record.map_err(|e| match e {
+                RecordError::Json(e) => self.deserialize_error(e),
+                RecordError::Schema(message) => {
+                    AccountingError::Deserialize(format!("line {}: {}", self.line, message), None)
+                }
+                RecordError::UnknownTransactionType(transaction_type) => {
+                    AccountingError::UnknownTransactionType(transaction_type)
+                }
+                RecordError::OversizedField => {
+                    AccountingError::OversizedField(self.line as u64, limits.max_field_bytes)
+                }
+            })?;
+            return self.config.record_to_transaction(record).map(Some);
+        }
+    }
+
+    fn deserialize_error<E: Error + Send + Sync + 'static>(&self, error: E) -> AccountingError {
+        AccountingError::Deserialize(format!("line {}: {}", self.line, error), source(error))
+    }
+}
+
+impl TransactionSource for JsonTransactionReader {
+    fn poll_transaction(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Transaction>, AccountingError>> {
+        Poll::Ready(self.get_next_record())
+    }
+
+    fn client_id_map(&self) -> Option<&ClientIdMap> {
+        self.config.client_id_map.as_ref()
+    }
+
+    fn take_client_id_map(&mut self) -> Option<ClientIdMap> {
+        self.config.client_id_map.take()
+    }
+
+    fn set_client_id_map(&mut self, client_id_map: ClientIdMap) {
+        self.config.client_id_map = Some(client_id_map);
+    }
+}
+
+enum RecordError {
+    Json(serde_json::Error),
+    // A field of the wrong json type, with a message for the producer
+    Schema(String),
+    UnknownTransactionType(String),
+    OversizedField,
+}
+
+// A line of the strict schema. Values that must have a specific json type are taken as they are and
+// checked in parse_strict_record, so the error can name the field and what was expected
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonRecord {
+    #[serde(rename = "type")]
+    transaction_type: Option<String>,
+    client: Option<Value>,
+    tx: Option<Value>,
+    amount: Option<Value>,
+    rate: Option<Value>,
+    period_days: Option<u32>,
+    under_dispute: Option<bool>,
+    #[cfg(feature = "multi-currency")]
+    currency: Option<String>,
+}
+
+fn parse_value(line: &str, max_field_bytes: usize) -> Result<Value, RecordError> {
+    let value: Value = serde_json::from_str(line).map_err(RecordError::Json)?;
+    if let Some(fields) = value.as_object() {
+        if fields.values().any(|field| {
+            field
+                .as_str()
+                .is_some_and(|field| field.len() > max_field_bytes)
+        }) {
+            return Err(RecordError::OversizedField);
+        }
+    }
+    Ok(value)
+}
+
+fn parse_strict_record(line: &str, max_field_bytes: usize) -> Result<Record, RecordError> {
+    let record: JsonRecord =
+        serde_json::from_value(parse_value(line, max_field_bytes)?).map_err(RecordError::Json)?;
+    // Not normalized like csv types, so "Deposit" or "withdraw" is an unknown type
+    if let Some(transaction_type) = &record.transaction_type {
+        if !TRANSACTION_TYPES.contains(&transaction_type.as_str()) {
+            return Err(RecordError::UnknownTransactionType(
+                transaction_type.clone(),
+            ));
+        }
+    }
+    let rate = match record.rate {
+        Some(rate) => {
+            let rate = decimal_string("rate", rate)?;
+            Some(Decimal::from_str(&rate).map_err(|e| {
+                RecordError::Schema(format!("rate {:?} is not a decimal: {}", rate, e))
+            })?)
+        }
+        None => None,
+    };
+    Ok(Record {
+        transaction_type: record.transaction_type,
+        // The client id map, if any, resolves it like a csv client column
+        client: record
+            .client
+            .map(|client| integer::<u64>("client", client))
+            .transpose()?
+            .map(|client| client.to_string()),
+        tx: record.tx.map(|tx| integer::<TxID>("tx", tx)).transpose()?,
+        amount: record
+            .amount
+            .map(|amount| decimal_string("amount", amount))
+            .transpose()?,
+        rate,
+        period_days: record.period_days,
+        under_dispute: record.under_dispute,
+        #[cfg(feature = "multi-currency")]
+        currency: record.currency,
+    })
+}
+
+fn integer<T: TryFrom<u64>>(field: &str, value: Value) -> Result<T, RecordError> {
+    let number = value.as_u64().ok_or_else(|| {
+        RecordError::Schema(format!("{} must be an integer, got {}", field, value))
+    })?;
+    T::try_from(number)
+        .map_err(|_| RecordError::Schema(format!("{} {} is out of range", field, number)))
+}
+
+// Decimals are sent as strings, a json number is read as a float by many producers and may already
+// have lost precision
+fn decimal_string(field: &str, value: Value) -> Result<String, RecordError> {
+    match value {
+        Value::String(decimal) => Ok(decimal),
+        Value::Number(number) => Err(RecordError::Schema(format!(
+            "{} must be sent as a string such as \"{}\", numbers may lose precision",
+            field, number
+        ))),
+        other => Err(RecordError::Schema(format!(
+            "{} must be a string, got {}",
+            field, other
+        ))),
+    }
+}
+
+// Client ids are numbers in json but strings in a Record, as they may also be external ids.
+// Amounts stay strings, so they are never read as a float
+fn parse_lenient_record(line: &str, max_field_bytes: usize) -> Result<Record, RecordError> {
+    let mut value = parse_value(line, max_field_bytes)?;
+    if let Some(client) = value.get_mut("client") {
+        if let Value::Number(number) = client {
+            *client = Value::String(number.to_string());
+        }
+    }
+    serde_json::from_value(value).map_err(RecordError::Json)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn reader_for(input: &'static str) -> JsonTransactionReader {
+        JsonTransactionReader::from_reader(input.as_bytes(), Default::default()).unwrap()
+    }
+
+    #[test]
+    fn test_read_json_lines() {
+        let mut reader = reader_for(
+            r#"{"type":"deposit","client":1,"tx":5,"amount":"2.5"}
+
+{"type":"withdrawal","client":2,"tx":6,"amount":"1"}
+{"type":"dispute","client":1,"tx":5}
+"#,
+        );
+        match reader.get_next_record() {
+            Ok(Some(Transaction::Deposit(tx))) => {
+                assert_eq!((tx.client_id, tx.tx_id, tx.amount), (1, 5, Some(dec!(2.5))))
+            }
+            other => panic!("expected deposit, got {:?}", other),
+        }
+        assert!(matches!(
+            reader.get_next_record(),
+            Ok(Some(Transaction::Withdrawal(_)))
+        ));
+        assert!(matches!(
+            reader.get_next_record(),
+            Ok(Some(Transaction::Dispute(_)))
+        ));
+        assert!(matches!(reader.get_next_record(), Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_all_transaction_types() {
+        let mut reader = reader_for(
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"10.0"}
+{"type":"deposit","client":1,"tx":2,"amount":"4.0"}
+{"type":"withdrawal","client":1,"tx":3,"amount":"1.5"}
+{"type":"dispute","client":1,"tx":1}
+{"type":"resolve","client":1,"tx":1}
+{"type":"dispute","client":1,"tx":2}
+{"type":"chargeback","client":1,"tx":2}
+"#,
+        );
+        let mut transactions = Vec::new();
+        while let Some(tx) = reader.get_next_record().unwrap() {
+            transactions.push(tx);
+        }
+        assert_eq!(
+            transactions.iter().map(|tx| tx.kind()).collect::<Vec<_>>(),
+            vec![
+                "deposit",
+                "deposit",
+                "withdrawal",
+                "dispute",
+                "resolve",
+                "dispute",
+                "chargeback"
+            ]
+        );
+        let accounts = crate::process_all(transactions).await;
+        let mut expected = crate::Account::new(1, dec!(8.5), dec!(0), dec!(8.5));
+        expected.locked = true;
+        expected.chargebacks = 1;
+        assert_eq!(accounts.get(&1), Some(&expected));
+    }
+
+    #[test]
+    fn test_malformed_line_reports_line_number() {
+        let mut reader = reader_for(
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"1.0"}
+{"type":"deposit","client":1,
+{"type":"deposit","client":1,"tx":2,"amount":"1.0"}
+"#,
+        );
+        assert!(reader.get_next_record().unwrap().is_some());
+        match reader.get_next_record() {
+            Err(AccountingError::Deserialize(message, _)) => {
+                assert!(message.starts_with("line 2:"))
+            }
+            other => panic!("expected a deserialize error, got {:?}", other),
+        }
+        // The reader carries on with the next line
+        assert!(reader.get_next_record().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_oversized_lines() {
+        let input = format!(
+            "{{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.{}\"}}\n{{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":\"{}\"}}\n{{\"type\":\"deposit\",\"client\":1,\"tx\":3,\"amount\":\"1.0\"}}\n",
+            "0".repeat(20_000),
+            " ".repeat(2_000),
+        );
+        let mut reader = JsonTransactionReader::from_reader(
+            std::io::Cursor::new(input.into_bytes()),
+            Default::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::OversizedRecord(1, _))
+        ));
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::OversizedField(2, 1024))
+        ));
+        assert!(matches!(
+            reader.get_next_record(),
+            Ok(Some(Transaction::Deposit(_)))
+        ));
+    }
+
+    #[test]
+    fn test_deposit_without_amount() {
+        let mut reader = reader_for(r#"{"type":"deposit","client":1,"tx":1}"#);
+        assert!(matches!(
+            reader.get_next_record(),
+            Err(AccountingError::MalformedTransaction)
+        ));
+    }
+
+    // Each line breaks one rule of the strict schema, with the error the producer gets
+    const SCHEMA_VIOLATIONS: &str = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.0","memo":"rent"}
+{"type":"deposit","client":1,"tx":2,"amount":1.5}
+{"type":"deposit","client":1,"tx":3,"amount":true}
+{"type":"deposit","client":"1","tx":4,"amount":"1.0"}
+{"type":"deposit","client":1,"tx":5.0,"amount":"1.0"}
+{"type":"deposit","client":1,"tx":4294967296,"amount":"1.0"}
+{"type":"interest","client":1,"rate":0.05,"period_days":30}
+{"type":"Deposit","client":1,"tx":6,"amount":"1.0"}
+{"type":"withdraw","client":1,"tx":7,"amount":"1.0"}
+"#;
+
+    #[test]
+    fn test_strict_schema() {
+        let mut reader = reader_for(SCHEMA_VIOLATIONS);
+        for expected in [
+            "line 1: unknown field `memo`",
+            r#"line 2: amount must be sent as a string such as "1.5", numbers may lose precision"#,
+            "line 3: amount must be a string, got true",
+            r#"line 4: client must be an integer, got "1""#,
+            "line 5: tx must be an integer, got 5.0",
+            "line 6: tx 4294967296 is out of range",
+            r#"line 7: rate must be sent as a string such as "0.05", numbers may lose precision"#,
+        ] {
+            match reader.get_next_record() {
+                Err(AccountingError::Deserialize(message, _)) => {
+                    assert!(message.starts_with(expected), "{}", message)
+                }
+                other => panic!("expected {}, got {:?}", expected, other),
+            }
+        }
+        // Types are case-sensitive and the spellings csv partners use aren't accepted
+        for expected in ["Deposit", "withdraw"] {
+            match reader.get_next_record() {
+                Err(AccountingError::UnknownTransactionType(transaction_type)) => {
+                    assert_eq!(transaction_type, expected)
+                }
+                other => panic!("expected unknown type {}, got {:?}", expected, other),
+            }
+        }
+        assert!(matches!(reader.get_next_record(), Ok(None)));
+    }
+
+    #[test]
+    fn test_lenient_json() {
+        let config = TransactionReaderConfig {
+            lenient_json: true,
+            ..Default::default()
+        };
+        let mut reader =
+            JsonTransactionReader::from_reader(SCHEMA_VIOLATIONS.as_bytes(), config).unwrap();
+        let mut accepted = Vec::new();
+        while let Some(result) = reader.get_next_record().transpose() {
+            if let Ok(tx) = result {
+                accepted.push((tx.kind(), tx.tx_id()));
+            }
+        }
+        // Json numbers still can't be read as a tx id or an amount
+        assert_eq!(
+            accepted,
+            vec![
+                ("deposit", Some(1)),
+                ("deposit", Some(4)),
+                ("interest", None),
+                ("deposit", Some(6)),
+                ("withdrawal", Some(7)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use std::ops::AddAssign;
+use std::time::Duration;
+
+const BUCKETS: usize = 32;
+
+// Histogram of processing times with power-of-two microsecond buckets: bucket 0 counts everything
+// below 2µs, bucket i counts [2^i, 2^(i+1)) µs and the last bucket everything above. Recording
+// is a single array increment, so it can be done for every transaction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros().max(1);
+        let bucket = (u128::BITS - 1 - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    // Lower bound of each bucket together with its count
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(i, count)| {
+            let lower = if i == 0 { 0 } else { 1u64 << i };
+            (Duration::from_micros(lower), *count)
+        })
+    }
+}
+
+// Adds up the histograms of several processors
+impl AddAssign for LatencyHistogram {
+    fn add_assign(&mut self, other: Self) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += count;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket_boundaries() {
+        let mut histogram = LatencyHistogram::default();
+        for micros in [0, 1, 2, 3, 4, 1023, 1024, 50_000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_secs(1 << 40));
+
+        let counts: Vec<(Duration, u64)> = histogram.buckets().filter(|(_, c)| *c > 0).collect();
+        assert_eq!(
+            counts,
+            vec![
+                (Duration::from_micros(0), 2),
+                (Duration::from_micros(2), 2),
+                (Duration::from_micros(4), 1),
+                (Duration::from_micros(512), 1),
+                (Duration::from_micros(1024), 1),
+                (Duration::from_micros(32768), 1),
+                (Duration::from_micros(1 << 31), 1),
+            ]
+        );
+        assert_eq!(histogram.count(), 9);
+    }
+}
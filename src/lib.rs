@@ -0,0 +1,40 @@
+// The processing engine, usable from other programs. The transactron binary is a thin csv CLI on top
+
+pub use account::Account;
+#[cfg(feature = "serde")]
+pub use csv_utils::TransactionReader;
+pub use error::AccountingError;
+pub use transaction::{Transaction, TransactionData};
+pub use transaction_processor::{process_all, TransactionProcessor};
+
+pub mod account;
+pub mod aggregates;
+#[cfg(feature = "serde")]
+pub mod anonymize;
+#[cfg(feature = "serde")]
+pub mod async_csv;
+#[cfg(feature = "serde")]
+pub mod client_id_map;
+pub mod compliance;
+#[cfg(feature = "serde")]
+pub mod conformance;
+#[cfg(feature = "serde")]
+pub mod csv_utils;
+pub mod disputes;
+pub mod error;
+pub mod events;
+#[cfg(feature = "iso20022")]
+pub mod iso20022;
+#[cfg(feature = "serde")]
+pub mod json_utils;
+pub mod latency;
+#[cfg(feature = "serde")]
+pub mod pipeline;
+pub mod rejection_log;
+pub mod reorder;
+pub mod rounding;
+pub mod run_diff;
+pub mod sharding;
+pub mod state;
+pub mod transaction;
+pub mod transaction_processor;
@@ -1,21 +1,42 @@
+#[cfg(feature = "serde")]
 use std::collections::BTreeMap;
+#[cfg(feature = "serde")]
 use std::sync::Arc;
+#[cfg(feature = "serde")]
+use std::time::Duration;
 
+#[cfg(feature = "serde")]
 use tokio::sync::RwLock;
-use tokio::task::JoinHandle;
 
-use account::Account;
-use csv_utils::TransactionReader;
-pub use error::AccountingError;
-use transaction::{ClientID, TransactionData, TxID};
-use transaction_processor::TransactionProcessor;
-
-mod account;
-mod csv_utils;
-mod error;
-mod transaction;
-mod transaction_processor;
+#[cfg(feature = "serde")]
+use transactron::async_csv::AsyncTransactionReader;
+#[cfg(feature = "serde")]
+use transactron::client_id_map::ClientIdMap;
+#[cfg(feature = "serde")]
+use transactron::csv_utils::{
+    self, OutputFormat, PrecisionPolicy, RecordLimits, ResyncPolicy, SequenceOrdering,
+    TransactionReaderConfig,
+};
+#[cfg(feature = "serde")]
+use transactron::json_utils::JsonTransactionReader;
+#[cfg(feature = "serde")]
+use transactron::pipeline::{PipelineBuilder, ProcessorStage, TransactionSource};
+#[cfg(feature = "serde")]
+use transactron::rejection_log::{LogSampling, RejectionLog};
+#[cfg(feature = "serde")]
+use transactron::sharding::ShardedProcessor;
+#[cfg(feature = "serde")]
+use transactron::state::LedgerState;
+#[cfg(feature = "serde")]
+use transactron::transaction::{Amount, ClientID, TransactionData, TxID};
+#[cfg(feature = "serde")]
+use transactron::transaction_processor::{
+    ChargebackLockPolicy, DepositVolumeLimit, TransactionProcessor, WithdrawalPolicy,
+};
+#[cfg(feature = "serde")]
+use transactron::{anonymize, conformance, error, Account, AccountingError};
 
+#[cfg(feature = "serde")]
 #[tokio::main]
 async fn main() -> Result<(), AccountingError> {
     // Let the ledger live throughout the lifetime of the program, and be shared between
@@ -23,47 +44,556 @@ async fn main() -> Result<(), AccountingError> {
     let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
     let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
 
-    let input_path = std::env::args()
-        .nth(1)
-        .expect("error: missing input file path");
-    if let Ok(mut reader) = TransactionReader::new(input_path) {
-        // Create the transaction processor for this input stream
-        let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
-        // Spawn a new thread for the processor, and let it await incoming data
-        let processor: JoinHandle<TransactionProcessor> =
-            tokio::spawn(async move { processor.process().await });
-
-        let processor_handle = processor;
-
-        loop {
-            match reader.get_next_record() {
-                Ok(Some(tx)) => {
-                    sender
-                        .send(tx)
-                        .map_err(|err| AccountingError::TokioChannel(err.to_string()))?;
-                }
-                Err(_e) => {
-                    // Log error, commented out for now to avoid clobbering stdout
-                    //eprintln!("Error: {}", _e);
-                }
-                // Done, no more records
-                Ok(None) => break,
+    if std::env::args().nth(1).as_deref() == Some("conformance") {
+        return run_conformance().await;
+    }
+    if std::env::args().nth(1).as_deref() == Some("explain") {
+        explain(std::env::args().nth(2));
+        return Ok(());
+    }
+    if std::env::args().nth(1).as_deref() == Some("anonymize") {
+        return run_anonymize().await;
+    }
+
+    let args = Args::parse();
+    let client_id_map = match &args.client_id_map {
+        // With auto assignment the map file is created on the first run
+        Some(path) if std::path::Path::new(path).exists() || !args.auto_assign_client_ids => {
+            Some(ClientIdMap::load(path, args.auto_assign_client_ids)?)
+        }
+        _ if args.client_id_map.is_some() || args.auto_assign_client_ids => {
+            Some(ClientIdMap::new(args.auto_assign_client_ids))
+        }
+        _ => None,
+    };
+    let config = TransactionReaderConfig {
+        client_id_map,
+        resync_policy: if args.no_resync {
+            ResyncPolicy::Disabled
+        } else {
+            ResyncPolicy::default()
+        },
+        trust_dispute_column: args.trust_dispute_column,
+        lenient_json: args.lenient_json,
+        record_limits: args.record_limits,
+        precision_policy: if args.round_excess_precision {
+            PrecisionPolicy::Round
+        } else {
+            PrecisionPolicy::Reject
+        },
+        ordering: args.order_by_column.clone().map(|column| SequenceOrdering {
+            column,
+            window: args.reorder_window,
+            strict: args.strict_sequence,
+        }),
+        ..Default::default()
+    };
+    let withdrawal_policy = match (&args.withdrawal_denylist, &args.withdrawal_allowlist) {
+        (Some(_), Some(_)) => {
+            return Err(AccountingError::Input(
+                "a withdrawal deny-list and allow-list can't be used together".to_string(),
+                None,
+            ))
+        }
+        (Some(path), None) => WithdrawalPolicy::Deny(csv_utils::read_client_list(path)?),
+        (None, Some(path)) => WithdrawalPolicy::Allow(csv_utils::read_client_list(path)?),
+        (None, None) => WithdrawalPolicy::default(),
+    };
+    // A file that can't be opened or read is an error rather than an empty report
+    let input_error = |e: Box<dyn std::error::Error>| AccountingError::Input(e.to_string(), None);
+    let mut readers: Vec<Box<dyn TransactionSource>> = Vec::new();
+    for (i, path) in args.input_paths.iter().enumerate() {
+        // The client id map is handed from one input to the next by the pipeline
+        let config = if i == 0 {
+            config.clone()
+        } else {
+            TransactionReaderConfig {
+                client_id_map: None,
+                ..config.clone()
+            }
+        };
+        // Csv is read without blocking the runtime, json lines are still read synchronously
+        readers.push(match args.format {
+            InputFormat::Csv => Box::new(AsyncTransactionReader::open(path, config).await?),
+            InputFormat::Json => Box::new(
+                JsonTransactionReader::with_config(path.clone(), config).map_err(input_error)?,
+            ),
+        });
+    }
+    // Create the transaction processor and pump the records of every input through it, so they share
+    // the ledger and the accounts
+    let withdrawal_policy = Arc::new(RwLock::new(withdrawal_policy));
+    let configure = |processor: TransactionProcessor| {
+        processor
+            .with_two_phase_withdrawals(args.two_phase_withdrawals)
+            .with_deposit_volume_limit(args.deposit_volume_limit)
+            .with_slow_tx_threshold(args.slow_tx_threshold)
+            .with_withdrawal_policy(withdrawal_policy.clone())
+            .with_chargeback_lock_policy(args.chargeback_lock_policy)
+    };
+    let processor: ProcessorStage = match args.shards {
+        1 => {
+            let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+            (configure(processor).into(), sender.into())
+        }
+        shards => {
+            let (processor, sender) =
+                ShardedProcessor::new(ledger.clone(), accounts.clone(), shards, configure);
+            (processor.into(), sender.into())
+        }
+    };
+    let stats = PipelineBuilder::new()
+        .readers(
+            readers
+                .iter_mut()
+                .map(|reader| reader.as_mut() as &mut dyn TransactionSource)
+                .collect(),
+        )
+        .rejection_log(RejectionLog::new(args.log_sampling))
+        .processor(processor)
+        .build()
+        .run()
+        .await?;
+
+    for (path, reader) in args.input_paths.iter().zip(&readers) {
+        // Only named when there are several inputs, so a single input keeps its usual messages
+        let input = match args.input_paths.len() {
+            1 => String::new(),
+            _ => format!(" in {}", path),
+        };
+        // Written to stderr so it doesn't end up in the csv output
+        for range in reader.skipped_ranges() {
+            eprintln!(
+                "warning: skipped corrupted input{} at bytes {}..{}",
+                input, range.start, range.end
+            );
+        }
+        if let Some(reorder) = reader.reorder_buffer() {
+            for gap in reorder.gaps() {
+                eprintln!(
+                    "warning: client {}{} is missing sequence numbers {}..{}",
+                    gap.client, input, gap.missing.start, gap.missing.end
+                );
             }
+            eprintln!("reorder buffer peak{}: {} rows", input, reorder.peak());
         }
+    }
+
+    let mismatches =
+        LedgerState::new(&*accounts.read().await, &*ledger.read().await).reconcile_held();
+    for mismatch in &mismatches {
+        eprintln!("warning: {}", mismatch);
+    }
+    if args.strict_invariants && !mismatches.is_empty() {
+        return Err(AccountingError::InvariantViolation(format!(
+            "{} held balances don't match the disputes",
+            mismatches.len()
+        )));
+    }
+
+    if let Some(path) = &args.aggregates {
+        let file = std::fs::File::create(path).map_err(|e| {
+            AccountingError::Output(format!("{}: {}", path, e), Some(error::ErrorSource::new(e)))
+        })?;
+        stats.volumes.write_csv(file)?;
+    }
 
-        drop(sender);
-        match processor_handle.await {
-            Ok(_processor) => (),
-            Err(e) => return Err(AccountingError::HandleAwait(e.to_string())),
+    let accounts_output = accounts.read().await;
+    let output = accounts_output
+        .clone()
+        .into_values()
+        .collect::<Vec<Account>>();
+    match &args.output {
+        Some(path) => csv_utils::write_output_file(output, path, args.output_format)?,
+        None => csv_utils::print_output(output, args.output_format)?,
+    }
+
+    // After the run the map is held by the last input
+    if let Some(client_id_map) = readers.last().and_then(|reader| reader.client_id_map()) {
+        // Auto-assigned ids are written back so they stay the same in the next run
+        if let (Some(path), true) = (&args.client_id_map, args.auto_assign_client_ids) {
+            client_id_map.save(path).map_err(|e| {
+                AccountingError::Output(
+                    format!("{}: {}", path, e),
+                    Some(error::ErrorSource::new(e)),
+                )
+            })?;
+        }
+        if let Some(path) = &args.dump_client_id_map {
+            client_id_map.save(path).map_err(|e| {
+                AccountingError::Output(
+                    format!("{}: {}", path, e),
+                    Some(error::ErrorSource::new(e)),
+                )
+            })?;
         }
+    }
+
+    if args.print_state_hash {
+        // Written to stderr so it doesn't end up in the csv output
+        let transactions = ledger.read().await;
+        eprintln!(
+            "state hash: {}",
+            LedgerState::new(&accounts_output, &transactions).state_hash_hex()
+        );
+    }
+    Ok(())
+}
 
-        let accounts_output = accounts.read().await;
-        let output = accounts_output
-            .clone()
-            .into_iter()
-            .map(|(_, v)| v)
-            .collect::<Vec<Account>>();
-        csv_utils::print_output(output);
+// Runs the conformance scenarios in the given directory, ./conformance by default, and exits
+// with an error if any of them fail
+#[cfg(feature = "serde")]
+async fn run_conformance() -> Result<(), AccountingError> {
+    let root = std::env::args()
+        .nth(2)
+        .unwrap_or_else(|| "conformance".to_string());
+    let results = conformance::run_all(std::path::Path::new(&root)).await?;
+    let mut failed = 0;
+    for result in &results {
+        match &result.failure {
+            None => println!("pass {}", result.name),
+            Some(failure) => {
+                failed += 1;
+                println!("FAIL {}\n{}", result.name, failure);
+            }
+        }
+    }
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        std::process::exit(1);
     }
     Ok(())
 }
+
+// Prints what an error code means, or every code if none is given. Unknown codes exit with an error
+#[cfg(feature = "serde")]
+fn explain(code: Option<String>) {
+    let Some(code) = code else {
+        for info in error::ERRORS {
+            println!("{:<30} {}", info.code, info.description);
+        }
+        for (old, new) in error::RENAMED_CODES {
+            println!("{:<30} renamed to {}", old, new);
+        }
+        return;
+    };
+    let Some(info) = error::lookup(&code) else {
+        eprintln!("error: unknown error code {}", code);
+        let suggestions = error::suggestions(&code);
+        if !suggestions.is_empty() {
+            eprintln!("did you mean: {}", suggestions.join(", "));
+        }
+        eprintln!("run `transactron explain` to list every error code");
+        std::process::exit(1);
+    };
+    if let Some(new) = error::renamed_to(&code) {
+        println!(
+            "{} has been renamed to {}, rejections are logged with the new code",
+            code, new
+        );
+    }
+    println!("{} ({})", info.code, info.variant);
+    println!("{}", info.description);
+    println!("triggered by: {}", info.triggers);
+    if info.recoverable {
+        println!("recoverable: yes, the row is rejected and the run carries on");
+    } else {
+        println!("recoverable: no, the run stops");
+    }
+    if !info.flags.is_empty() {
+        println!("related flags: {}", info.flags.join(", "));
+    }
+}
+
+// Writes a fixture of the given ledger with remapped client ids and perturbed amounts,
+// see anonymize::anonymize
+#[cfg(feature = "serde")]
+async fn run_anonymize() -> Result<(), AccountingError> {
+    let mut input_path = None;
+    let mut seed = None;
+    let mut out = None;
+    let mut args = std::env::args().skip(2);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => {
+                seed = Some(
+                    args.next()
+                        .and_then(|seed| seed.parse::<u64>().ok())
+                        .expect("error: missing or invalid seed"),
+                )
+            }
+            "--out" => out = Some(args.next().expect("error: missing output path")),
+            _ if !arg.starts_with("--") && input_path.is_none() => input_path = Some(arg),
+            _ => panic!("error: unknown argument {}", arg),
+        }
+    }
+    let transactions =
+        anonymize::read_transactions(&input_path.expect("error: missing input file path"))?;
+    let anonymized =
+        anonymize::anonymize(transactions, seed.expect("error: missing --seed")).await?;
+    if anonymized.unperturbed_clients > 0 {
+        eprintln!(
+            "warning: amounts of {} clients are kept, scaling them would change an outcome",
+            anonymized.unperturbed_clients
+        );
+    }
+    match out {
+        Some(path) => {
+            let file = std::fs::File::create(path).map_err(|e| {
+                AccountingError::Output(e.to_string(), Some(error::ErrorSource::new(e)))
+            })?;
+            anonymize::write_transactions(&anonymized.transactions, file)
+        }
+        None => anonymize::write_transactions(&anonymized.transactions, std::io::stdout()),
+    }
+}
+
+#[cfg(feature = "serde")]
+enum InputFormat {
+    Csv,
+    // Newline-delimited json, see JsonTransactionReader
+    Json,
+}
+
+#[cfg(feature = "serde")]
+struct Args {
+    // Read in order into the same ledger
+    input_paths: Vec<String>,
+    format: InputFormat,
+    output_format: OutputFormat,
+    output: Option<String>,
+    print_state_hash: bool,
+    client_id_map: Option<String>,
+    auto_assign_client_ids: bool,
+    dump_client_id_map: Option<String>,
+    two_phase_withdrawals: bool,
+    no_resync: bool,
+    deposit_volume_limit: Option<DepositVolumeLimit>,
+    slow_tx_threshold: Option<Duration>,
+    log_sampling: LogSampling,
+    order_by_column: Option<String>,
+    reorder_window: usize,
+    strict_sequence: bool,
+    withdrawal_denylist: Option<String>,
+    withdrawal_allowlist: Option<String>,
+    trust_dispute_column: bool,
+    strict_invariants: bool,
+    record_limits: RecordLimits,
+    round_excess_precision: bool,
+    // Number of processors the clients are spread over
+    shards: usize,
+    chargeback_lock_policy: ChargebackLockPolicy,
+    // Where to write the volume aggregates of the run, see VolumeAggregates
+    aggregates: Option<String>,
+    lenient_json: bool,
+}
+
+#[cfg(feature = "serde")]
+impl Args {
+    fn parse() -> Self {
+        let mut input_paths = Vec::new();
+        let mut format = InputFormat::Csv;
+        let mut output_format = OutputFormat::Csv;
+        let mut output = None;
+        let mut print_state_hash = false;
+        let mut client_id_map = None;
+        let mut auto_assign_client_ids = false;
+        let mut dump_client_id_map = None;
+        let mut two_phase_withdrawals = false;
+        let mut no_resync = false;
+        let mut volume_limit = None;
+        let mut enforce_volume_limit = false;
+        let mut slow_tx_threshold = None;
+        let mut log_sampling = LogSampling::default();
+        let mut order_by_column = None;
+        let mut reorder_window = 1000;
+        let mut strict_sequence = false;
+        let mut withdrawal_denylist = None;
+        let mut withdrawal_allowlist = None;
+        let mut trust_dispute_column = false;
+        let mut strict_invariants = false;
+        let mut record_limits = RecordLimits::default();
+        let mut round_excess_precision = false;
+        let mut shards = 1;
+        let mut chargeback_lock_policy = ChargebackLockPolicy::default();
+        let mut aggregates = None;
+        let mut lenient_json = false;
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--format" => {
+                    format = match args.next().as_deref() {
+                        Some("csv") => InputFormat::Csv,
+                        Some("json") => InputFormat::Json,
+                        _ => panic!("error: missing or invalid input format, expected csv or json"),
+                    }
+                }
+                "--output-format" => {
+                    output_format = match args.next().as_deref() {
+                        Some("csv") => OutputFormat::Csv,
+                        Some("json") => OutputFormat::Json,
+                        Some("jsonl") => OutputFormat::JsonLines,
+                        _ => panic!(
+                            "error: missing or invalid output format, expected csv, json or jsonl"
+                        ),
+                    }
+                }
+                "--output" => output = Some(args.next().expect("error: missing output path")),
+                "--print-state-hash" => print_state_hash = true,
+                "--client-id-map" => {
+                    client_id_map = Some(args.next().expect("error: missing client id map path"))
+                }
+                "--auto-assign-client-ids" => auto_assign_client_ids = true,
+                "--dump-client-id-map" => {
+                    dump_client_id_map =
+                        Some(args.next().expect("error: missing client id map dump path"))
+                }
+                "--two-phase-withdrawals" => two_phase_withdrawals = true,
+                "--no-resync" => no_resync = true,
+                "--deposit-volume-limit" => {
+                    volume_limit = Some(
+                        args.next()
+                            .and_then(|limit| limit.parse::<Amount>().ok())
+                            .expect("error: missing or invalid deposit volume limit"),
+                    )
+                }
+                "--enforce-volume-limit" => enforce_volume_limit = true,
+                "--slow-tx-threshold" => {
+                    slow_tx_threshold = Some(
+                        args.next()
+                            .as_deref()
+                            .and_then(parse_duration)
+                            .expect("error: missing or invalid slow transaction threshold"),
+                    )
+                }
+                "--rejection-log-first" => {
+                    log_sampling.first = args
+                        .next()
+                        .and_then(|first| first.parse().ok())
+                        .expect("error: missing or invalid number of rejections to log")
+                }
+                "--rejection-log-every" => {
+                    log_sampling.every = args
+                        .next()
+                        .and_then(|every| every.parse().ok())
+                        .filter(|every| *every > 0)
+                        .expect("error: missing or invalid rejection log sampling rate")
+                }
+                "--order-by-column" => {
+                    order_by_column = Some(args.next().expect("error: missing ordering column"))
+                }
+                "--reorder-window" => {
+                    reorder_window = args
+                        .next()
+                        .and_then(|window| window.parse().ok())
+                        .expect("error: missing or invalid reorder window")
+                }
+                "--strict-sequence" => strict_sequence = true,
+                "--withdrawal-denylist" => {
+                    withdrawal_denylist = Some(
+                        args.next()
+                            .expect("error: missing withdrawal deny-list path"),
+                    )
+                }
+                "--withdrawal-allowlist" => {
+                    withdrawal_allowlist = Some(
+                        args.next()
+                            .expect("error: missing withdrawal allow-list path"),
+                    )
+                }
+                "--trust-dispute-column" => trust_dispute_column = true,
+                "--strict-invariants" => strict_invariants = true,
+                "--max-field-bytes" => {
+                    record_limits.max_field_bytes = args
+                        .next()
+                        .and_then(|limit| limit.parse().ok())
+                        .expect("error: missing or invalid field size limit")
+                }
+                "--max-record-bytes" => {
+                    record_limits.max_record_bytes = args
+                        .next()
+                        .and_then(|limit| limit.parse().ok())
+                        .expect("error: missing or invalid record size limit")
+                }
+                "--round-excess-precision" => round_excess_precision = true,
+                "--shards" => {
+                    shards = args
+                        .next()
+                        .and_then(|shards| shards.parse().ok())
+                        .filter(|shards| *shards > 0)
+                        .expect("error: missing or invalid number of shards")
+                }
+                "--chargeback-lock-threshold" => {
+                    chargeback_lock_policy = ChargebackLockPolicy::Threshold {
+                        count: args
+                            .next()
+                            .and_then(|count| count.parse().ok())
+                            .filter(|count| *count > 0)
+                            .expect("error: missing or invalid chargeback lock threshold"),
+                    }
+                }
+                "--aggregates" => {
+                    aggregates = Some(args.next().expect("error: missing aggregates path"))
+                }
+                "--lenient-json" => lenient_json = true,
+                _ if !arg.starts_with("--") => input_paths.push(arg),
+                // A mistyped flag would otherwise run with the default behaviour
+                _ => panic!("error: unknown flag {}", arg),
+            }
+        }
+        if input_paths.is_empty() {
+            // Without a path the csv is read from stdin
+            input_paths.push(String::new());
+        }
+        Args {
+            input_paths,
+            format,
+            output_format,
+            output,
+            print_state_hash,
+            client_id_map,
+            auto_assign_client_ids,
+            dump_client_id_map,
+            two_phase_withdrawals,
+            no_resync,
+            deposit_volume_limit: volume_limit.map(|limit| DepositVolumeLimit {
+                limit,
+                enforce: enforce_volume_limit,
+            }),
+            slow_tx_threshold,
+            log_sampling,
+            order_by_column,
+            reorder_window,
+            strict_sequence,
+            withdrawal_denylist,
+            withdrawal_allowlist,
+            trust_dispute_column,
+            strict_invariants,
+            record_limits,
+            round_excess_precision,
+            shards,
+            chargeback_lock_policy,
+            aggregates,
+            lenient_json,
+        }
+    }
+}
+
+// Parses durations like 50ms, 2s or 500us
+#[cfg(feature = "serde")]
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let split = duration.find(|c: char| !c.is_ascii_digit())?;
+    let value = duration[..split].parse::<u64>().ok()?;
+    match &duration[split..] {
+        "us" => Some(Duration::from_micros(value)),
+        "ms" => Some(Duration::from_millis(value)),
+        "s" => Some(Duration::from_secs(value)),
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "serde"))]
+fn main() {
+    eprintln!("error: transactron was built without the serde feature, csv input is not available");
+    std::process::exit(1);
+}
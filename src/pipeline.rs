@@ -0,0 +1,687 @@
+use std::ops::Range;
+use std::task::{Context, Poll};
+
+use tokio::task::JoinHandle;
+
+use crate::client_id_map::ClientIdMap;
+use crate::compliance::ComplianceChecker;
+use crate::error::ErrorSource;
+use crate::rejection_log::RejectionLog;
+use crate::reorder::ReorderBuffer;
+use crate::sharding::ShardedProcessor;
+use crate::transaction::Transaction;
+use crate::transaction_processor::{ProcessingStats, TransactionProcessor, TransactionSender};
+use crate::AccountingError;
+
+// Reads transactions from an input, e.g. a csv or json lines file. The processor doesn't know
+// which format fed it, or whether it was read without blocking
+pub trait TransactionSource {
+    // Ok(None) once the input is exhausted, an error rejects a single record. Readers that block
+    // are always ready, async ones are pending while they wait for input
+    fn poll_transaction(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Transaction>, AccountingError>>;
+
+    // Byte ranges of corrupted input that were skipped
+    fn skipped_ranges(&self) -> &[Range<u64>] {
+        &[]
+    }
+
+    // Only csv inputs can be ordered by a sequence column
+    fn reorder_buffer(&self) -> Option<&ReorderBuffer> {
+        None
+    }
+
+    fn client_id_map(&self) -> Option<&ClientIdMap> {
+        None
+    }
+
+    // Hands the client id map on to the next input of the same run, so the ids assigned while
+    // reading one input stay the same in the next
+    fn take_client_id_map(&mut self) -> Option<ClientIdMap> {
+        None
+    }
+
+    fn set_client_id_map(&mut self, _client_id_map: ClientIdMap) {}
+}
+
+// Decides which transactions are passed on to the processor
+pub trait TransactionFilter: Send {
+    fn accept(&self, tx: &Transaction) -> bool;
+}
+
+impl<F> TransactionFilter for F
+where
+    F: Fn(&Transaction) -> bool + Send,
+{
+    fn accept(&self, tx: &Transaction) -> bool {
+        self(tx)
+    }
+}
+
+// Placeholder for a pipeline stage that has not been set yet, build() is only available once
+// both the reader and the processor are set
+pub struct Missing;
+
+// Applies the transactions sent on its channel, with a single processor or sharded by client
+pub enum Processor {
+    Single(TransactionProcessor),
+    Sharded(ShardedProcessor),
+}
+
+impl From<TransactionProcessor> for Processor {
+    fn from(processor: TransactionProcessor) -> Self {
+        Processor::Single(processor)
+    }
+}
+
+impl From<ShardedProcessor> for Processor {
+    fn from(processor: ShardedProcessor) -> Self {
+        Processor::Sharded(processor)
+    }
+}
+
+impl Processor {
+    fn with_compliance(self, compliance: ComplianceChecker) -> Self {
+        match self {
+            Processor::Single(processor) => processor.with_compliance(compliance).into(),
+            Processor::Sharded(processor) => processor.with_compliance(compliance).into(),
+        }
+    }
+
+    async fn process(self) -> Self {
+        match self {
+            Processor::Single(processor) => processor.process().await.into(),
+            Processor::Sharded(processor) => processor.process().await.into(),
+        }
+    }
+
+    fn stats(&self) -> ProcessingStats {
+        match self {
+            Processor::Single(processor) => processor.stats(),
+            Processor::Sharded(processor) => processor.stats(),
+        }
+    }
+}
+
+// A processor together with the sending half of its channel, as returned by TransactionProcessor::new
+// or ShardedProcessor::new
+pub type ProcessorStage = (Processor, TransactionSender);
+
+pub struct PipelineBuilder<R, P> {
+    reader: R,
+    filters: Vec<Box<dyn TransactionFilter>>,
+    compliance: Option<ComplianceChecker>,
+    rejection_log: RejectionLog,
+    processor: P,
+}
+
+impl PipelineBuilder<Missing, Missing> {
+    pub fn new() -> Self {
+        PipelineBuilder {
+            reader: Missing,
+            filters: Vec::new(),
+            compliance: None,
+            rejection_log: RejectionLog::default(),
+            processor: Missing,
+        }
+    }
+}
+
+impl Default for PipelineBuilder<Missing, Missing> {
+    fn default() -> Self {
+        PipelineBuilder::new()
+    }
+}
+
+impl<R, P> PipelineBuilder<R, P> {
+    pub fn reader(
+        self,
+        reader: &mut dyn TransactionSource,
+    ) -> PipelineBuilder<Vec<&mut dyn TransactionSource>, P> {
+        self.readers(vec![reader])
+    }
+
+    // Several inputs are read one after another, in the given order, into the same processor
+    pub fn readers(
+        self,
+        readers: Vec<&mut dyn TransactionSource>,
+    ) -> PipelineBuilder<Vec<&mut dyn TransactionSource>, P> {
+        PipelineBuilder {
+            reader: readers,
+            filters: self.filters,
+            compliance: self.compliance,
+            rejection_log: self.rejection_log,
+            processor: self.processor,
+        }
+    }
+
+    // Filters are applied in the order they were added, a transaction must pass all of them
+    pub fn filter<F: TransactionFilter + 'static>(mut self, filter: F) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    // Replaces any compliance checker the processor was created with
+    pub fn compliance(mut self, checker: ComplianceChecker) -> Self {
+        self.compliance = Some(checker);
+        self
+    }
+
+    // Replaces the default log for records the reader rejects
+    pub fn rejection_log(mut self, rejection_log: RejectionLog) -> Self {
+        self.rejection_log = rejection_log;
+        self
+    }
+
+    pub fn processor<T: Into<Processor>, S: Into<TransactionSender>>(
+        self,
+        (processor, sender): (T, S),
+    ) -> PipelineBuilder<R, ProcessorStage> {
+        PipelineBuilder {
+            reader: self.reader,
+            filters: self.filters,
+            compliance: self.compliance,
+            rejection_log: self.rejection_log,
+            processor: (processor.into(), sender.into()),
+        }
+    }
+}
+
+impl<'a> PipelineBuilder<Vec<&'a mut dyn TransactionSource>, ProcessorStage> {
+    pub fn build(self) -> Pipeline<'a> {
+        let (mut processor, sender) = self.processor;
+        if let Some(compliance) = self.compliance {
+            processor = processor.with_compliance(compliance);
+        }
+        Pipeline {
+            readers: self.reader,
+            filters: self.filters,
+            rejection_log: self.rejection_log,
+            processor,
+            sender,
+        }
+    }
+}
+
+// Reads every record from the readers, filters it and applies it with the processor
+pub struct Pipeline<'a> {
+    readers: Vec<&'a mut dyn TransactionSource>,
+    filters: Vec<Box<dyn TransactionFilter>>,
+    rejection_log: RejectionLog,
+    processor: Processor,
+    sender: TransactionSender,
+}
+
+impl<'a> Pipeline<'a> {
+    pub async fn run(mut self) -> Result<ProcessingStats, AccountingError> {
+        let mut stats = ProcessingStats::default();
+        // Spawn a new thread for the processor, and let it await incoming data
+        let processor: JoinHandle<Processor> =
+            tokio::spawn(async move { self.processor.process().await });
+
+        for i in 0..self.readers.len() {
+            if i > 0 {
+                if let Some(client_id_map) = self.readers[i - 1].take_client_id_map() {
+                    self.readers[i].set_client_id_map(client_id_map);
+                }
+            }
+            loop {
+                let reader = &mut self.readers[i];
+                match std::future::poll_fn(|cx| reader.poll_transaction(cx)).await {
+                    Ok(Some(tx)) => {
+                        if !self.filters.iter().all(|filter| filter.accept(&tx)) {
+                            stats.filtered += 1;
+                            continue;
+                        }
+                        self.sender
+                            .send(tx)
+                            .await
+                            .map_err(|err| AccountingError::TokioChannel(ErrorSource::new(err)))?;
+                    }
+                    // E.g. the input can't be read any further, rejecting the record would only hide that
+                    Err(e) if !e.is_recoverable() => return Err(e),
+                    Err(e) => {
+                        stats.rejected += 1;
+                        if matches!(
+                            e,
+                            AccountingError::OversizedRecord(..)
+                                | AccountingError::OversizedField(..)
+                        ) {
+                            stats.oversized += 1;
+                        }
+                        self.rejection_log.record(&e);
+                    }
+                    // Done with this input, on to the next one
+                    Ok(None) => break,
+                }
+            }
+        }
+
+        self.rejection_log.write_summary();
+        stats.skipped_bytes = self
+            .readers
+            .iter()
+            .flat_map(|reader| reader.skipped_ranges())
+            .map(|range| range.end - range.start)
+            .sum();
+
+        drop(self.sender);
+        let processor = processor
+            .await
+            .map_err(|e| AccountingError::HandleAwait(ErrorSource::new(e)))?;
+        Ok(ProcessingStats {
+            rejected: stats.rejected,
+            filtered: stats.filtered,
+            skipped_bytes: stats.skipped_bytes,
+            oversized: stats.oversized,
+            ..processor.stats()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use tokio::sync::mpsc::UnboundedSender;
+    use tokio::sync::RwLock;
+
+    use super::*;
+    use crate::account::Account;
+    use crate::compliance::MaxTransactionAmount;
+    use crate::csv_utils::{ResyncPolicy, TransactionReader, TransactionReaderConfig};
+    use crate::json_utils::JsonTransactionReader;
+    use crate::rejection_log::{CapturedLog, LogSampling};
+    use crate::transaction::{ClientID, TransactionData, TxID};
+    use crate::transaction_processor::TransactionProcessorBuilder;
+
+    const INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 20.0
+withdrawal, 1, 3, 15.0
+deposit, 1, 4, 500.0
+bogus, 1, 5, 1.0
+withdrawal, 2, 6, 5.0
+";
+
+    struct Fixture {
+        reader: TransactionReader,
+        ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>>,
+        accounts: Arc<RwLock<BTreeMap<ClientID, Account>>>,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "transactron_pipeline_{}_{}.csv",
+                name,
+                std::process::id()
+            ));
+            std::fs::write(&path, INPUT).unwrap();
+            Fixture {
+                reader: TransactionReader::new(path.to_string_lossy().into_owned()).unwrap(),
+                ledger: Default::default(),
+                accounts: Default::default(),
+            }
+        }
+
+        fn processor(&self) -> (TransactionProcessor, UnboundedSender<Transaction>) {
+            TransactionProcessor::new(self.ledger.clone(), self.accounts.clone())
+        }
+
+        async fn account(&self, client: ClientID) -> Option<Account> {
+            self.accounts.read().await.get(&client).cloned()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reader_and_processor() {
+        let mut fixture = Fixture::new("plain");
+        let processor = fixture.processor();
+        let stats = PipelineBuilder::new()
+            .reader(&mut fixture.reader)
+            .processor(processor)
+            .build()
+            .run()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            stats,
+            ProcessingStats {
+                rejected: 1,
+                filtered: 0,
+                applied: 4,
+                failed: 1,
+                skipped_bytes: 0,
+                oversized: 0,
+                volume_limit_exceeded: 0,
+                // Timings vary from run to run
+                latency: stats.latency,
+                slow_transactions: 0,
+                withdrawals_suspended: 0,
+                // Checked by the processor tests
+                volumes: stats.volumes,
+            }
+        );
+        assert_eq!(
+            fixture.account(1).await,
+            Some(Account::new(1, dec!(510), dec!(0), dec!(510)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bounded_processor_channel() {
+        let mut fixture = Fixture::new("bounded");
+        // The reader waits for the processor whenever the channel is full
+        let processor = TransactionProcessorBuilder::new()
+            .channel_capacity(Some(1))
+            .build(fixture.ledger.clone(), fixture.accounts.clone());
+        let stats = PipelineBuilder::new()
+            .reader(&mut fixture.reader)
+            .processor(processor)
+            .build()
+            .run()
+            .await
+            .unwrap();
+
+        assert_eq!((stats.rejected, stats.applied, stats.failed), (1, 4, 1));
+        assert_eq!(
+            fixture.account(1).await,
+            Some(Account::new(1, dec!(510), dec!(0), dec!(510)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_with_filter() {
+        let mut fixture = Fixture::new("filter");
+        let processor = fixture.processor();
+        let stats = PipelineBuilder::new()
+            .reader(&mut fixture.reader)
+            .filter(|tx: &Transaction| tx.client_id() != 2)
+            .processor(processor)
+            .build()
+            .run()
+            .await
+            .unwrap();
+
+        assert_eq!(stats.filtered, 2);
+        assert_eq!((stats.applied, stats.failed), (2, 1));
+        assert_eq!(fixture.account(2).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_with_compliance() {
+        let mut fixture = Fixture::new("compliance");
+        let processor = fixture.processor();
+        let stats = PipelineBuilder::new()
+            .reader(&mut fixture.reader)
+            .compliance(ComplianceChecker::new(vec![Box::new(
+                MaxTransactionAmount { limit: dec!(100) },
+            )]))
+            .processor(processor)
+            .build()
+            .run()
+            .await
+            .unwrap();
+
+        // The withdrawal exceeding the funds and the deposit exceeding the limit both fail
+        assert_eq!((stats.applied, stats.failed), (3, 2));
+        assert_eq!(
+            fixture.account(1).await,
+            Some(Account::new(1, dec!(10), dec!(0), dec!(10)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_with_filter_and_compliance() {
+        let mut fixture = Fixture::new("filter_compliance");
+        let processor = fixture.processor();
+        let stats = PipelineBuilder::new()
+            .processor(processor)
+            .compliance(ComplianceChecker::new(vec![Box::new(
+                MaxTransactionAmount { limit: dec!(100) },
+            )]))
+            .filter(|tx: &Transaction| !matches!(tx, Transaction::Withdrawal(_)))
+            .reader(&mut fixture.reader)
+            .build()
+            .run()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            stats,
+            ProcessingStats {
+                rejected: 1,
+                filtered: 2,
+                applied: 2,
+                failed: 1,
+                skipped_bytes: 0,
+                oversized: 0,
+                volume_limit_exceeded: 0,
+                // Timings vary from run to run
+                latency: stats.latency,
+                slow_transactions: 0,
+                withdrawals_suspended: 0,
+                // Checked by the processor tests
+                volumes: stats.volumes,
+            }
+        );
+        assert_eq!(
+            fixture.account(2).await,
+            Some(Account::new(2, dec!(20), dec!(0), dec!(20)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_source() {
+        // The same rows as INPUT, with the same result as test_reader_and_processor
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":"10.0"}
+{"type":"deposit","client":2,"tx":2,"amount":"20.0"}
+{"type":"withdrawal","client":1,"tx":3,"amount":"15.0"}
+{"type":"deposit","client":1,"tx":4,"amount":"500.0"}
+{"type":"bogus","client":1,"tx":5,"amount":"1.0"}
+{"type":"withdrawal","client":2,"tx":6,"amount":"5.0"}
+"#;
+        let mut reader =
+            JsonTransactionReader::from_reader(input.as_bytes(), Default::default()).unwrap();
+        let fixture = Fixture::new("json");
+        let processor = fixture.processor();
+        let stats = PipelineBuilder::new()
+            .reader(&mut reader)
+            .processor(processor)
+            .build()
+            .run()
+            .await
+            .unwrap();
+
+        assert_eq!((stats.rejected, stats.applied, stats.failed), (1, 4, 1));
+        assert_eq!(stats.oversized, 0);
+        assert_eq!(
+            fixture.account(1).await,
+            Some(Account::new(1, dec!(510), dec!(0), dec!(510)))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversized_rows_are_counted() {
+        let input = format!(
+            "type, client, tx, amount\ndeposit, 1, 1, 1.{}\ndeposit, 1, 2, 1.0\n",
+            "0".repeat(20_000)
+        );
+        let mut reader =
+            TransactionReader::from_reader(std::io::Cursor::new(input), Default::default())
+                .unwrap();
+        let stats = PipelineBuilder::new()
+            .reader(&mut reader)
+            .processor(TransactionProcessor::new(
+                Default::default(),
+                Default::default(),
+            ))
+            .build()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!((stats.rejected, stats.oversized, stats.applied), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_readers_share_client_id_map() {
+        let csv_reader = |input: &str, config| {
+            TransactionReader::from_reader(std::io::Cursor::new(input.to_string()), config).unwrap()
+        };
+        let mut first = csv_reader(
+            "type, client, tx, amount\ndeposit, alice, 1, 1.0\n",
+            TransactionReaderConfig {
+                client_id_map: Some(ClientIdMap::new(true)),
+                ..Default::default()
+            },
+        );
+        let mut second = csv_reader(
+            "type, client, tx, amount\ndeposit, bob, 2, 1.0\ndeposit, alice, 3, 2.0\n",
+            Default::default(),
+        );
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let stats = PipelineBuilder::new()
+            .readers(vec![&mut first, &mut second])
+            .processor(TransactionProcessor::new(
+                Default::default(),
+                accounts.clone(),
+            ))
+            .build()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(stats.applied, 3);
+        let client_id_map = second.client_id_map().unwrap();
+        let alice = client_id_map.get("alice").unwrap();
+        assert_ne!(client_id_map.get("bob"), Some(alice));
+        assert_eq!(accounts.read().await[&alice].total(), dec!(3));
+    }
+
+    #[tokio::test]
+    async fn test_overflowing_deposits_fail_without_aborting() {
+        let input = format!(
+            "type, client, tx, amount\n{}deposit, 2, 4, 1.0\n",
+            (1..=3)
+                .map(|tx| format!("deposit, 1, {}, {}\n", tx, Decimal::MAX))
+                .collect::<String>()
+        );
+        let mut reader =
+            TransactionReader::from_reader(std::io::Cursor::new(input), Default::default())
+                .unwrap();
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let stats = PipelineBuilder::new()
+            .reader(&mut reader)
+            .processor(TransactionProcessor::new(ledger.clone(), accounts.clone()))
+            .build()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!((stats.applied, stats.failed), (2, 2));
+        assert_eq!(
+            accounts.read().await.get(&1),
+            Some(&Account::new(1, Decimal::MAX, dec!(0), Decimal::MAX))
+        );
+        assert_eq!(
+            accounts.read().await.get(&2),
+            Some(&Account::new(2, dec!(1), dec!(0), dec!(1)))
+        );
+        // The failed deposits aren't recorded, so their tx ids can't be disputed
+        assert_eq!(ledger.read().await.keys().collect::<Vec<_>>(), vec![&1, &4]);
+    }
+
+    #[tokio::test]
+    async fn test_rejection_storm_is_sampled() {
+        let path = std::env::temp_dir().join(format!(
+            "transactron_pipeline_storm_{}.csv",
+            std::process::id()
+        ));
+        let input = "type, client, tx, amount\n".to_string() + &"bogus, 1, 5, 1.0\n".repeat(10_000);
+        std::fs::write(&path, input).unwrap();
+        let config = TransactionReaderConfig {
+            resync_policy: ResyncPolicy::Disabled,
+            ..Default::default()
+        };
+        let mut reader =
+            TransactionReader::with_config(path.to_string_lossy().into_owned(), config).unwrap();
+        let captured = CapturedLog::default();
+        let stats = PipelineBuilder::new()
+            .reader(&mut reader)
+            .rejection_log(
+                RejectionLog::new(LogSampling {
+                    first: 10,
+                    every: 1000,
+                })
+                .with_sink(Box::new(captured.clone())),
+            )
+            .processor(TransactionProcessor::new(
+                Default::default(),
+                Default::default(),
+            ))
+            .build()
+            .run()
+            .await
+            .unwrap();
+
+        assert_eq!(stats.rejected, 10_000);
+        let output = captured.output();
+        // The first 10, then occurrences 1010, 2010, ..., 9010, then the summary
+        assert_eq!(output.lines().count(), 10 + 9 + 1);
+        assert!(output.contains("occurrence 9010)"));
+        assert!(output.ends_with("warning: 10000 records rejected with unknown_transaction_type\n"));
+    }
+    // Yields a deposit, then fails the way a file does when the disk goes away
+    struct BrokenSource {
+        reads: u32,
+    }
+
+    impl TransactionSource for BrokenSource {
+        fn poll_transaction(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<Option<Transaction>, AccountingError>> {
+            self.reads += 1;
+            Poll::Ready(match self.reads {
+                1 => Ok(Some(Transaction::deposit(1, 1, dec!(1)))),
+                2 => {
+                    let e = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "disk gone");
+                    Err(AccountingError::Input(
+                        e.to_string(),
+                        crate::error::source(e),
+                    ))
+                }
+                _ => Ok(Some(Transaction::deposit(1, self.reads, dec!(1)))),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_io_error_aborts_run() {
+        let mut reader = BrokenSource { reads: 0 };
+        let error = PipelineBuilder::new()
+            .reader(&mut reader)
+            .processor(TransactionProcessor::new(
+                Default::default(),
+                Default::default(),
+            ))
+            .build()
+            .run()
+            .await
+            .unwrap_err();
+        assert!(error.is_io());
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(
+            source.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+        // Nothing after the error is read
+        assert_eq!(reader.reads, 2);
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::AccountingError;
+
+// Logs the first `first` rejections of every error code, and after that only every `every`th one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogSampling {
+    pub first: u64,
+    pub every: u64,
+}
+
+impl Default for LogSampling {
+    fn default() -> Self {
+        LogSampling {
+            first: 10,
+            every: 1000,
+        }
+    }
+}
+
+// Warnings for rejected records. A completely broken file can have millions of bad rows, so the
+// log is sampled per error code, while the counts stay exact for the summary
+pub struct RejectionLog {
+    sampling: LogSampling,
+    counts: BTreeMap<&'static str, u64>,
+    sink: Box<dyn Write + Send>,
+}
+
+impl RejectionLog {
+    pub fn new(sampling: LogSampling) -> Self {
+        RejectionLog {
+            sampling,
+            counts: BTreeMap::new(),
+            sink: Box::new(std::io::stderr()),
+        }
+    }
+
+    // Writes the log somewhere else than stderr
+    pub fn with_sink(mut self, sink: Box<dyn Write + Send>) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    pub fn record(&mut self, err: &AccountingError) {
+        let count = self.counts.entry(err.code()).or_default();
+        *count += 1;
+        let n = *count;
+        if n <= self.sampling.first
+            || (n - self.sampling.first).is_multiple_of(self.sampling.every.max(1))
+        {
+            // A failing log must not fail the run
+            let _ = writeln!(
+                self.sink,
+                "warning: rejected record ({}, occurrence {}): {}",
+                err.code(),
+                n,
+                err
+            );
+        }
+    }
+
+    // Number of rejections per error code
+    pub fn counts(&self) -> &BTreeMap<&'static str, u64> {
+        &self.counts
+    }
+
+    // One line per error code with the exact number of rejections
+    pub fn write_summary(&mut self) {
+        for (code, count) in &self.counts {
+            let _ = writeln!(
+                self.sink,
+                "warning: {} records rejected with {}",
+                count, code
+            );
+        }
+    }
+}
+
+impl Default for RejectionLog {
+    fn default() -> Self {
+        RejectionLog::new(LogSampling::default())
+    }
+}
+
+// Collects everything written to the log, for tests
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct CapturedLog(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl CapturedLog {
+    pub fn output(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for CapturedLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sampling_per_code() {
+        let captured = CapturedLog::default();
+        let mut log = RejectionLog::new(LogSampling { first: 3, every: 4 })
+            .with_sink(Box::new(captured.clone()));
+        for _ in 0..12 {
+            log.record(&AccountingError::MalformedTransaction);
+        }
+        log.record(&AccountingError::Withdrawal);
+        log.write_summary();
+
+        let output = captured.output();
+        let occurrences: Vec<&str> = output
+            .lines()
+            .filter(|line| line.contains("(malformed_transaction, occurrence"))
+            .map(|line| line.split(['(', ')']).nth(1).unwrap())
+            .collect();
+        assert_eq!(
+            occurrences,
+            vec![
+                "malformed_transaction, occurrence 1",
+                "malformed_transaction, occurrence 2",
+                "malformed_transaction, occurrence 3",
+                "malformed_transaction, occurrence 7",
+                "malformed_transaction, occurrence 11",
+            ]
+        );
+        // Every code gets its own first occurrences and summary line
+        assert!(output.contains("(withdrawal, occurrence 1)"));
+        assert!(output.contains("warning: 12 records rejected with malformed_transaction\n"));
+        assert!(output.contains("warning: 1 records rejected with withdrawal\n"));
+        assert_eq!(
+            log.counts(),
+            &BTreeMap::from([("malformed_transaction", 12), ("withdrawal", 1)])
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::ops::Range;
+
+use crate::transaction::{ClientID, Transaction};
+use crate::AccountingError;
+
+// Sequence numbers missing from a client's stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceGap {
+    pub client: ClientID,
+    pub missing: Range<u64>,
+}
+
+// Releases transactions in per-client sequence order, when the input interleaves several streams
+// whose order differs from the file order. Sequence numbers of a client are consecutive, and start
+// at the lowest one seen for that client. At most `window` transactions are held back; when the
+// buffer is full the client of the oldest buffered transaction skips ahead to its lowest buffered
+// sequence number and the skipped numbers are reported as a gap. In strict mode every later
+// transaction of that client is rejected instead
+pub struct ReorderBuffer {
+    window: usize,
+    strict: bool,
+    pending: BTreeMap<ClientID, BTreeMap<u64, (u64, Transaction)>>,
+    // Buffered transactions by arrival, to find the oldest one
+    arrivals: BTreeMap<u64, ClientID>,
+    next_arrival: u64,
+    next_sequence: BTreeMap<ClientID, u64>,
+    broken: BTreeSet<ClientID>,
+    ready: VecDeque<Result<Transaction, AccountingError>>,
+    gaps: Vec<SequenceGap>,
+    peak: usize,
+}
+
+impl ReorderBuffer {
+    pub fn new(window: usize, strict: bool) -> Self {
+        ReorderBuffer {
+            window,
+            strict,
+            pending: BTreeMap::new(),
+            arrivals: BTreeMap::new(),
+            next_arrival: 0,
+            next_sequence: BTreeMap::new(),
+            broken: BTreeSet::new(),
+            ready: VecDeque::new(),
+            gaps: Vec::new(),
+            peak: 0,
+        }
+    }
+
+    pub fn push(&mut self, sequence: u64, tx: Transaction) {
+        let client = tx.client_id();
+        if self.broken.contains(&client) {
+            self.ready.push_back(Err(AccountingError::SequenceGap));
+            return;
+        }
+        let passed = self
+            .next_sequence
+            .get(&client)
+            .is_some_and(|next| sequence < *next);
+        let pending = self.pending.entry(client).or_default();
+        if passed || pending.contains_key(&sequence) {
+            self.ready.push_back(Err(AccountingError::StaleSequence));
+            return;
+        }
+        pending.insert(sequence, (self.next_arrival, tx));
+        self.arrivals.insert(self.next_arrival, client);
+        self.next_arrival += 1;
+        self.peak = self.peak.max(self.arrivals.len());
+
+        self.release(client);
+        if self.arrivals.len() > self.window {
+            self.skip_ahead();
+        }
+    }
+
+    // Releases everything still buffered, called at the end of the input
+    pub fn finish(&mut self) {
+        while !self.arrivals.is_empty() {
+            self.skip_ahead();
+        }
+    }
+
+    pub fn pop_ready(&mut self) -> Option<Result<Transaction, AccountingError>> {
+        self.ready.pop_front()
+    }
+
+    pub fn gaps(&self) -> &[SequenceGap] {
+        &self.gaps
+    }
+
+    // The most transactions buffered at once
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+
+    // Moves the client's transactions that are next in sequence to the ready queue
+    fn release(&mut self, client: ClientID) {
+        let Some(next) = self.next_sequence.get_mut(&client) else {
+            return;
+        };
+        let pending = self.pending.entry(client).or_default();
+        while let Some((arrival, tx)) = pending.remove(next) {
+            self.arrivals.remove(&arrival);
+            self.ready.push_back(Ok(tx));
+            *next += 1;
+        }
+    }
+
+    // Gives up waiting for the client of the oldest buffered transaction
+    fn skip_ahead(&mut self) {
+        let Some((_, client)) = self.arrivals.first_key_value() else {
+            return;
+        };
+        let client = *client;
+        let pending = self.pending.entry(client).or_default();
+        let Some(lowest) = pending.keys().next().copied() else {
+            return;
+        };
+        // Without a next sequence number this is the client's first release, its sequence starts here
+        if let Some(next) = self.next_sequence.get(&client) {
+            self.gaps.push(SequenceGap {
+                client,
+                missing: *next..lowest,
+            });
+            if self.strict {
+                for (arrival, _) in std::mem::take(pending).into_values() {
+                    self.arrivals.remove(&arrival);
+                    self.ready.push_back(Err(AccountingError::SequenceGap));
+                }
+                self.broken.insert(client);
+                return;
+            }
+        }
+        self.next_sequence.insert(client, lowest);
+        self.release(client);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::transaction::TxID;
+
+    fn deposit(client_id: ClientID, tx_id: TxID) -> Transaction {
+        Transaction::deposit(client_id, tx_id, dec!(1))
+    }
+
+    fn drain(buffer: &mut ReorderBuffer) -> Vec<Result<TxID, String>> {
+        std::iter::from_fn(|| buffer.pop_ready())
+            .map(|result| match result {
+                Ok(Transaction::Deposit(tx)) => Ok(tx.tx_id),
+                Ok(other) => panic!("unexpected transaction {:?}", other),
+                Err(e) => Err(e.code().to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lenient_gap() {
+        let mut buffer = ReorderBuffer::new(2, false);
+        // Client 1 starts at 5, 7 arrives before 6 and 9 never does
+        for (sequence, tx) in [(5, deposit(1, 1)), (7, deposit(1, 3)), (6, deposit(1, 2))] {
+            buffer.push(sequence, tx);
+        }
+        buffer.push(10, deposit(1, 4));
+        buffer.push(8, deposit(1, 5));
+        buffer.push(11, deposit(1, 6));
+        buffer.finish();
+        assert_eq!(
+            drain(&mut buffer),
+            vec![Ok(1), Ok(2), Ok(3), Ok(5), Ok(4), Ok(6)]
+        );
+        assert_eq!(
+            buffer.gaps(),
+            &[SequenceGap {
+                client: 1,
+                missing: 9..10
+            }]
+        );
+        assert_eq!(buffer.peak(), 3);
+    }
+
+    #[test]
+    fn test_strict_gap() {
+        let mut buffer = ReorderBuffer::new(1, true);
+        buffer.push(1, deposit(1, 1));
+        buffer.push(2, deposit(1, 2));
+        buffer.push(2, deposit(1, 3));
+        buffer.push(4, deposit(1, 4));
+        buffer.push(5, deposit(1, 5));
+        buffer.push(3, deposit(1, 6));
+        buffer.finish();
+        assert_eq!(
+            drain(&mut buffer),
+            vec![
+                Ok(1),
+                Ok(2),
+                Err("stale_sequence".to_string()),
+                Err("sequence_gap".to_string()),
+                Err("sequence_gap".to_string()),
+                Err("sequence_gap".to_string()),
+            ]
+        );
+        assert_eq!(buffer.gaps().len(), 1);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::transaction::{Amount, MAX_AMOUNT_SCALE};
+
+// Every feature that needs to round an amount must go through RoundingConfig::round so that
+// all call sites agree on scale and strategy, otherwise we end up with penny drift between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingStrategy {
+    // Round half to even
+    #[default]
+    Bankers,
+    HalfUp,
+    HalfDown,
+    TowardZero,
+    AwayFromZero,
+}
+
+impl From<RoundingStrategy> for rust_decimal::RoundingStrategy {
+    fn from(strategy: RoundingStrategy) -> Self {
+        match strategy {
+            RoundingStrategy::Bankers => rust_decimal::RoundingStrategy::MidpointNearestEven,
+            RoundingStrategy::HalfUp => rust_decimal::RoundingStrategy::MidpointAwayFromZero,
+            RoundingStrategy::HalfDown => rust_decimal::RoundingStrategy::MidpointTowardZero,
+            RoundingStrategy::TowardZero => rust_decimal::RoundingStrategy::ToZero,
+            RoundingStrategy::AwayFromZero => rust_decimal::RoundingStrategy::AwayFromZero,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoundingConfig {
+    pub scale: u32,
+    pub strategy: RoundingStrategy,
+}
+
+// The rounding of every amount the ledger stores, parses or prints: formatting, interest, balances, the
+// state hash and inputs read with PrecisionPolicy::Round all read this one config
+pub const LEDGER_ROUNDING: RoundingConfig =
+    RoundingConfig::new(MAX_AMOUNT_SCALE, RoundingStrategy::Bankers);
+
+impl Default for RoundingConfig {
+    fn default() -> Self {
+        LEDGER_ROUNDING
+    }
+}
+
+impl RoundingConfig {
+    pub const fn new(scale: u32, strategy: RoundingStrategy) -> Self {
+        RoundingConfig { scale, strategy }
+    }
+
+    pub fn round(&self, amount: Amount) -> Amount {
+        amount.round_dp_with_strategy(self.scale, self.strategy.into())
+    }
+
+    // Rounds and then pads to exactly `scale` decimal places, so equal amounts are stored bit-identically
+    pub fn canonical(&self, amount: Amount) -> Amount {
+        let mut amount = self.round(amount);
+        amount.rescale(self.scale);
+        amount
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::Account;
+    use crate::transaction::format_amount;
+    use rust_decimal_macros::dec;
+
+    type BoundaryValue = (Amount, u32, Amount, Amount, Amount, Amount, Amount);
+
+    // (input, scale, bankers, half up, half down, toward zero, away from zero)
+    const BOUNDARY_VALUES: [BoundaryValue; 9] = [
+        (
+            dec!(0.125),
+            2,
+            dec!(0.12),
+            dec!(0.13),
+            dec!(0.12),
+            dec!(0.12),
+            dec!(0.13),
+        ),
+        (
+            dec!(0.135),
+            2,
+            dec!(0.14),
+            dec!(0.14),
+            dec!(0.13),
+            dec!(0.13),
+            dec!(0.14),
+        ),
+        (
+            dec!(-0.125),
+            2,
+            dec!(-0.12),
+            dec!(-0.13),
+            dec!(-0.12),
+            dec!(-0.12),
+            dec!(-0.13),
+        ),
+        (
+            dec!(1.00005),
+            4,
+            dec!(1.0000),
+            dec!(1.0001),
+            dec!(1.0000),
+            dec!(1.0000),
+            dec!(1.0001),
+        ),
+        (
+            dec!(1.00015),
+            4,
+            dec!(1.0002),
+            dec!(1.0002),
+            dec!(1.0001),
+            dec!(1.0001),
+            dec!(1.0002),
+        ),
+        (
+            dec!(2.00025),
+            4,
+            dec!(2.0002),
+            dec!(2.0003),
+            dec!(2.0002),
+            dec!(2.0002),
+            dec!(2.0003),
+        ),
+        (
+            dec!(9.99995),
+            4,
+            dec!(10.0000),
+            dec!(10.0000),
+            dec!(9.9999),
+            dec!(9.9999),
+            dec!(10.0000),
+        ),
+        (
+            dec!(-1.00005),
+            4,
+            dec!(-1.0000),
+            dec!(-1.0001),
+            dec!(-1.0000),
+            dec!(-1.0000),
+            dec!(-1.0001),
+        ),
+        (dec!(2.5), 0, dec!(2), dec!(3), dec!(2), dec!(2), dec!(3)),
+    ];
+
+    fn expected_outputs(value: BoundaryValue) -> [(RoundingStrategy, Amount); 5] {
+        let (_, _, bankers, half_up, half_down, toward_zero, away_from_zero) = value;
+        [
+            (RoundingStrategy::Bankers, bankers),
+            (RoundingStrategy::HalfUp, half_up),
+            (RoundingStrategy::HalfDown, half_down),
+            (RoundingStrategy::TowardZero, toward_zero),
+            (RoundingStrategy::AwayFromZero, away_from_zero),
+        ]
+    }
+
+    #[test]
+    fn test_rounding_boundary_values() {
+        for value in BOUNDARY_VALUES {
+            let (input, scale, ..) = value;
+            for (strategy, output) in expected_outputs(value) {
+                assert_eq!(
+                    RoundingConfig::new(scale, strategy).round(input),
+                    output,
+                    "{} rounded to {} places with {:?}",
+                    input,
+                    scale,
+                    strategy
+                );
+            }
+        }
+    }
+
+    // The rows at the ledger's scale, run through every place that rounds an amount
+    #[test]
+    fn test_call_sites_round_boundary_values() {
+        let rows = BOUNDARY_VALUES
+            .into_iter()
+            .filter(|(_, scale, ..)| *scale == LEDGER_ROUNDING.scale);
+        for value in rows {
+            let input = value.0;
+            let (_, expected) = expected_outputs(value)
+                .into_iter()
+                .find(|(strategy, _)| *strategy == LEDGER_ROUNDING.strategy)
+                .unwrap();
+            assert_eq!(format_amount(input), expected.to_string(), "{}", input);
+            // Deposits and balances can't be negative
+            if input.is_sign_negative() {
+                continue;
+            }
+
+            #[cfg(feature = "serde")]
+            {
+                use crate::csv_utils::{
+                    PrecisionPolicy, TransactionReader, TransactionReaderConfig,
+                };
+                use crate::transaction::Transaction;
+
+                let config = TransactionReaderConfig {
+                    precision_policy: PrecisionPolicy::Round,
+                    ..Default::default()
+                };
+                let input = format!("type,client,tx,amount\ndeposit,1,1,{}\n", input);
+                let mut reader =
+                    TransactionReader::from_reader(std::io::Cursor::new(input), config).unwrap();
+                match reader.next() {
+                    Some(Ok(Transaction::Deposit(tx))) => assert_eq!(tx.amount(), Some(expected)),
+                    other => panic!("expected a deposit, got {:?}", other),
+                }
+            }
+
+            // Every stored balance is canonical
+            let mut account = Account::default();
+            account.deposit(input).unwrap();
+            assert_eq!(account.available(), expected);
+            assert_eq!(account.available().scale(), LEDGER_ROUNDING.scale);
+            // A year's interest on 365 at the input as rate is the input
+            let mut account = Account::default();
+            account.deposit(dec!(365)).unwrap();
+            assert_eq!(account.apply_interest(input, 1).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_default_is_bankers_rounding_to_four_places() {
+        let config = RoundingConfig::default();
+        assert_eq!(config.round(dec!(1.00005)), dec!(1.0000));
+        assert_eq!(config.round(dec!(1.00015)), dec!(1.0002));
+    }
+
+    // Rounding anywhere but through RoundingConfig::round would let call sites drift apart
+    #[test]
+    fn test_no_rounding_outside_helper() {
+        let src = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        for entry in std::fs::read_dir(src).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap() == "rounding.rs" {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            for call in [
+                ".round()",
+                "round_dp(",
+                "round_dp_with_strategy(",
+                "rescale(",
+            ] {
+                let offending = source.lines().filter(|line| line.contains(call)).count();
+                assert_eq!(
+                    offending,
+                    0,
+                    "{} calls {} directly, use RoundingConfig::round instead",
+                    path.display(),
+                    call
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "serde")]
+use std::io::Write;
+
+use crate::account::Account;
+use crate::transaction::ClientID;
+#[cfg(feature = "serde")]
+use crate::transaction::{serialize_optional_amount, Amount};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountDiff {
+    pub before: Account,
+    pub after: Account,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RunDiff {
+    pub new_accounts: Vec<Account>,
+    pub removed_accounts: Vec<ClientID>,
+    pub changed_accounts: Vec<AccountDiff>,
+}
+
+// Compares the account state of two processing runs, e.g. before and after reprocessing a corrected input file.
+// Both maps are ordered by client id, so they are walked side by side in a single pass
+pub fn diff_runs(
+    before: BTreeMap<ClientID, Account>,
+    after: BTreeMap<ClientID, Account>,
+) -> RunDiff {
+    let mut diff = RunDiff::default();
+    let mut before = before.into_iter().peekable();
+    let mut after = after.into_iter().peekable();
+
+    loop {
+        match (before.peek(), after.peek()) {
+            (Some((old_id, _)), Some((new_id, _))) if old_id == new_id => {
+                let (_, old) = before.next().unwrap();
+                let (_, new) = after.next().unwrap();
+                if old != new {
+                    diff.changed_accounts.push(AccountDiff {
+                        before: old,
+                        after: new,
+                    });
+                }
+            }
+            (Some((old_id, _)), Some((new_id, _))) if old_id < new_id => {
+                let (client, _) = before.next().unwrap();
+                diff.removed_accounts.push(client);
+            }
+            (Some(_), None) => {
+                let (client, _) = before.next().unwrap();
+                diff.removed_accounts.push(client);
+            }
+            (_, Some(_)) => {
+                let (_, new) = after.next().unwrap();
+                diff.new_accounts.push(new);
+            }
+            (None, None) => break,
+        }
+    }
+    diff
+}
+
+impl RunDiff {
+    pub fn is_empty(&self) -> bool {
+        self.new_accounts.is_empty()
+            && self.removed_accounts.is_empty()
+            && self.changed_accounts.is_empty()
+    }
+
+    // Writes one row per affected account, ordered by client id. Columns that don't apply to the
+    // kind of change (e.g. the "before" values of a new account) are left empty
+    #[cfg(feature = "serde")]
+    pub fn to_csv<W: Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut records = Vec::with_capacity(
+            self.new_accounts.len() + self.removed_accounts.len() + self.changed_accounts.len(),
+        );
+        for account in &self.new_accounts {
+            records.push(DiffRecord::new("new", account.client, None, Some(account)));
+        }
+        for client in &self.removed_accounts {
+            records.push(DiffRecord::new("removed", *client, None, None));
+        }
+        for diff in &self.changed_accounts {
+            records.push(DiffRecord::new(
+                "changed",
+                diff.after.client,
+                Some(&diff.before),
+                Some(&diff.after),
+            ));
+        }
+        records.sort_by_key(|r| r.client);
+
+        let mut writer = csv::Writer::from_writer(writer);
+        for record in records {
+            writer.serialize(record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct DiffRecord {
+    change: &'static str,
+    client: ClientID,
+    #[serde(serialize_with = "serialize_optional_amount")]
+    available_before: Option<Amount>,
+    #[serde(serialize_with = "serialize_optional_amount")]
+    held_before: Option<Amount>,
+    #[serde(serialize_with = "serialize_optional_amount")]
+    total_before: Option<Amount>,
+    locked_before: Option<bool>,
+    #[serde(serialize_with = "serialize_optional_amount")]
+    available_after: Option<Amount>,
+    #[serde(serialize_with = "serialize_optional_amount")]
+    held_after: Option<Amount>,
+    #[serde(serialize_with = "serialize_optional_amount")]
+    total_after: Option<Amount>,
+    locked_after: Option<bool>,
+}
+
+#[cfg(feature = "serde")]
+impl DiffRecord {
+    fn new(
+        change: &'static str,
+        client: ClientID,
+        before: Option<&Account>,
+        after: Option<&Account>,
+    ) -> Self {
+        DiffRecord {
+            change,
+            client,
+            available_before: before.map(|a| a.available()),
+            held_before: before.map(|a| a.held()),
+            total_before: before.map(|a| a.total()),
+            locked_before: before.map(|a| a.locked),
+            available_after: after.map(|a| a.available()),
+            held_after: after.map(|a| a.held()),
+            total_after: after.map(|a| a.total()),
+            locked_after: after.map(|a| a.locked),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_diff_runs() {
+        let before = BTreeMap::from([
+            (1, Account::new(1, dec!(1.5), dec!(0), dec!(1.5))),
+            (2, Account::new(2, dec!(2), dec!(0), dec!(2))),
+            (3, Account::new(3, dec!(3), dec!(0), dec!(3))),
+        ]);
+        let after = BTreeMap::from([
+            (1, Account::new(1, dec!(1.5), dec!(0), dec!(1.5))),
+            (3, Account::new(3, dec!(1), dec!(2), dec!(3))),
+            (4, Account::new(4, dec!(4), dec!(0), dec!(4))),
+        ]);
+
+        let diff = diff_runs(before, after);
+        assert_eq!(
+            diff,
+            RunDiff {
+                new_accounts: vec![Account::new(4, dec!(4), dec!(0), dec!(4))],
+                removed_accounts: vec![2],
+                changed_accounts: vec![AccountDiff {
+                    before: Account::new(3, dec!(3), dec!(0), dec!(3)),
+                    after: Account::new(3, dec!(1), dec!(2), dec!(3)),
+                }],
+            }
+        );
+
+        #[cfg(feature = "serde")]
+        {
+            let mut buffer = Vec::new();
+            diff.to_csv(&mut buffer).unwrap();
+            assert_eq!(
+                String::from_utf8(buffer).unwrap(),
+                "change,client,available_before,held_before,total_before,locked_before,available_after,held_after,total_after,locked_after\n\
+                 removed,2,,,,,,,,\n\
+                 changed,3,3.0000,0.0000,3.0000,false,1.0000,2.0000,3.0000,false\n\
+                 new,4,,,,,4.0000,0.0000,4.0000,false\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_diff_identical_runs() {
+        let accounts = BTreeMap::from([(1, Account::new(1, dec!(1.5), dec!(0), dec!(1.5)))]);
+        assert!(diff_runs(accounts.clone(), accounts).is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+
+use crate::account::Account;
+use crate::compliance::ComplianceChecker;
+use crate::transaction::{ClientID, Transaction, TransactionData, TxID};
+use crate::transaction_processor::{ProcessingStats, TransactionProcessor};
+
+type Accounts = Arc<RwLock<BTreeMap<ClientID, Account>>>;
+
+// Spreads transactions over several processors by client id (client % shards), so distinct clients
+// are processed in parallel while the transactions of a client keep their order. Every shard has
+// accounts of its own, which are merged back into the shared accounts once the channel is closed.
+// The ledger stays shared, so a transaction id can still only be used once. When different clients
+// reuse a tx id, the shard that used it before applies everything it was sent before the next use is
+// routed, so the first use in input order gets the id as with a single processor
+pub struct ShardedProcessor {
+    shards: Vec<(TransactionProcessor, UnboundedSender<Transaction>)>,
+    shard_accounts: Vec<Accounts>,
+    accounts: Accounts,
+    transaction_recv: UnboundedReceiver<Transaction>,
+    stats: ProcessingStats,
+}
+
+impl ShardedProcessor {
+    // Every shard is created with TransactionProcessor::new and passed through configure, which sets
+    // the same options on all of them, e.g. |processor| processor.with_two_phase_withdrawals(true)
+    pub fn new<F>(
+        transactions: Arc<RwLock<BTreeMap<TxID, TransactionData>>>,
+        accounts: Accounts,
+        shards: usize,
+        mut configure: F,
+    ) -> (Self, UnboundedSender<Transaction>)
+    where
+        F: FnMut(TransactionProcessor) -> TransactionProcessor,
+    {
+        assert!(shards > 0, "at least one shard is needed");
+        let shard_accounts: Vec<Accounts> = (0..shards).map(|_| Default::default()).collect();
+        let shards = shard_accounts
+            .iter()
+            .map(|shard_accounts| {
+                let (processor, sender) =
+                    TransactionProcessor::new(transactions.clone(), shard_accounts.clone());
+                (configure(processor), sender)
+            })
+            .collect();
+        let (sender, receiver) = unbounded_channel();
+        (
+            ShardedProcessor {
+                shards,
+                shard_accounts,
+                accounts,
+                transaction_recv: receiver,
+                stats: ProcessingStats::default(),
+            },
+            sender,
+        )
+    }
+
+    // Runs the same compliance rules in every shard, rules keeping per-client state share it
+    pub fn with_compliance(mut self, compliance: ComplianceChecker) -> Self {
+        self.shards = self
+            .shards
+            .into_iter()
+            .map(|(processor, sender)| (processor.with_compliance(compliance.clone()), sender))
+            .collect();
+        self
+    }
+
+    fn shard_of(&self, client_id: ClientID) -> usize {
+        client_id as usize % self.shard_accounts.len()
+    }
+
+    pub async fn process(mut self) -> Self {
+        // Accounts from before the run move to their shard for the time being
+        for (client_id, account) in std::mem::take(&mut *self.accounts.write().await) {
+            self.shard_accounts[self.shard_of(client_id)]
+                .write()
+                .await
+                .insert(client_id, account);
+        }
+
+        let mut running: Vec<RunningShard> = self
+            .shards
+            .drain(..)
+            .map(|(processor, sender)| RunningShard::spawn(processor, sender))
+            .collect();
+        // The shard each tx id of a deposit or withdrawal was last sent to
+        let mut entry_shards: HashMap<TxID, usize> = HashMap::new();
+        // loop until sender is dropped
+        while let Some(tx) = self.transaction_recv.recv().await {
+            let shard = self.shard_of(tx.client_id());
+            if let (true, Some(tx_id)) = (tx.records_entry(), tx.tx_id()) {
+                match entry_shards.insert(tx_id, shard) {
+                    // Whether the tx id is taken depends on the earlier use, which has to be applied first
+                    Some(previous) if previous != shard => {
+                        let mut processor = running.remove(previous).finish().await;
+                        let sender = processor.reopen();
+                        running.insert(previous, RunningShard::spawn(processor, sender));
+                    }
+                    _ => (),
+                }
+            }
+            // The shard runs until its sender is dropped, so sending can't fail
+            let _ = running[shard].sender.send(tx);
+        }
+
+        for shard in running {
+            self.stats += shard.finish().await.stats();
+        }
+        let mut accounts = self.accounts.write().await;
+        for shard_accounts in &self.shard_accounts {
+            accounts.append(&mut *shard_accounts.write().await);
+        }
+        drop(accounts);
+        self
+    }
+
+    pub fn stats(&self) -> ProcessingStats {
+        self.stats
+    }
+}
+
+// A shard processing the transactions sent to it on a task of its own
+struct RunningShard {
+    sender: UnboundedSender<Transaction>,
+    handle: JoinHandle<TransactionProcessor>,
+}
+
+impl RunningShard {
+    fn spawn(processor: TransactionProcessor, sender: UnboundedSender<Transaction>) -> Self {
+        RunningShard {
+            sender,
+            handle: tokio::spawn(async move { processor.process().await }),
+        }
+    }
+
+    // Returns the processor once it has applied everything sent to it
+    async fn finish(self) -> TransactionProcessor {
+        drop(self.sender);
+        self.handle.await.expect("a shard processor panicked")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    // Deposits, withdrawals and disputes for a number of clients, interleaved. Some of the withdrawals
+    // and disputes fail, e.g. for insufficient funds
+    fn transactions() -> Vec<Transaction> {
+        let mut transactions = Vec::new();
+        for round in 0..20u32 {
+            for client in 1..=10u16 {
+                let tx_id = round * 100 + client as u32;
+                let amount = Decimal::from(client);
+                // The first deposit of the current group of five rounds
+                let first = tx_id - round % 5 * 100;
+                transactions.push(match round % 5 {
+                    0 | 1 => Transaction::deposit(client, tx_id, dec!(10) + amount),
+                    2 => Transaction::withdrawal(client, tx_id, dec!(7) * amount),
+                    // Odd clients dispute their second deposit, so resolving the first one fails
+                    3 => Transaction::dispute(client, first + client as u32 % 2 * 100),
+                    _ if client % 3 == 0 => Transaction::chargeback(client, first),
+                    _ => Transaction::resolve(client, first),
+                });
+            }
+        }
+        // Different clients reusing tx ids. A single processor lets the first use in input order have
+        // the id, unless it failed to apply
+        for tx_id in 5000..5100u32 {
+            let (first, second) = (tx_id as u16 % 10 + 1, (tx_id as u16 + 3) % 10 + 1);
+            if tx_id % 3 == 0 {
+                transactions.push(Transaction::withdrawal(first, tx_id, dec!(1000)));
+            } else {
+                transactions.push(Transaction::deposit(first, tx_id, dec!(1)));
+            }
+            transactions.push(Transaction::deposit(second, tx_id, dec!(2)));
+            transactions.push(Transaction::dispute(second, tx_id));
+        }
+        transactions
+    }
+
+    async fn run(
+        shards: usize,
+        accounts: BTreeMap<ClientID, Account>,
+    ) -> (BTreeMap<ClientID, Account>, ProcessingStats) {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts = Arc::new(RwLock::new(accounts));
+        let (processor, sender) =
+            ShardedProcessor::new(ledger.clone(), accounts.clone(), shards, |processor| {
+                processor.with_two_phase_withdrawals(false)
+            });
+        let processor = tokio::spawn(async move { processor.process().await });
+        for tx in transactions() {
+            sender.send(tx).unwrap();
+        }
+        drop(sender);
+        let stats = processor.await.unwrap().stats();
+        let accounts = accounts.read().await.clone();
+        for account in accounts.values() {
+            account.check_invariant().unwrap();
+        }
+        (accounts, stats)
+    }
+
+    #[tokio::test]
+    async fn test_shards_match_single_processor() {
+        let expected = crate::process_all(transactions()).await;
+        assert_eq!(expected.len(), 10);
+        assert!(expected.values().any(|account| account.locked));
+        let (accounts, expected_stats) = run(1, BTreeMap::new()).await;
+        assert_eq!(accounts, expected);
+        assert!(expected_stats.failed > 0);
+
+        let (accounts, stats) = run(4, BTreeMap::new()).await;
+        assert_eq!(accounts, expected);
+        assert_eq!(
+            (stats.applied, stats.failed, stats.latency.count()),
+            (expected_stats.applied, expected_stats.failed, 500)
+        );
+        assert_eq!(stats.volumes, expected_stats.volumes);
+    }
+
+    #[tokio::test]
+    async fn test_existing_accounts_are_kept() {
+        let existing = BTreeMap::from([
+            (3, Account::new(3, dec!(5), dec!(0), dec!(5))),
+            (42, Account::new(42, dec!(1), dec!(0), dec!(1))),
+        ]);
+        let (expected, _) = run(1, existing.clone()).await;
+        let (accounts, _) = run(4, existing).await;
+        assert_eq!(accounts, expected);
+        assert_eq!(accounts[&42], Account::new(42, dec!(1), dec!(0), dec!(1)));
+    }
+}
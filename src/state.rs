@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+
+use sha2::{Digest, Sha256};
+
+use crate::account::Account;
+use crate::rounding::LEDGER_ROUNDING;
+use crate::transaction::{Amount, ClientID, CurrencyCode, TransactionData, TxID};
+
+// A read-only view of the full ledger state, i.e. the accounts and the transactions recorded so far
+pub struct LedgerState<'a> {
+    pub accounts: &'a BTreeMap<ClientID, Account>,
+    pub transactions: &'a BTreeMap<TxID, TransactionData>,
+}
+
+// A balance whose held funds don't add up to the amounts of its transactions under dispute
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeldMismatch {
+    pub client: ClientID,
+    pub currency: Option<CurrencyCode>,
+    // Sum of the amounts under dispute
+    pub expected: Amount,
+    pub actual: Amount,
+    // The transactions under dispute, empty if funds are held without any dispute
+    pub tx_ids: Vec<TxID>,
+}
+
+impl std::fmt::Display for HeldMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "client {} holds {}", self.client, self.actual)?;
+        if let Some(currency) = self.currency {
+            write!(f, " {}", currency)?;
+        }
+        write!(f, " but has {} under dispute", self.expected)?;
+        if !self.tx_ids.is_empty() {
+            let tx_ids: Vec<String> = self.tx_ids.iter().map(|tx_id| tx_id.to_string()).collect();
+            write!(f, " (tx {})", tx_ids.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> LedgerState<'a> {
+    pub fn new(
+        accounts: &'a BTreeMap<ClientID, Account>,
+        transactions: &'a BTreeMap<TxID, TransactionData>,
+    ) -> Self {
+        LedgerState {
+            accounts,
+            transactions,
+        }
+    }
+
+    // Hashes a canonical encoding of the state, so two runs that end in the same logical state produce
+    // the same hash regardless of how they got there (e.g. 1.50 and 1.5 are the same amount).
+    // Amounts are hashed in the fixed-scale form from LEDGER_ROUNDING.canonical
+    pub fn state_hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"accounts");
+        for (client, account) in self.accounts {
+            hasher.update(client.to_be_bytes());
+            hash_amount(&mut hasher, account.available());
+            hash_amount(&mut hasher, account.held());
+            hash_amount(&mut hasher, account.total());
+            hash_amount(&mut hasher, account.pending_out());
+            hasher.update([account.locked as u8]);
+        }
+        hasher.update(b"transactions");
+        for (tx_id, tx) in self.transactions {
+            hasher.update(tx_id.to_be_bytes());
+            hasher.update(tx.client_id.to_be_bytes());
+            match tx.amount {
+                Some(amount) => hash_amount(&mut hasher, amount),
+                None => hasher.update([0u8]),
+            }
+            hasher.update([
+                tx.dispute_state as u8,
+                tx.pending_settlement as u8,
+                tx.kind as u8,
+            ]);
+            if let Some(currency) = tx.currency {
+                hasher.update(currency.0);
+            }
+            hasher.update([0u8]);
+        }
+        hasher.finalize().into()
+    }
+
+    // Checks that every balance holds exactly the amounts of its transactions under dispute.
+    // Takes a single pass over the ledger, meant to run once at the end of a run
+    pub fn reconcile_held(&self) -> Vec<HeldMismatch> {
+        let mut disputed: BTreeMap<(ClientID, Option<CurrencyCode>), (Amount, Vec<TxID>)> =
+            BTreeMap::new();
+        for (tx_id, tx) in self.transactions {
+            if let (true, Some(amount)) = (tx.under_dispute(), tx.amount) {
+                let (expected, tx_ids) = disputed.entry((tx.client_id, tx.currency)).or_default();
+                *expected += amount;
+                tx_ids.push(*tx_id);
+            }
+        }
+        // Balances without anything under dispute must not hold any funds either
+        for account in self.accounts.values() {
+            disputed.entry((account.client, None)).or_default();
+            #[cfg(feature = "multi-currency")]
+            for currency in account.balances.keys() {
+                disputed
+                    .entry((account.client, Some(*currency)))
+                    .or_default();
+            }
+        }
+
+        disputed
+            .into_iter()
+            .filter_map(|((client, currency), (expected, tx_ids))| {
+                let actual = self
+                    .accounts
+                    .get(&client)
+                    .map(|account| account.held_in(currency))
+                    .unwrap_or_default();
+                (actual != expected).then_some(HeldMismatch {
+                    client,
+                    currency,
+                    expected,
+                    actual,
+                    tx_ids,
+                })
+            })
+            .collect()
+    }
+
+    pub fn state_hash_hex(&self) -> String {
+        self.state_hash()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+// Account balances are stored in canonical form already, ledger amounts keep the scale they were read with
+fn hash_amount(hasher: &mut Sha256, amount: Amount) {
+    hasher.update(LEDGER_ROUNDING.canonical(amount).serialize());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transaction::DisputeState;
+    use rust_decimal_macros::dec;
+
+    fn deposit(client_id: ClientID, tx_id: TxID, amount: Amount) -> TransactionData {
+        TransactionData::new(client_id, tx_id, Some(amount))
+    }
+
+    #[test]
+    fn test_equal_states_hash_equal() {
+        let mut accounts = BTreeMap::new();
+        accounts.insert(2, Account::new(2, dec!(3), dec!(0), dec!(3)));
+        accounts.insert(1, Account::new(1, dec!(1.5), dec!(0), dec!(1.5)));
+        let transactions =
+            BTreeMap::from([(1, deposit(1, 1, dec!(1.5))), (2, deposit(2, 2, dec!(3)))]);
+
+        // Same state built in another order and with amounts at a different scale
+        let mut other_accounts = BTreeMap::new();
+        other_accounts.insert(1, Account::new(1, dec!(1.5000), dec!(0.00), dec!(1.50)));
+        other_accounts.insert(2, Account::new(2, dec!(3.0), dec!(0), dec!(3.0000)));
+        let mut other_transactions = BTreeMap::new();
+        other_transactions.insert(2, deposit(2, 2, dec!(3.00)));
+        other_transactions.insert(1, deposit(1, 1, dec!(1.5000)));
+
+        assert_eq!(
+            LedgerState::new(&accounts, &transactions).state_hash(),
+            LedgerState::new(&other_accounts, &other_transactions).state_hash()
+        );
+    }
+
+    #[test]
+    fn test_state_changes_change_hash() {
+        let accounts = BTreeMap::from([(1, Account::new(1, dec!(1.5), dec!(0), dec!(1.5)))]);
+        let transactions = BTreeMap::from([(1, deposit(1, 1, dec!(1.5)))]);
+        let hash = LedgerState::new(&accounts, &transactions).state_hash();
+
+        let mut locked = accounts.clone();
+        locked.get_mut(&1).unwrap().locked = true;
+        assert_ne!(LedgerState::new(&locked, &transactions).state_hash(), hash);
+
+        let mut disputed = transactions.clone();
+        disputed.get_mut(&1).unwrap().dispute_state = DisputeState::Disputed;
+        assert_ne!(LedgerState::new(&accounts, &disputed).state_hash(), hash);
+    }
+
+    #[test]
+    fn test_reconcile_held() {
+        let mut disputed = deposit(1, 1, dec!(2));
+        disputed.dispute_state = DisputeState::Disputed;
+        let transactions = BTreeMap::from([
+            (1, disputed),
+            (2, deposit(1, 2, dec!(3))),
+            (3, deposit(2, 3, dec!(1))),
+        ]);
+        let accounts = BTreeMap::from([
+            (1, Account::new(1, dec!(3), dec!(2.0), dec!(5))),
+            (2, Account::new(2, dec!(1), dec!(0), dec!(1))),
+        ]);
+        assert!(LedgerState::new(&accounts, &transactions)
+            .reconcile_held()
+            .is_empty());
+
+        // Funds held for client 2 without a dispute, and client 1's dispute not held
+        let broken = BTreeMap::from([
+            (1, Account::new(1, dec!(5), dec!(0), dec!(5))),
+            (2, Account::new(2, dec!(0), dec!(1), dec!(1))),
+        ]);
+        assert_eq!(
+            LedgerState::new(&broken, &transactions).reconcile_held(),
+            vec![
+                HeldMismatch {
+                    client: 1,
+                    currency: None,
+                    expected: dec!(2),
+                    actual: dec!(0),
+                    tx_ids: vec![1],
+                },
+                HeldMismatch {
+                    client: 2,
+                    currency: None,
+                    expected: dec!(0),
+                    actual: dec!(1),
+                    tx_ids: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            LedgerState::new(&broken, &transactions).reconcile_held()[0].to_string(),
+            "client 1 holds 0.0000 but has 2 under dispute (tx 1)"
+        );
+    }
+}
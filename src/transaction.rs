@@ -1,36 +1,436 @@
 use rust_decimal::prelude::*;
-use std::ops::Deref;
+
+use crate::error::source;
+use crate::rounding::LEDGER_ROUNDING;
+use crate::AccountingError;
 
 pub type TxID = u32;
 pub type ClientID = u16;
 pub type Amount = Decimal;
 
-#[derive(Clone, Debug)]
+// Amounts in the input carry at most four decimal places
+pub const MAX_AMOUNT_SCALE: u32 = 4;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Transaction {
     Deposit(TransactionData),
     Withdrawal(TransactionData),
+    // Withdraws all available funds, the amount is determined when the transaction is processed
+    WithdrawAll(TransactionData),
     Resolve(TransactionData),
     Dispute(TransactionData),
     Chargeback(TransactionData),
+    // Completes or cancels a two-phase withdrawal, the tx id refers to the withdrawal
+    Settle(TransactionData),
+    Cancel(TransactionData),
+    Interest(InterestData),
 }
 
-impl Deref for Transaction {
-    type Target = TransactionData;
-    fn deref(&self) -> &TransactionData {
+// Constructors taking only the fields that belong to each type, so a transaction can't be created
+// with an amount on a dispute or already under dispute
+impl Transaction {
+    pub fn deposit(client_id: ClientID, tx_id: TxID, amount: Amount) -> Self {
+        Transaction::Deposit(TransactionData::new(client_id, tx_id, Some(amount)))
+    }
+
+    pub fn withdrawal(client_id: ClientID, tx_id: TxID, amount: Amount) -> Self {
+        Transaction::Withdrawal(TransactionData {
+            kind: DisputeKind::Withdrawal,
+            ..TransactionData::new(client_id, tx_id, Some(amount))
+        })
+    }
+
+    pub fn withdraw_all(client_id: ClientID, tx_id: TxID) -> Self {
+        Transaction::WithdrawAll(TransactionData {
+            kind: DisputeKind::Withdrawal,
+            ..TransactionData::new(client_id, tx_id, None)
+        })
+    }
+
+    pub fn dispute(client_id: ClientID, tx_id: TxID) -> Self {
+        Transaction::Dispute(TransactionData::new(client_id, tx_id, None))
+    }
+
+    pub fn resolve(client_id: ClientID, tx_id: TxID) -> Self {
+        Transaction::Resolve(TransactionData::new(client_id, tx_id, None))
+    }
+
+    pub fn chargeback(client_id: ClientID, tx_id: TxID) -> Self {
+        Transaction::Chargeback(TransactionData::new(client_id, tx_id, None))
+    }
+
+    pub fn settle(client_id: ClientID, tx_id: TxID) -> Self {
+        Transaction::Settle(TransactionData::new(client_id, tx_id, None))
+    }
+
+    pub fn cancel(client_id: ClientID, tx_id: TxID) -> Self {
+        Transaction::Cancel(TransactionData::new(client_id, tx_id, None))
+    }
+
+    pub fn interest(client_id: ClientID, rate: Decimal, period_days: u32) -> Self {
+        Transaction::Interest(InterestData {
+            client_id,
+            rate,
+            period_days,
+        })
+    }
+
+    // Sets the currency of the transaction, interest is always in the default currency
+    pub fn in_currency(mut self, currency: Option<CurrencyCode>) -> Self {
+        match &mut self {
+            Transaction::Deposit(tx)
+            | Transaction::Withdrawal(tx)
+            | Transaction::WithdrawAll(tx)
+            | Transaction::Resolve(tx)
+            | Transaction::Dispute(tx)
+            | Transaction::Chargeback(tx)
+            | Transaction::Settle(tx)
+            | Transaction::Cancel(tx) => tx.currency = currency,
+            Transaction::Interest(_) => (),
+        }
+        self
+    }
+
+    // Marks a deposit or withdrawal as disputed from the start, for readers loading the state of a ledger
+    // export with a trusted dispute column. Other transactions can't be under dispute themselves
+    #[cfg(feature = "serde")]
+    pub(crate) fn under_dispute(mut self, under_dispute: bool) -> Self {
+        if let Transaction::Deposit(tx) | Transaction::Withdrawal(tx) = &mut self {
+            tx.dispute_state = if under_dispute {
+                DisputeState::Disputed
+            } else {
+                DisputeState::None
+            };
+        }
+        self
+    }
+
+    pub fn client_id(&self) -> ClientID {
         match self {
-            Transaction::Deposit(tx) => tx,
-            Transaction::Withdrawal(tx) => tx,
-            Transaction::Resolve(tx) => tx,
-            Transaction::Dispute(tx) => tx,
-            Transaction::Chargeback(tx) => tx,
+            Transaction::Deposit(tx) => tx.client_id,
+            Transaction::Withdrawal(tx) => tx.client_id,
+            Transaction::WithdrawAll(tx) => tx.client_id,
+            Transaction::Resolve(tx) => tx.client_id,
+            Transaction::Dispute(tx) => tx.client_id,
+            Transaction::Chargeback(tx) => tx.client_id,
+            Transaction::Settle(tx) => tx.client_id,
+            Transaction::Cancel(tx) => tx.client_id,
+            Transaction::Interest(interest) => interest.client_id,
+        }
+    }
+
+    // Interest rows are the only transactions without an id of their own
+    pub fn tx_id(&self) -> Option<TxID> {
+        match self {
+            Transaction::Deposit(tx)
+            | Transaction::Withdrawal(tx)
+            | Transaction::WithdrawAll(tx)
+            | Transaction::Resolve(tx)
+            | Transaction::Dispute(tx)
+            | Transaction::Chargeback(tx)
+            | Transaction::Settle(tx)
+            | Transaction::Cancel(tx) => Some(tx.tx_id),
+            Transaction::Interest(_) => None,
+        }
+    }
+
+    // Deposits and withdrawals are the transactions with a ledger entry of their own
+    pub fn records_entry(&self) -> bool {
+        matches!(
+            self,
+            Transaction::Deposit(_) | Transaction::Withdrawal(_) | Transaction::WithdrawAll(_)
+        )
+    }
+
+    // The value of the type column for this transaction
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Transaction::Deposit(_) => "deposit",
+            Transaction::Withdrawal(_) => "withdrawal",
+            Transaction::WithdrawAll(_) => "withdraw_all",
+            Transaction::Resolve(_) => "resolve",
+            Transaction::Dispute(_) => "dispute",
+            Transaction::Chargeback(_) => "chargeback",
+            Transaction::Settle(_) => "settle",
+            Transaction::Cancel(_) => "cancel",
+            Transaction::Interest(_) => "interest",
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct TransactionData {
+    pub(crate) client_id: ClientID,
+    pub(crate) tx_id: TxID,
+    pub(crate) amount: Option<Amount>,
+    pub(crate) dispute_state: DisputeState,
+    // A two-phase withdrawal whose funds are in the account's pending_out bucket
+    pub(crate) pending_settlement: bool,
+    // Only taken into account with the multi-currency feature, None means the account's default currency
+    pub(crate) currency: Option<CurrencyCode>,
+    // Whether the ledger entry took funds into or out of the account, which decides how a dispute of it is applied
+    pub(crate) kind: DisputeKind,
+    // Global sequence number assigned when the processor accepted the transaction, if sequencing is enabled.
+    // Unlike the tx id it is ours, and increases across all processors sharing the sequence
+    pub(crate) seq: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisputeKind {
+    // Disputing holds the deposited funds, a chargeback takes them out of the account
+    #[default]
+    Deposit,
+    // The funds have already left the account. Disputing holds them on top of the balance, a resolve
+    // lets the withdrawal stand and a chargeback returns them to available
+    Withdrawal,
+}
+
+// Where a ledger entry is in the dispute lifecycle. An entry can be disputed again after a resolve,
+// a chargeback is final
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DisputeState {
+    #[default]
+    None,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl std::fmt::Display for DisputeState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            DisputeState::None => "not disputed",
+            DisputeState::Disputed => "disputed",
+            DisputeState::Resolved => "resolved",
+            DisputeState::ChargedBack => "charged back",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+impl TransactionData {
+    // The ledger entry of a transaction that has just been recorded
+    pub(crate) fn new(client_id: ClientID, tx_id: TxID, amount: Option<Amount>) -> Self {
+        TransactionData {
+            client_id,
+            tx_id,
+            amount,
+            dispute_state: DisputeState::None,
+            pending_settlement: false,
+            currency: None,
+            kind: DisputeKind::Deposit,
+            seq: None,
+        }
+    }
+
+    pub fn client_id(&self) -> ClientID {
+        self.client_id
+    }
+
+    pub fn tx_id(&self) -> TxID {
+        self.tx_id
+    }
+
+    // Only deposits and withdrawals carry an amount
+    pub fn amount(&self) -> Option<Amount> {
+        self.amount
+    }
+
+    pub fn dispute_state(&self) -> DisputeState {
+        self.dispute_state
+    }
+
+    // Whether the amount is currently held by an open dispute
+    pub fn under_dispute(&self) -> bool {
+        self.dispute_state == DisputeState::Disputed
+    }
+
+    pub fn pending_settlement(&self) -> bool {
+        self.pending_settlement
+    }
+
+    pub fn currency(&self) -> Option<CurrencyCode> {
+        self.currency
+    }
+
+    pub fn kind(&self) -> DisputeKind {
+        self.kind
+    }
+
+    pub fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+}
+
+// ISO 4217 currency code, e.g. CurrencyCode(*b"USD")
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CurrencyCode(pub [u8; 3]);
+
+impl FromStr for CurrencyCode {
+    type Err = AccountingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            &[a, b, c] if s.bytes().all(|b| b.is_ascii_uppercase()) => Ok(CurrencyCode([a, b, c])),
+            _ => Err(AccountingError::MalformedTransaction),
+        }
+    }
+}
+
+impl std::fmt::Display for CurrencyCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Always valid ASCII, see from_str
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+// Interest is credited to the account without a transaction id of its own, so it can't be disputed
+#[derive(Clone, Debug, PartialEq)]
+pub struct InterestData {
     pub client_id: ClientID,
-    pub tx_id: TxID,
-    pub amount: Option<Amount>,
-    pub under_dispute: bool,
+    // Annual interest rate, e.g. 0.05 for 5%
+    pub rate: Decimal,
+    pub period_days: u32,
+}
+
+pub trait AmountExt: Sized {
+    // Formats the amount for output with exactly four decimal places, e.g. "1.5000" for 1.5 and "0.0000" for 0
+    fn to_display_string(&self) -> String;
+
+    // Parses an amount, rejecting inputs with more than four decimal places instead of silently accepting them
+    fn from_str_strict(s: &str) -> Result<Self, AccountingError>;
+}
+
+impl AmountExt for Amount {
+    fn to_display_string(&self) -> String {
+        format_amount(*self)
+    }
+
+    fn from_str_strict(s: &str) -> Result<Self, AccountingError> {
+        let amount = Decimal::from_str(s)
+            .map_err(|e| AccountingError::Deserialize(e.to_string(), source(e)))?;
+        validate_amount_precision(amount, None)?;
+        Ok(amount)
+    }
+}
+
+// Rejects amounts with more than four decimal places. The scale is taken as written, so 1.00000 is rejected too.
+// The tx id is named in the error when the amount belongs to one
+pub fn validate_amount_precision(amount: Amount, tx: Option<TxID>) -> Result<(), AccountingError> {
+    if amount.scale() > MAX_AMOUNT_SCALE {
+        return Err(AccountingError::PrecisionExceeded(tx, amount.scale()));
+    }
+    Ok(())
+}
+
+pub fn format_amount(amount: Amount) -> String {
+    format!(
+        "{:.*}",
+        LEDGER_ROUNDING.scale as usize,
+        LEDGER_ROUNDING.round(amount)
+    )
+}
+
+#[cfg(feature = "serde")]
+pub fn serialize_amount<S: serde::Serializer>(
+    amount: &Amount,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_amount(*amount))
+}
+
+#[cfg(feature = "serde")]
+pub fn serialize_optional_amount<S: serde::Serializer>(
+    amount: &Option<Amount>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => serialize_amount(amount, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(dec!(1.5)), "1.5000");
+        assert_eq!(format_amount(dec!(0)), "0.0000");
+        assert_eq!(format_amount(dec!(0.0001)), "0.0001");
+        assert_eq!(format_amount(dec!(9999999.9999)), "9999999.9999");
+        assert_eq!(format_amount(dec!(-2)), "-2.0000");
+        assert_eq!(dec!(3.3333).to_display_string(), "3.3333");
+    }
+
+    #[test]
+    fn test_currency_code() {
+        assert_eq!(
+            "USD".parse::<CurrencyCode>().unwrap(),
+            CurrencyCode(*b"USD")
+        );
+        assert_eq!(CurrencyCode(*b"NOK").to_string(), "NOK");
+        for invalid in ["usd", "US", "USDT", "U$D", ""] {
+            assert!(invalid.parse::<CurrencyCode>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_seed_dispute_state() {
+        let Transaction::Deposit(deposit) = Transaction::deposit(1, 1, dec!(2)).under_dispute(true)
+        else {
+            panic!("expected a deposit");
+        };
+        assert_eq!(deposit.dispute_state(), DisputeState::Disputed);
+        assert_eq!(deposit.amount(), Some(dec!(2)));
+        // Only deposits and withdrawals have a dispute state of their own
+        let Transaction::Dispute(dispute) = Transaction::dispute(1, 1).under_dispute(true) else {
+            panic!("expected a dispute");
+        };
+        assert_eq!(
+            (dispute.dispute_state(), dispute.amount()),
+            (DisputeState::None, None)
+        );
+    }
+
+    #[test]
+    fn test_from_str_strict() {
+        assert_eq!(Amount::from_str_strict("1").unwrap(), dec!(1));
+        assert_eq!(Amount::from_str_strict("1.1").unwrap(), dec!(1.1));
+        assert_eq!(Amount::from_str_strict("1.12").unwrap(), dec!(1.12));
+        assert_eq!(Amount::from_str_strict("1.123").unwrap(), dec!(1.123));
+        assert_eq!(Amount::from_str_strict("1.1234").unwrap(), dec!(1.1234));
+        assert!(matches!(
+            Amount::from_str_strict("1.12345"),
+            Err(AccountingError::PrecisionExceeded(None, 5))
+        ));
+        assert!(matches!(
+            Amount::from_str_strict("abc"),
+            Err(AccountingError::Deserialize(..))
+        ));
+    }
+
+    #[test]
+    fn test_validate_amount_precision() {
+        assert!(validate_amount_precision(dec!(1.1234), Some(7)).is_ok());
+        let error = validate_amount_precision(dec!(1.12340), Some(7)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Error: Amount of transaction 7 has 5 decimal places, at most four are allowed"
+        );
+        let error = validate_amount_precision(dec!(0.00001), None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Error: Amount has 5 decimal places, at most four are allowed"
+        );
+    }
+
+    #[test]
+    fn test_format_amount_rounds_excess_precision() {
+        assert_eq!(format_amount(dec!(0.00005)), "0.0000");
+        assert_eq!(format_amount(dec!(0.00015)), "0.0002");
+    }
 }
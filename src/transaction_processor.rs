@@ -1,16 +1,261 @@
-use std::collections::{btree_map::Entry, BTreeMap};
+use rust_decimal_macros::dec;
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
+use std::ops::AddAssign;
+#[cfg(feature = "serde")]
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
 use tokio::sync::RwLock;
 
 use crate::account::Account;
-use crate::transaction::{ClientID, Transaction, TransactionData, TxID};
+use crate::aggregates::VolumeAggregates;
+use crate::compliance::ComplianceChecker;
+#[cfg(feature = "serde")]
+use crate::csv_utils::TransactionReader;
+use crate::events::EventBus;
+use crate::latency::LatencyHistogram;
+#[cfg(feature = "serde")]
+use crate::pipeline::PipelineBuilder;
+use crate::transaction::{
+    Amount, ClientID, CurrencyCode, DisputeKind, DisputeState, Transaction, TransactionData, TxID,
+};
 use crate::AccountingError;
 
+// Counts of what happened to the transactions of a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessingStats {
+    // Input rows that could not be turned into a transaction
+    pub rejected: usize,
+    // Rejected rows over the record or field size limits, see RecordLimits
+    pub oversized: usize,
+    // Transactions dropped by a filter before reaching the processor
+    pub filtered: usize,
+    pub applied: usize,
+    // Transactions refused by the processor, e.g. for insufficient funds or a compliance block
+    pub failed: usize,
+    // Bytes of corrupted input skipped by the reader to resynchronize
+    pub skipped_bytes: u64,
+    // Clients whose cumulative deposits went over the deposit volume limit
+    pub volume_limit_exceeded: usize,
+    // Time spent processing each transaction
+    pub latency: LatencyHistogram,
+    // Transactions that took longer than the slow transaction threshold
+    pub slow_transactions: usize,
+    // Withdrawals rejected by the withdrawal policy
+    pub withdrawals_suspended: usize,
+    // Count and sum of the deposits, withdrawals and chargebacks applied
+    pub volumes: VolumeAggregates,
+}
+
+// Adds up the stats of several processors, e.g. the shards of a run
+impl AddAssign for ProcessingStats {
+    fn add_assign(&mut self, other: Self) {
+        self.rejected += other.rejected;
+        self.oversized += other.oversized;
+        self.filtered += other.filtered;
+        self.applied += other.applied;
+        self.failed += other.failed;
+        self.skipped_bytes += other.skipped_bytes;
+        self.volume_limit_exceeded += other.volume_limit_exceeded;
+        self.latency += other.latency;
+        self.slow_transactions += other.slow_transactions;
+        self.withdrawals_suspended += other.withdrawals_suspended;
+        self.volumes += other.volumes;
+    }
+}
+
+// AML limit on the cumulative deposits of a client within a run. Crossing it flags the account,
+// when enforced any further deposits for the client are rejected as well
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepositVolumeLimit {
+    pub limit: Amount,
+    pub enforce: bool,
+}
+
+// When a chargeback locks the account. Transactions carry no timestamps, so a threshold counts every
+// chargeback of the client so far rather than those within a time window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChargebackLockPolicy {
+    #[default]
+    Always,
+    // Locks the account with the client's count-th chargeback
+    Threshold {
+        count: u32,
+    },
+}
+
+impl ChargebackLockPolicy {
+    pub fn locks(&self, chargebacks: u32) -> bool {
+        match self {
+            ChargebackLockPolicy::Always => true,
+            ChargebackLockPolicy::Threshold { count } => chargebacks >= *count,
+        }
+    }
+}
+
+// Clients whose withdrawals are suspended, without locking their accounts. Deposits, disputes and
+// everything else still go through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WithdrawalPolicy {
+    // Withdrawals of the listed clients are rejected
+    Deny(BTreeSet<ClientID>),
+    // Only the listed clients may withdraw
+    Allow(BTreeSet<ClientID>),
+}
+
+impl Default for WithdrawalPolicy {
+    fn default() -> Self {
+        WithdrawalPolicy::Deny(BTreeSet::new())
+    }
+}
+
+impl WithdrawalPolicy {
+    pub fn permits(&self, client: ClientID) -> bool {
+        match self {
+            WithdrawalPolicy::Deny(clients) => !clients.contains(&client),
+            WithdrawalPolicy::Allow(clients) => clients.contains(&client),
+        }
+    }
+
+    // The listed clients, to add or remove clients while processing
+    pub fn clients_mut(&mut self) -> &mut BTreeSet<ClientID> {
+        match self {
+            WithdrawalPolicy::Deny(clients) | WithdrawalPolicy::Allow(clients) => clients,
+        }
+    }
+}
+
+// Sending half of a processor's channel. Sending on a bounded channel waits while it is full, so a
+// producer can't get further ahead of the processor than the channel's capacity
+#[derive(Debug, Clone)]
+pub enum TransactionSender {
+    Unbounded(UnboundedSender<Transaction>),
+    Bounded(Sender<Transaction>),
+}
+
+impl TransactionSender {
+    // Fails once the processor has stopped
+    pub async fn send(&self, tx: Transaction) -> Result<(), SendError<Transaction>> {
+        match self {
+            TransactionSender::Unbounded(sender) => sender.send(tx),
+            TransactionSender::Bounded(sender) => sender.send(tx).await,
+        }
+    }
+}
+
+impl From<UnboundedSender<Transaction>> for TransactionSender {
+    fn from(sender: UnboundedSender<Transaction>) -> Self {
+        TransactionSender::Unbounded(sender)
+    }
+}
+
+enum TransactionReceiver {
+    Unbounded(UnboundedReceiver<Transaction>),
+    Bounded(Receiver<Transaction>),
+}
+
+impl TransactionReceiver {
+    async fn recv(&mut self) -> Option<Transaction> {
+        match self {
+            TransactionReceiver::Unbounded(receiver) => receiver.recv().await,
+            TransactionReceiver::Bounded(receiver) => receiver.recv().await,
+        }
+    }
+}
+
+// Options of the processor's channel and run loop. Options of how transactions are applied are set
+// on the processor itself, with its with_ methods
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TransactionProcessorBuilder {
+    channel_capacity: Option<usize>,
+    idle_timeout: Option<Duration>,
+}
+
+impl TransactionProcessorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Number of transactions the channel holds before sending waits, None for an unbounded channel
+    pub fn channel_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    // Stops processing when no transaction arrives for this long, even though senders are still around,
+    // e.g. for a producer that went quiet without closing its end
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn build(
+        self,
+        transactions: Arc<RwLock<BTreeMap<TxID, TransactionData>>>,
+        accounts: Arc<RwLock<BTreeMap<ClientID, Account>>>,
+    ) -> (TransactionProcessor, TransactionSender) {
+        let (sender, receiver) = match self.channel_capacity {
+            Some(capacity) => {
+                let (sender, receiver) = channel(capacity);
+                (
+                    TransactionSender::Bounded(sender),
+                    TransactionReceiver::Bounded(receiver),
+                )
+            }
+            None => {
+                let (sender, receiver) = unbounded_channel();
+                (
+                    TransactionSender::Unbounded(sender),
+                    TransactionReceiver::Unbounded(receiver),
+                )
+            }
+        };
+        (self.processor(transactions, accounts, receiver), sender)
+    }
+
+    fn processor(
+        self,
+        transactions: Arc<RwLock<BTreeMap<TxID, TransactionData>>>,
+        accounts: Arc<RwLock<BTreeMap<ClientID, Account>>>,
+        receiver: TransactionReceiver,
+    ) -> TransactionProcessor {
+        TransactionProcessor {
+            accounts,
+            transactions,
+            transaction_recv: receiver,
+            idle_timeout: self.idle_timeout,
+            compliance: ComplianceChecker::default(),
+            events: None,
+            stats: ProcessingStats::default(),
+            two_phase_withdrawals: false,
+            deposit_volume_limit: None,
+            slow_tx_threshold: None,
+            withdrawal_policy: Default::default(),
+            sequence: None,
+            chargeback_lock_policy: ChargebackLockPolicy::default(),
+        }
+    }
+}
+
 pub struct TransactionProcessor {
     accounts: Arc<RwLock<BTreeMap<ClientID, Account>>>,
     transactions: Arc<RwLock<BTreeMap<TxID, TransactionData>>>,
-    transaction_recv: UnboundedReceiver<Transaction>,
+    transaction_recv: TransactionReceiver,
+    idle_timeout: Option<Duration>,
+    compliance: ComplianceChecker,
+    events: Option<Arc<EventBus>>,
+    stats: ProcessingStats,
+    two_phase_withdrawals: bool,
+    deposit_volume_limit: Option<DepositVolumeLimit>,
+    slow_tx_threshold: Option<Duration>,
+    withdrawal_policy: Arc<RwLock<WithdrawalPolicy>>,
+    sequence: Option<Arc<AtomicU64>>,
+    chargeback_lock_policy: ChargebackLockPolicy,
 }
 
 impl TransactionProcessor {
@@ -18,24 +263,89 @@ impl TransactionProcessor {
         transactions: Arc<RwLock<BTreeMap<TxID, TransactionData>>>,
         accounts: Arc<RwLock<BTreeMap<ClientID, Account>>>,
     ) -> (Self, UnboundedSender<Transaction>) {
+        // The defaults of the builder, with an unbounded channel
         let (sender, receiver) = unbounded_channel();
+        let receiver = TransactionReceiver::Unbounded(receiver);
         (
-            TransactionProcessor {
-                accounts,
-                transactions,
-                transaction_recv: receiver,
-            },
+            TransactionProcessorBuilder::new().processor(transactions, accounts, receiver),
             sender,
         )
     }
 
+    // Runs the given compliance rules against every transaction before it is applied
+    pub fn with_compliance(mut self, compliance: ComplianceChecker) -> Self {
+        self.compliance = compliance;
+        self
+    }
+
+    // Publishes ledger events on the given bus, which should be shared by all processors on the same accounts
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    // Withdrawals only move funds to pending_out, a later settle or cancel completes them
+    pub fn with_two_phase_withdrawals(mut self, enabled: bool) -> Self {
+        self.two_phase_withdrawals = enabled;
+        self
+    }
+
+    pub fn with_deposit_volume_limit(mut self, limit: Option<DepositVolumeLimit>) -> Self {
+        self.deposit_volume_limit = limit;
+        self
+    }
+
+    // Transactions taking longer than the threshold to process are logged to stderr
+    pub fn with_slow_tx_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_tx_threshold = threshold;
+        self
+    }
+
+    // Shared so the policy can be changed while the processor is running, and applies to the
+    // transactions processed after the change
+    pub fn with_withdrawal_policy(mut self, policy: Arc<RwLock<WithdrawalPolicy>>) -> Self {
+        self.withdrawal_policy = policy;
+        self
+    }
+
+    // Stamps every applied transaction with the next number of the sequence, starting at 1. Share the
+    // sequence between all processors of a run so the numbers increase globally, they don't need to be gap-free
+    pub fn with_sequence(mut self, sequence: Option<Arc<AtomicU64>>) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    pub fn with_chargeback_lock_policy(mut self, policy: ChargebackLockPolicy) -> Self {
+        self.chargeback_lock_policy = policy;
+        self
+    }
+
     pub async fn process(mut self) -> Self {
-        // loop until sender is dropped
-        while let Some(tx) = self.transaction_recv.recv().await {
-            match self.process_transaction(tx).await {
+        // loop until sender is dropped, or until the idle timeout passes without a transaction
+        while let Some(tx) = self.next_transaction().await {
+            let (client_id, tx_id, kind) = (tx.client_id(), tx.tx_id(), tx.kind());
+            let started = Instant::now();
+            let result = self.process_transaction(tx).await;
+            let elapsed = started.elapsed();
+            self.stats.latency.record(elapsed);
+            if self
+                .slow_tx_threshold
+                .is_some_and(|threshold| elapsed > threshold)
+            {
+                self.stats.slow_transactions += 1;
+                eprintln!(
+                    "warning: slow transaction: tx {} client {} type {} took {:?}",
+                    tx_id.map_or_else(|| "-".to_string(), |tx_id| tx_id.to_string()),
+                    client_id,
+                    kind,
+                    elapsed
+                );
+            }
+            match result {
                 // TODO: Error handling
-                Ok(_) => (),
+                Ok(_) => self.stats.applied += 1,
                 Err(_e) => {
+                    self.stats.failed += 1;
                     // Todo: Do more sophisticated error handling. Write the erroneous transaction to log etc.
                     // eprintln!("Error processing transaction: {:?}", _e);
                 }
@@ -45,96 +355,473 @@ impl TransactionProcessor {
         self
     }
 
-    async fn process_transaction(&mut self, tx: Transaction) -> Result<(), AccountingError> {
-        let client_id = tx.client_id;
+    async fn next_transaction(&mut self) -> Option<Transaction> {
+        match self.idle_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.transaction_recv.recv())
+                .await
+                .ok()
+                .flatten(),
+            None => self.transaction_recv.recv().await,
+        }
+    }
+
+    pub fn stats(&self) -> ProcessingStats {
+        self.stats
+    }
+
+    // Gives the processor a new channel, e.g. to process again after its senders were dropped
+    pub(crate) fn reopen(&mut self) -> UnboundedSender<Transaction> {
+        let (sender, receiver) = unbounded_channel();
+        self.transaction_recv = TransactionReceiver::Unbounded(receiver);
+        sender
+    }
+
+    // Processes every record of a csv file, returns once all of them have been applied.
+    // The processor gets a new channel of its own, so any sender returned by new() is disconnected
+    #[cfg(feature = "serde")]
+    pub async fn process_csv_file(
+        mut self,
+        path: &Path,
+    ) -> Result<ProcessingStats, AccountingError> {
+        let mut reader = TransactionReader::new(path.to_string_lossy().into_owned())
+            .map_err(|e| AccountingError::Input(e.to_string(), None))?;
+        let sender = self.reopen();
+        PipelineBuilder::new()
+            .reader(&mut reader)
+            .processor((self, sender))
+            .build()
+            .run()
+            .await
+    }
+
+    // Applies a single transaction right away, without going through the channel
+    pub async fn process_transaction(&mut self, tx: Transaction) -> Result<(), AccountingError> {
+        let client_id = tx.client_id();
+        let tx_id = tx.tx_id();
+        let records_entry = tx.records_entry();
 
         let mut accounts = self.accounts.write().await;
         // Create new client with default values if it doesn't already exist
-        let mut client = accounts.entry(client_id).or_default();
+        let client = accounts.entry(client_id).or_default();
         if client.client != client_id {
             // New client, set correct client id
             client.client = client_id;
         }
 
-        if client.locked {
+        // A locked account takes no new money movements, but disputes against its earlier
+        // transactions can still run their course so held funds can be charged back
+        let dispute_family = matches!(
+            tx,
+            Transaction::Dispute(_) | Transaction::Resolve(_) | Transaction::Chargeback(_)
+        );
+        if client.locked && !dispute_family {
             return Err(AccountingError::AccountLocked);
         }
 
+        self.compliance.check(&tx, client)?;
+
+        // Settling is what takes the funds out of the account, so it is suspended too
+        if let Transaction::Withdrawal(tx_data)
+        | Transaction::WithdrawAll(tx_data)
+        | Transaction::Settle(tx_data) = &tx
+        {
+            if !self.withdrawal_policy.read().await.permits(client_id) {
+                self.stats.withdrawals_suspended += 1;
+                if let Some(events) = &self.events {
+                    events.withdrawal_suspended(client_id, tx_data.tx_id);
+                }
+                return Err(AccountingError::WithdrawalsSuspended);
+            }
+        }
+
         match tx {
             Transaction::Deposit(tx_data) => {
                 // Safe to unwrap because of the check performed when the Transaction was created
                 let amount = tx_data.amount.unwrap();
-                let mut transactions = self.transactions.write().await;
-                if let Entry::Vacant(e) = transactions.entry(tx_data.tx_id) {
-                    e.insert(tx_data);
-                } else {
-                    return Err(AccountingError::TransactionAlreadyExists);
+                let tx_id = tx_data.tx_id;
+                let currency = tx_data.currency;
+                let under_dispute = tx_data.under_dispute();
+                if let Some(DepositVolumeLimit { enforce: true, .. }) = self.deposit_volume_limit {
+                    if client.volume_flagged {
+                        return Err(AccountingError::DepositVolumeExceeded);
+                    }
+                }
+                self.insert_entry(client, tx_id, |client| {
+                    // Applied to the balance first, a deposit that would overflow it isn't recorded
+                    client.funds(currency).deposit(amount)?;
+                    if under_dispute {
+                        // Can't fail, the funds to hold were just deposited
+                        client.funds(currency).dispute(amount)?;
+                    }
+                    Ok(TransactionData {
+                        kind: DisputeKind::Deposit,
+                        ..tx_data
+                    })
+                })
+                .await?;
+                if let Some(events) = &self.events {
+                    events.deposit_applied(client_id, tx_id);
+                }
+                if currency.is_none() {
+                    self.stats.volumes.deposit(amount);
+                }
+                // Only the default balance counts towards the volume, amounts in different currencies can't be added up
+                if let (Some(limit), None) = (self.deposit_volume_limit, currency) {
+                    client.deposit_volume += amount;
+                    if client.deposit_volume > limit.limit && !client.volume_flagged {
+                        client.volume_flagged = true;
+                        self.stats.volume_limit_exceeded += 1;
+                        if let Some(events) = &self.events {
+                            events.deposit_volume_exceeded(client_id, tx_id);
+                        }
+                    }
                 }
-                client.deposit(amount);
             }
-            Transaction::Withdrawal(tx_data) => {
+            Transaction::Withdrawal(mut tx_data) => {
                 let amount = tx_data.amount.unwrap();
-                let mut transactions = self.transactions.write().await;
-                // This can fail if the amount exceeds the available amount in the account
-                match client.withdrawal(amount) {
-                    Ok(()) => {
-                        if let Entry::Vacant(e) = transactions.entry(tx_data.tx_id) {
-                            e.insert(tx_data);
-                        } else {
-                            return Err(AccountingError::TransactionAlreadyExists);
-                        }
+                let two_phase = self.two_phase_withdrawals;
+                let default_currency = tx_data.currency.is_none();
+                // A duplicate is rejected first, a rejected withdrawal must not touch the funds
+                self.insert_entry(client, tx_data.tx_id, |client| {
+                    if tx_data.under_dispute() && two_phase {
+                        return Err(AccountingError::DisputePendingWithdrawal);
+                    }
+                    let funds = client.funds(tx_data.currency);
+                    // This can fail if the amount exceeds the available amount in the account
+                    if two_phase {
+                        funds.reserve_withdrawal(amount)?;
+                    } else {
+                        funds.withdrawal(amount)?;
+                    }
+                    if tx_data.under_dispute() {
+                        // Can't fail, the held funds are the ones that were just withdrawn
+                        funds.dispute_withdrawal(amount)?;
+                    }
+                    tx_data.pending_settlement = two_phase;
+                    tx_data.kind = DisputeKind::Withdrawal;
+                    Ok(tx_data)
+                })
+                .await?;
+                if !two_phase && default_currency {
+                    self.stats.volumes.withdrawal(amount);
+                }
+            }
+            Transaction::WithdrawAll(mut tx_data) => {
+                let amount = client.funds(tx_data.currency).available();
+                if amount <= dec!(0) {
+                    return Err(AccountingError::NoFundsAvailable);
+                }
+                let two_phase = self.two_phase_withdrawals;
+                let default_currency = tx_data.currency.is_none();
+                self.insert_entry(client, tx_data.tx_id, |client| {
+                    let funds = client.funds(tx_data.currency);
+                    if two_phase {
+                        funds.reserve_withdrawal(amount)?;
+                        tx_data.pending_settlement = true;
+                    } else {
+                        funds.withdrawal(amount)?;
                     }
-                    Err(e) => return Err(e),
+                    // Record the computed amount so the withdrawal can be disputed like any other
+                    tx_data.amount = Some(amount);
+                    tx_data.kind = DisputeKind::Withdrawal;
+                    Ok(tx_data)
+                })
+                .await?;
+                if !two_phase && default_currency {
+                    self.stats.volumes.withdrawal(amount);
                 }
             }
             Transaction::Dispute(tx_data) => {
-                let mut transactions = self.transactions.write().await;
-                if let Some(mut t) = transactions.get_mut(&tx_data.tx_id) {
-                    // Transaction under dispute exists in the ledger
-                    if t.amount.is_some() && !t.under_dispute {
+                self.update_entry(client, tx_data.tx_id, |t, client| {
+                    if t.client_id != tx_data.client_id {
+                        return Err(AccountingError::DisputeClientMismatch);
+                    }
+                    check_currency(t.currency, tx_data.currency)?;
+                    if t.pending_settlement {
+                        // The funds haven't left the account yet, the withdrawal should be cancelled instead
+                        return Err(AccountingError::DisputePendingWithdrawal);
+                    }
+                    check_transition(t, DisputeState::Disputed)?;
+                    if t.under_dispute() {
+                        return Err(AccountingError::DisputeAlreadyActive(t.tx_id));
+                    }
+                    if let Some(amount) = t.amount {
                         // Dispute the amount iff this is a transaction with an associated amount (i.e. Deposit or Withdrawal)
-                        // and there are sufficient funds available to be held
-                        match client.dispute(t.amount.unwrap()) {
-                            Ok(()) => t.under_dispute = true,
-                            Err(e) => return Err(e),
+                        // and, for a deposit, there are sufficient funds available to be held
+                        let funds = client.funds(t.currency);
+                        match t.kind {
+                            DisputeKind::Deposit => funds.dispute(amount)?,
+                            DisputeKind::Withdrawal => funds.dispute_withdrawal(amount)?,
                         }
+                        t.dispute_state = DisputeState::Disputed;
                     } // else ignore since it is an error on partners side
-                }
+                    Ok(())
+                })
+                .await?;
             }
             Transaction::Resolve(tx_data) => {
-                let mut transactions = self.transactions.write().await;
-                if let Some(mut t) = transactions.get_mut(&tx_data.tx_id) {
-                    // Transaction under dispute exists in the ledger
-                    if t.amount.is_some() && t.under_dispute {
-                        // Dispute the amount iff this is a transaction with an associated amount (i.e. Deposit or Withdrawal)
-                        client.resolve(t.amount.unwrap());
-                        t.under_dispute = false;
-                    } // else ignore since it is an error on partners side
-                }
+                self.update_entry(client, tx_data.tx_id, |t, client| {
+                    if t.client_id != tx_data.client_id {
+                        return Err(AccountingError::DisputeClientMismatch);
+                    }
+                    check_currency(t.currency, tx_data.currency)?;
+                    check_transition(t, DisputeState::Resolved)?;
+                    // Only transactions with an amount (i.e. Deposit or Withdrawal) can be under dispute
+                    let (Some(amount), true) = (t.amount, t.under_dispute()) else {
+                        return Err(AccountingError::ResolveNotDisputed);
+                    };
+                    let funds = client.funds(t.currency);
+                    match t.kind {
+                        DisputeKind::Deposit => funds.resolve(amount)?,
+                        DisputeKind::Withdrawal => funds.resolve_withdrawal(amount)?,
+                    }
+                    t.dispute_state = DisputeState::Resolved;
+                    Ok(())
+                })
+                .await?;
             }
             Transaction::Chargeback(tx_data) => {
-                let mut transactions = self.transactions.write().await;
-                if let Some(mut t) = transactions.get_mut(&tx_data.tx_id) {
-                    // Transaction under dispute exists in the ledger
-                    if t.amount.is_some() && t.under_dispute {
-                        // Dispute the amount iff this is a transaction with an associated amount (i.e. Deposit or Withdrawal)
-                        client.chargeback(t.amount.unwrap());
-                        t.under_dispute = false;
+                let lock_policy = self.chargeback_lock_policy;
+                let was_locked = client.locked;
+                let mut charged_back = None;
+                self.update_entry(client, tx_data.tx_id, |t, client| {
+                    if t.client_id != tx_data.client_id {
+                        return Err(AccountingError::DisputeClientMismatch);
+                    }
+                    check_currency(t.currency, tx_data.currency)?;
+                    check_transition(t, DisputeState::ChargedBack)?;
+                    let (Some(amount), true) = (t.amount, t.under_dispute()) else {
+                        return Err(AccountingError::ChargebackNotDisputed);
+                    };
+                    let funds = client.funds(t.currency);
+                    match t.kind {
+                        DisputeKind::Deposit => funds.chargeback(amount)?,
+                        DisputeKind::Withdrawal => funds.chargeback_withdrawal(amount)?,
+                    }
+                    t.dispute_state = DisputeState::ChargedBack;
+                    client.chargebacks += 1;
+                    if lock_policy.locks(client.chargebacks) {
                         client.locked = true;
-                    } // else ignore since it is an error on partners side
+                    }
+                    charged_back = t.currency.is_none().then_some((amount, t.kind));
+                    Ok(())
+                })
+                .await?;
+                if let Some((amount, kind)) = charged_back {
+                    self.stats.volumes.chargeback(amount, kind);
+                }
+                if let Some(events) = &self.events {
+                    events.chargeback_applied(client_id, tx_data.tx_id);
+                    if client.locked && !was_locked {
+                        events.account_locked(client_id, tx_data.tx_id);
+                    }
+                }
+            }
+            Transaction::Settle(tx_data) => {
+                let mut settled = None;
+                let result = self.update_entry(client, tx_data.tx_id, |t, client| {
+                    if t.client_id != tx_data.client_id {
+                        return Err(AccountingError::SettlementClientMismatch);
+                    }
+                    check_currency(t.currency, tx_data.currency)?;
+                    match (t.amount, t.pending_settlement) {
+                        (Some(amount), true) => {
+                            client.funds(t.currency).settle(amount)?;
+                            t.pending_settlement = false;
+                            settled = t.currency.is_none().then_some(amount);
+                            Ok(())
+                        }
+                        _ => Err(AccountingError::SettlementNotPending),
+                    }
+                });
+                result.await?;
+                if let Some(amount) = settled {
+                    self.stats.volumes.withdrawal(amount);
+                }
+            }
+            Transaction::Cancel(tx_data) => {
+                let result = self.update_entry(client, tx_data.tx_id, |t, client| {
+                    if t.client_id != tx_data.client_id {
+                        return Err(AccountingError::SettlementClientMismatch);
+                    }
+                    check_currency(t.currency, tx_data.currency)?;
+                    match (t.amount, t.pending_settlement) {
+                        (Some(amount), true) => {
+                            client.funds(t.currency).cancel_withdrawal(amount)?;
+                            t.pending_settlement = false;
+                            // The funds never left the account, so there is nothing left to dispute
+                            t.amount = None;
+                            Ok(())
+                        }
+                        _ => Err(AccountingError::SettlementNotPending),
+                    }
+                });
+                result.await?;
+            }
+            Transaction::Interest(interest) => {
+                client.apply_interest(interest.rate, interest.period_days)?;
+            }
+        }
+        // Taken while the accounts are locked, so the numbers follow the order transactions are applied in
+        if let Some(sequence) = &self.sequence {
+            let seq = sequence.fetch_add(1, Ordering::SeqCst) + 1;
+            if let (true, Some(tx_id)) = (records_entry, tx_id) {
+                if let Some(t) = self.transactions.write().await.get_mut(&tx_id) {
+                    t.seq = Some(seq);
                 }
             }
+            if let Some(events) = &self.events {
+                events.transaction_accepted(client_id, tx_id, seq);
+            }
+        }
+        if let Some(events) = &self.events {
+            events.account_updated(client);
+        }
+        Ok(())
+    }
+
+    // Records a new ledger entry. The checks and balance arithmetic run on a copy of the account
+    // before the ledger is write-locked, the lock is only held to re-check the tx id is still free
+    // and insert the entry. The account is updated once the entry is recorded
+    async fn insert_entry<F>(
+        &self,
+        client: &mut Account,
+        tx_id: TxID,
+        apply: F,
+    ) -> Result<(), AccountingError>
+    where
+        F: FnOnce(&mut Account) -> Result<TransactionData, AccountingError>,
+    {
+        if self.transactions.read().await.contains_key(&tx_id) {
+            return Err(AccountingError::TransactionAlreadyExists);
         }
+        let mut account = client.clone();
+        let entry = apply(&mut account)?;
+        match self.transactions.write().await.entry(tx_id) {
+            Entry::Vacant(e) => e.insert(entry),
+            // Recorded by another processor sharing the ledger since the check above
+            Entry::Occupied(_) => return Err(AccountingError::TransactionAlreadyExists),
+        };
+        *client = account;
         Ok(())
     }
+
+    // Updates the ledger entry of a previous transaction, e.g. for a dispute. The entry is copied out
+    // under a read lock, and the checks and balance arithmetic run on copies of the entry and the
+    // account. The write lock is only held to commit the new entry, if it hasn't changed in the
+    // meantime. Otherwise another processor sharing the ledger got there first and it starts over
+    async fn update_entry<F>(
+        &self,
+        client: &mut Account,
+        tx_id: TxID,
+        mut apply: F,
+    ) -> Result<(), AccountingError>
+    where
+        F: FnMut(&mut TransactionData, &mut Account) -> Result<(), AccountingError>,
+    {
+        loop {
+            let entry = self
+                .transactions
+                .read()
+                .await
+                .get(&tx_id)
+                .cloned()
+                .ok_or(AccountingError::TransactionNotFound(tx_id))?;
+            let (mut updated, mut account) = (entry.clone(), client.clone());
+            apply(&mut updated, &mut account)?;
+            if let Some(current) = self.transactions.write().await.get_mut(&tx_id) {
+                if *current == entry {
+                    *current = updated;
+                    *client = account;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// Applies the transactions in order to new, empty accounts and returns the accounts. Transactions
+// that fail are skipped, like rows that fail in a csv run
+pub async fn process_all(
+    transactions: impl IntoIterator<Item = Transaction>,
+) -> BTreeMap<ClientID, Account> {
+    let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+    let (processor, sender) = TransactionProcessor::new(Default::default(), accounts.clone());
+    for tx in transactions {
+        // The receiver is still alive, so sending can't fail
+        let _ = sender.send(tx);
+    }
+    drop(sender);
+    processor.process().await;
+    let accounts = accounts.read().await;
+    accounts.clone()
+}
+
+// Rows referencing a previous transaction may omit the currency, but must not name a different one
+fn check_currency(
+    original: Option<CurrencyCode>,
+    referencing: Option<CurrencyCode>,
+) -> Result<(), AccountingError> {
+    match referencing {
+        Some(currency) if Some(currency) != original => Err(AccountingError::CurrencyMismatch),
+        _ => Ok(()),
+    }
+}
+
+// A chargeback is final, the transaction can't be disputed, resolved or charged back again. The other
+// illegal transitions have their own errors, e.g. resolving a transaction that isn't disputed
+fn check_transition(tx: &TransactionData, to: DisputeState) -> Result<(), AccountingError> {
+    match tx.dispute_state {
+        DisputeState::ChargedBack => Err(AccountingError::InvalidDisputeTransition(
+            tx.tx_id,
+            tx.dispute_state,
+            to,
+        )),
+        _ => Ok(()),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    #[cfg(feature = "multi-currency")]
+    use crate::account::CurrencyBalance;
+    #[cfg(feature = "serde")]
+    use crate::aggregates::Volume;
+    use crate::compliance::{ComplianceResult, ComplianceRule, MaxTransactionAmount};
+    use crate::events::LedgerEvent;
+    use crate::state::LedgerState;
+    use crate::transaction::InterestData;
     use rust_decimal_macros::dec;
+    use tokio::sync::broadcast;
     use tokio::task::JoinHandle;
 
+    // Every test ends by checking that the balances of all accounts still add up
+    fn assert_invariants(accounts: &BTreeMap<ClientID, Account>) {
+        for account in accounts.values() {
+            account.check_invariant().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_all() {
+        let accounts = process_all(vec![
+            Transaction::deposit(1, 1, dec!(2)),
+            Transaction::withdrawal(1, 2, dec!(5)),
+            Transaction::deposit(2, 3, dec!(1)),
+            Transaction::withdrawal(1, 4, dec!(0.5)),
+        ])
+        .await;
+        assert_eq!(
+            accounts,
+            BTreeMap::from([
+                (1, Account::new(1, dec!(1.5), dec!(0), dec!(1.5))),
+                (2, Account::new(2, dec!(1), dec!(0), dec!(1))),
+            ])
+        );
+        assert_invariants(&accounts);
+    }
+
     #[tokio::test]
     async fn test_one_deposit() {
         let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
@@ -142,27 +829,20 @@ mod test {
         let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
         let processor: JoinHandle<TransactionProcessor> =
             tokio::spawn(async move { processor.process().await });
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(dec!(1.5)),
-                under_dispute: false,
-            }))
-            .unwrap();
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
         drop(sender);
         processor.await.unwrap();
 
         let accounts_output = accounts.read().await;
         let output = accounts_output
             .clone()
-            .into_iter()
-            .map(|(_, v)| v)
+            .into_values()
             .collect::<Vec<Account>>();
         assert_eq!(
             &Account::new(1u16, dec!(1.5), dec!(0), dec!(1.5),),
-            output.get(0).unwrap()
+            output.first().unwrap()
         );
+        assert_invariants(&*accounts.read().await);
     }
 
     #[tokio::test]
@@ -172,48 +852,29 @@ mod test {
         let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
         let processor: JoinHandle<TransactionProcessor> =
             tokio::spawn(async move { processor.process().await });
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
         sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(dec!(1.5)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 2,
-                tx_id: 2,
-                amount: Some(dec!(3.3333)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Withdrawal(TransactionData {
-                client_id: 2,
-                tx_id: 3,
-                amount: Some(dec!(1)),
-                under_dispute: false,
-            }))
+            .send(Transaction::deposit(2, 2, dec!(3.3333)))
             .unwrap();
+        sender.send(Transaction::withdrawal(2, 3, dec!(1))).unwrap();
         drop(sender);
         processor.await.unwrap();
 
         let accounts_output = accounts.read().await;
         let output = accounts_output
             .clone()
-            .into_iter()
-            .map(|(_, v)| v)
+            .into_values()
             .collect::<Vec<Account>>();
 
         assert_eq!(
             &Account::new(1u16, dec!(1.5), dec!(0), dec!(1.5),),
-            output.get(0).unwrap()
+            output.first().unwrap()
         );
         assert_eq!(
             &Account::new(2u16, dec!(2.3333), dec!(0), dec!(2.3333),),
             output.get(1).unwrap()
         );
+        assert_invariants(&*accounts.read().await);
     }
 
     #[tokio::test]
@@ -223,30 +884,9 @@ mod test {
         let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
         let processor: JoinHandle<TransactionProcessor> =
             tokio::spawn(async move { processor.process().await });
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(dec!(1.5)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: Some(dec!(3)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Dispute(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
+        sender.send(Transaction::deposit(1, 2, dec!(3))).unwrap();
+        sender.send(Transaction::dispute(1, 2)).unwrap();
 
         drop(sender);
         processor.await.unwrap();
@@ -254,13 +894,13 @@ mod test {
         let accounts_output = accounts.read().await;
         let output = accounts_output
             .clone()
-            .into_iter()
-            .map(|(_, v)| v)
+            .into_values()
             .collect::<Vec<Account>>();
         assert_eq!(
             &Account::new(1u16, dec!(1.5), dec!(3), dec!(4.5),),
-            output.get(0).unwrap()
+            output.first().unwrap()
         );
+        assert_invariants(&*accounts.read().await);
     }
 
     #[tokio::test]
@@ -270,38 +910,10 @@ mod test {
         let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
         let processor: JoinHandle<TransactionProcessor> =
             tokio::spawn(async move { processor.process().await });
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(dec!(1.5)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: Some(dec!(3)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Dispute(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Chargeback(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
+        sender.send(Transaction::deposit(1, 2, dec!(3))).unwrap();
+        sender.send(Transaction::dispute(1, 2)).unwrap();
+        sender.send(Transaction::chargeback(1, 2)).unwrap();
 
         drop(sender);
         processor.await.unwrap();
@@ -309,13 +921,14 @@ mod test {
         let accounts_output = accounts.read().await;
         let output = accounts_output
             .clone()
-            .into_iter()
-            .map(|(_, v)| v)
+            .into_values()
             .collect::<Vec<Account>>();
 
         let mut account = Account::new(1u16, dec!(1.5), dec!(0), dec!(1.5));
         account.locked = true;
-        assert_eq!(&account, output.get(0).unwrap());
+        account.chargebacks = 1;
+        assert_eq!(&account, output.first().unwrap());
+        assert_invariants(&*accounts.read().await);
     }
 
     #[tokio::test]
@@ -325,38 +938,10 @@ mod test {
         let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
         let processor: JoinHandle<TransactionProcessor> =
             tokio::spawn(async move { processor.process().await });
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(dec!(1.5)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: Some(dec!(3)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Dispute(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Resolve(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
+        sender.send(Transaction::deposit(1, 2, dec!(3))).unwrap();
+        sender.send(Transaction::dispute(1, 2)).unwrap();
+        sender.send(Transaction::resolve(1, 2)).unwrap();
 
         drop(sender);
         processor.await.unwrap();
@@ -364,14 +949,14 @@ mod test {
         let accounts_output = accounts.read().await;
         let output = accounts_output
             .clone()
-            .into_iter()
-            .map(|(_, v)| v)
+            .into_values()
             .collect::<Vec<Account>>();
 
         assert_eq!(
             &Account::new(1u16, dec!(4.5), dec!(0), dec!(4.5)),
-            output.get(0).unwrap()
+            output.first().unwrap()
         );
+        assert_invariants(&*accounts.read().await);
     }
 
     #[tokio::test]
@@ -381,30 +966,9 @@ mod test {
         let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
         let processor: JoinHandle<TransactionProcessor> =
             tokio::spawn(async move { processor.process().await });
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(dec!(1.5)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: Some(dec!(3)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Dispute(TransactionData {
-                client_id: 1,
-                tx_id: 3,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
+        sender.send(Transaction::deposit(1, 2, dec!(3))).unwrap();
+        sender.send(Transaction::dispute(1, 3)).unwrap();
 
         drop(sender);
         processor.await.unwrap();
@@ -412,14 +976,14 @@ mod test {
         let accounts_output = accounts.read().await;
         let output = accounts_output
             .clone()
-            .into_iter()
-            .map(|(_, v)| v)
+            .into_values()
             .collect::<Vec<Account>>();
 
         assert_eq!(
             &Account::new(1u16, dec!(4.5), dec!(0), dec!(4.5)),
-            output.get(0).unwrap()
+            output.first().unwrap()
         );
+        assert_invariants(&*accounts.read().await);
     }
 
     #[tokio::test]
@@ -429,38 +993,10 @@ mod test {
         let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
         let processor: JoinHandle<TransactionProcessor> =
             tokio::spawn(async move { processor.process().await });
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(dec!(1.5)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: Some(dec!(3)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Dispute(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Resolve(TransactionData {
-                client_id: 1,
-                tx_id: 3,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
+        sender.send(Transaction::deposit(1, 2, dec!(3))).unwrap();
+        sender.send(Transaction::dispute(1, 2)).unwrap();
+        sender.send(Transaction::resolve(1, 3)).unwrap();
 
         drop(sender);
         processor.await.unwrap();
@@ -468,14 +1004,14 @@ mod test {
         let accounts_output = accounts.read().await;
         let output = accounts_output
             .clone()
-            .into_iter()
-            .map(|(_, v)| v)
+            .into_values()
             .collect::<Vec<Account>>();
 
         assert_eq!(
             &Account::new(1u16, dec!(1.5), dec!(3), dec!(4.5)),
-            output.get(0).unwrap()
+            output.first().unwrap()
         );
+        assert_invariants(&*accounts.read().await);
     }
 
     #[tokio::test]
@@ -485,38 +1021,10 @@ mod test {
         let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
         let processor: JoinHandle<TransactionProcessor> =
             tokio::spawn(async move { processor.process().await });
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(dec!(1.5)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: Some(dec!(3)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Dispute(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Chargeback(TransactionData {
-                client_id: 1,
-                tx_id: 3,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
+        sender.send(Transaction::deposit(1, 2, dec!(3))).unwrap();
+        sender.send(Transaction::dispute(1, 2)).unwrap();
+        sender.send(Transaction::chargeback(1, 3)).unwrap();
 
         drop(sender);
         processor.await.unwrap();
@@ -524,14 +1032,14 @@ mod test {
         let accounts_output = accounts.read().await;
         let output = accounts_output
             .clone()
-            .into_iter()
-            .map(|(_, v)| v)
+            .into_values()
             .collect::<Vec<Account>>();
 
         assert_eq!(
             &Account::new(1u16, dec!(1.5), dec!(3), dec!(4.5)),
-            output.get(0).unwrap()
+            output.first().unwrap()
         );
+        assert_invariants(&*accounts.read().await);
     }
 
     #[tokio::test]
@@ -541,46 +1049,11 @@ mod test {
         let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
         let processor: JoinHandle<TransactionProcessor> =
             tokio::spawn(async move { processor.process().await });
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(dec!(1.5)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: Some(dec!(3)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Withdrawal(TransactionData {
-                client_id: 1,
-                tx_id: 3,
-                amount: Some(dec!(2)),
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Dispute(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
-        sender
-            .send(Transaction::Chargeback(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
-            }))
-            .unwrap();
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
+        sender.send(Transaction::deposit(1, 2, dec!(3))).unwrap();
+        sender.send(Transaction::withdrawal(1, 3, dec!(2))).unwrap();
+        sender.send(Transaction::dispute(1, 2)).unwrap();
+        sender.send(Transaction::chargeback(1, 2)).unwrap();
 
         drop(sender);
         processor.await.unwrap();
@@ -588,14 +1061,14 @@ mod test {
         let accounts_output = accounts.read().await;
         let output = accounts_output
             .clone()
-            .into_iter()
-            .map(|(_, v)| v)
+            .into_values()
             .collect::<Vec<Account>>();
 
         assert_eq!(
             &Account::new(1u16, dec!(2.5), dec!(0), dec!(2.5)),
-            output.get(0).unwrap()
+            output.first().unwrap()
         );
+        assert_invariants(&*accounts.read().await);
     }
 
     #[tokio::test]
@@ -605,44 +1078,477 @@ mod test {
         let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
         let processor: JoinHandle<TransactionProcessor> =
             tokio::spawn(async move { processor.process().await });
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 1,
-                amount: Some(dec!(1.5)),
-                under_dispute: false,
-            }))
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
+        sender.send(Transaction::deposit(1, 2, dec!(3))).unwrap();
+        sender.send(Transaction::withdrawal(1, 3, dec!(2))).unwrap();
+        sender.send(Transaction::dispute(1, 2)).unwrap();
+        sender.send(Transaction::resolve(1, 2)).unwrap();
+
+        drop(sender);
+        processor.await.unwrap();
+
+        let accounts_output = accounts.read().await;
+        let output = accounts_output
+            .clone()
+            .into_values()
+            .collect::<Vec<Account>>();
+
+        assert_eq!(
+            &Account::new(1u16, dec!(2.5), dec!(0), dec!(2.5)),
+            output.first().unwrap()
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_dispute_resolve() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger, accounts.clone());
+        for tx in [
+            Transaction::deposit(1, 1, dec!(10)),
+            Transaction::withdrawal(1, 2, dec!(10)),
+            // Nothing is available, but the withdrawn funds can still be disputed
+            Transaction::dispute(1, 2),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+        }
+        assert_eq!(
+            accounts.read().await.get(&1),
+            Some(&Account::new(1, dec!(0), dec!(10), dec!(10)))
+        );
+
+        processor
+            .process_transaction(Transaction::resolve(1, 2))
+            .await
             .unwrap();
-        sender
-            .send(Transaction::Deposit(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: Some(dec!(3)),
-                under_dispute: false,
-            }))
+        assert_eq!(
+            accounts.read().await.get(&1),
+            Some(&Account::new(1, dec!(0), dec!(0), dec!(0)))
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_dispute_chargeback() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger, accounts.clone());
+        for tx in [
+            Transaction::deposit(1, 1, dec!(10)),
+            Transaction::withdrawal(1, 2, dec!(4)),
+            Transaction::dispute(1, 2),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+        }
+        assert_eq!(
+            accounts.read().await.get(&1),
+            Some(&Account::new(1, dec!(6), dec!(4), dec!(10)))
+        );
+
+        processor
+            .process_transaction(Transaction::chargeback(1, 2))
+            .await
             .unwrap();
-        sender
-            .send(Transaction::Withdrawal(TransactionData {
-                client_id: 1,
-                tx_id: 3,
-                amount: Some(dec!(2)),
-                under_dispute: false,
-            }))
+        // The withdrawal is reversed and the funds are back in available
+        let mut expected = Account::new(1, dec!(10), dec!(0), dec!(10));
+        expected.locked = true;
+        expected.chargebacks = 1;
+        assert_eq!(accounts.read().await.get(&1), Some(&expected));
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_and_chargeback_not_disputed() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        processor
+            .process_transaction(Transaction::deposit(1, 1, dec!(5)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::resolve(1, 1))
+                .await,
+            Err(AccountingError::ResolveNotDisputed)
+        ));
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::chargeback(1, 1))
+                .await,
+            Err(AccountingError::ChargebackNotDisputed)
+        ));
+        // A resolved dispute is no longer under dispute either
+        processor
+            .process_transaction(Transaction::dispute(1, 1))
+            .await
+            .unwrap();
+        processor
+            .process_transaction(Transaction::resolve(1, 1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::chargeback(1, 1))
+                .await,
+            Err(AccountingError::ChargebackNotDisputed)
+        ));
+        assert_eq!(
+            accounts.read().await.get(&1),
+            Some(&Account::new(1, dec!(5), dec!(0), dec!(5)))
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_already_active() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) =
+            TransactionProcessor::new(Default::default(), accounts.clone());
+        for tx in [
+            Transaction::deposit(1, 1, dec!(5)),
+            Transaction::dispute(1, 1),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+        }
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::dispute(1, 1))
+                .await,
+            Err(AccountingError::DisputeAlreadyActive(1))
+        ));
+        // The amount is only held once
+        assert_eq!(
+            accounts.read().await.get(&1),
+            Some(&Account::new(1, dec!(0), dec!(5), dec!(5)))
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_states() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        let state = |ledger: &BTreeMap<TxID, TransactionData>| ledger[&1].dispute_state;
+        processor
+            .process_transaction(Transaction::deposit(1, 1, dec!(5)))
+            .await
             .unwrap();
+        assert_eq!(state(&*ledger.read().await), DisputeState::None);
+        for (tx, expected) in [
+            (Transaction::dispute(1, 1), DisputeState::Disputed),
+            (Transaction::resolve(1, 1), DisputeState::Resolved),
+            // A resolved transaction can be disputed again
+            (Transaction::dispute(1, 1), DisputeState::Disputed),
+            (Transaction::chargeback(1, 1), DisputeState::ChargedBack),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+            assert_eq!(state(&*ledger.read().await), expected);
+        }
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_is_terminal() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        // Enough funds left after the chargeback to hold the deposit a second time
+        for tx in [
+            Transaction::deposit(1, 1, dec!(5)),
+            Transaction::deposit(1, 2, dec!(10)),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+        }
+        for (tx, to) in [
+            (Transaction::dispute(1, 1), DisputeState::Disputed),
+            (Transaction::resolve(1, 1), DisputeState::Resolved),
+            (Transaction::chargeback(1, 1), DisputeState::ChargedBack),
+        ] {
+            assert!(matches!(
+                processor.process_transaction(tx).await,
+                Err(AccountingError::InvalidDisputeTransition(
+                    1,
+                    DisputeState::ChargedBack,
+                    state
+                )) if state == to
+            ));
+        }
+        assert_eq!(
+            ledger.read().await[&1].dispute_state,
+            DisputeState::ChargedBack
+        );
+        let mut expected = Account::new(1, dec!(10), dec!(0), dec!(10));
+        expected.locked = true;
+        expected.chargebacks = 1;
+        assert_eq!(accounts.read().await.get(&1), Some(&expected));
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_chargeback_is_terminal() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) =
+            TransactionProcessor::new(Default::default(), accounts.clone());
+        for tx in [
+            Transaction::deposit(1, 1, dec!(5)),
+            Transaction::withdrawal(1, 2, dec!(3)),
+            Transaction::dispute(1, 2),
+            Transaction::chargeback(1, 2),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+        }
+        // Disputing the withdrawal again would return its funds a second time
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::dispute(1, 2))
+                .await,
+            Err(AccountingError::InvalidDisputeTransition(..))
+        ));
+        let mut expected = Account::new(1, dec!(5), dec!(0), dec!(5));
+        expected.locked = true;
+        expected.chargebacks = 1;
+        assert_eq!(accounts.read().await.get(&1), Some(&expected));
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_family_not_found() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) =
+            TransactionProcessor::new(Default::default(), accounts.clone());
+        processor
+            .process_transaction(Transaction::deposit(1, 1, dec!(5)))
+            .await
+            .unwrap();
+        for tx in [
+            Transaction::dispute(1, 2),
+            Transaction::resolve(1, 2),
+            Transaction::chargeback(1, 2),
+        ] {
+            assert!(matches!(
+                processor.process_transaction(tx).await,
+                Err(AccountingError::TransactionNotFound(2))
+            ));
+        }
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_not_found_message() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) =
+            TransactionProcessor::new(Default::default(), accounts.clone());
+        let err = processor
+            .process_transaction(Transaction::dispute(1, 42))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: Transaction 42 does not exist in the ledger"
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_trusted_dispute_state_continues_like_a_dispute() {
+        use crate::csv_utils::TransactionReaderConfig;
+
+        let export = "type, client, tx, amount, under_dispute
+deposit, 1, 1, 10.0, false
+deposit, 1, 2, 4.0, true
+withdrawal, 1, 3, 3.0, true
+";
+        let config = TransactionReaderConfig {
+            trust_dispute_column: true,
+            ..Default::default()
+        };
+        let mut reader = TransactionReader::from_reader(export.as_bytes(), config).unwrap();
+        let loaded_accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut loaded, _sender) =
+            TransactionProcessor::new(Default::default(), loaded_accounts.clone());
+        while let Some(tx) = reader.get_next_record().unwrap() {
+            loaded.process_transaction(tx).await.unwrap();
+        }
+        let replayed_accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut replayed, _sender) =
+            TransactionProcessor::new(Default::default(), replayed_accounts.clone());
+        for tx in [
+            Transaction::deposit(1, 1, dec!(10)),
+            Transaction::deposit(1, 2, dec!(4)),
+            Transaction::withdrawal(1, 3, dec!(3)),
+            Transaction::dispute(1, 2),
+            Transaction::dispute(1, 3),
+        ] {
+            replayed.process_transaction(tx).await.unwrap();
+        }
+
+        // The disputed deposit is held, the disputed withdrawal is held on top of the balance
+        assert_eq!(
+            loaded_accounts.read().await.get(&1),
+            Some(&Account::new(1, dec!(7), dec!(7), dec!(14)))
+        );
+        assert_eq!(
+            *loaded_accounts.read().await,
+            *replayed_accounts.read().await
+        );
+        for tx in [Transaction::resolve(1, 2), Transaction::chargeback(1, 3)] {
+            loaded.process_transaction(tx.clone()).await.unwrap();
+            replayed.process_transaction(tx).await.unwrap();
+            assert_eq!(
+                *loaded_accounts.read().await,
+                *replayed_accounts.read().await
+            );
+        }
+        assert_invariants(&*loaded_accounts.read().await);
+        assert_invariants(&*replayed_accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_detects_tampered_ledger() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        for tx in [
+            Transaction::deposit(1, 1, dec!(5)),
+            Transaction::deposit(1, 2, dec!(1)),
+            Transaction::dispute(1, 1),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+        }
+        let reconcile = || async {
+            LedgerState::new(&*accounts.read().await, &*ledger.read().await).reconcile_held()
+        };
+        assert!(reconcile().await.is_empty());
+
+        // Marking a transaction as disputed behind the processor's back leaves its funds unheld
+        ledger.write().await.get_mut(&2).unwrap().dispute_state = DisputeState::Disputed;
+        let mismatches = reconcile().await;
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(
+            (mismatches[0].expected, mismatches[0].actual),
+            (dec!(6), dec!(5))
+        );
+        assert_eq!(mismatches[0].tx_ids, vec![1, 2]);
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_client_mismatch() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        processor
+            .process_transaction(Transaction::deposit(1, 1, dec!(5)))
+            .await
+            .unwrap();
+        processor
+            .process_transaction(Transaction::deposit(2, 2, dec!(5)))
+            .await
+            .unwrap();
+        for tx in [
+            Transaction::dispute(2, 1),
+            Transaction::resolve(2, 1),
+            Transaction::chargeback(2, 1),
+        ] {
+            assert!(matches!(
+                processor.process_transaction(tx).await,
+                Err(AccountingError::DisputeClientMismatch)
+            ));
+        }
+        // The owner's dispute is still accepted, but another client can't resolve or charge it back
+        processor
+            .process_transaction(Transaction::dispute(1, 1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::chargeback(2, 1))
+                .await,
+            Err(AccountingError::DisputeClientMismatch)
+        ));
+
+        let accounts_output = accounts.read().await;
+        assert_eq!(
+            accounts_output.get(&1),
+            Some(&Account::new(1, dec!(0), dec!(5), dec!(5)))
+        );
+        assert_eq!(
+            accounts_output.get(&2),
+            Some(&Account::new(2, dec!(5), dec!(0), dec!(5)))
+        );
+        assert!(ledger.read().await.get(&1).unwrap().under_dispute());
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_on_locked_account() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger, accounts.clone());
+        for transaction in [
+            Transaction::deposit(1, 1, dec!(5)),
+            Transaction::deposit(1, 2, dec!(3)),
+            Transaction::deposit(1, 3, dec!(2)),
+            // The first chargeback locks the account
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+            // The second fraudulent deposit can still be charged back, and another one resolved
+            Transaction::dispute(1, 2),
+            Transaction::chargeback(1, 2),
+            Transaction::dispute(1, 3),
+            Transaction::resolve(1, 3),
+        ] {
+            processor.process_transaction(transaction).await.unwrap();
+        }
+        // Money movements are still refused
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::deposit(1, 4, dec!(1)))
+                .await,
+            Err(AccountingError::AccountLocked)
+        ));
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::withdrawal(1, 5, dec!(1)))
+                .await,
+            Err(AccountingError::AccountLocked)
+        ));
+
+        let accounts_output = accounts.read().await;
+        let mut expected = Account::new(1, dec!(2), dec!(0), dec!(2));
+        expected.locked = true;
+        expected.chargebacks = 2;
+        assert_eq!(accounts_output.get(&1), Some(&expected));
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_interest() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        let processor: JoinHandle<TransactionProcessor> =
+            tokio::spawn(async move { processor.process().await });
+        sender.send(Transaction::deposit(1, 1, dec!(1000))).unwrap();
         sender
-            .send(Transaction::Dispute(TransactionData {
+            .send(Transaction::Interest(InterestData {
                 client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
+                rate: dec!(0.05),
+                period_days: 30,
             }))
             .unwrap();
+        // Zero balance accounts don't accrue anything
         sender
-            .send(Transaction::Resolve(TransactionData {
-                client_id: 1,
-                tx_id: 2,
-                amount: None,
-                under_dispute: false,
+            .send(Transaction::Interest(InterestData {
+                client_id: 2,
+                rate: dec!(0.05),
+                period_days: 30,
             }))
             .unwrap();
 
@@ -652,13 +1558,1048 @@ mod test {
         let accounts_output = accounts.read().await;
         let output = accounts_output
             .clone()
-            .into_iter()
-            .map(|(_, v)| v)
+            .into_values()
             .collect::<Vec<Account>>();
 
+        // 1000 * 0.05 * 30 / 365 = 4.10958904..., rounded to four places
         assert_eq!(
-            &Account::new(1u16, dec!(2.5), dec!(0), dec!(2.5)),
-            output.get(0).unwrap()
+            &Account::new(1u16, dec!(1004.1096), dec!(0), dec!(1004.1096)),
+            output.first().unwrap()
+        );
+        assert_eq!(
+            &Account::new(2u16, dec!(0), dec!(0), dec!(0)),
+            output.get(1).unwrap()
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[test]
+    fn test_apply_interest_rounding() {
+        let mut account = Account::new(1u16, dec!(100), dec!(50), dec!(150));
+        // 100 * 0.01 * 1 / 365 = 0.00273972..., interest is not paid on held funds
+        assert_eq!(account.apply_interest(dec!(0.01), 1).unwrap(), dec!(0.0027));
+        assert_eq!(
+            account,
+            Account::new(1u16, dec!(100.0027), dec!(50), dec!(150.0027))
+        );
+
+        let mut empty = Account::default();
+        assert_eq!(empty.apply_interest(dec!(0.05), 365).unwrap(), dec!(0));
+        assert_eq!(empty, Account::default());
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_all() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        processor
+            .process_transaction(Transaction::deposit(1, 1, dec!(3.3333)))
+            .await
+            .unwrap();
+        processor
+            .process_transaction(Transaction::withdrawal(1, 2, dec!(1.1111)))
+            .await
+            .unwrap();
+        processor
+            .process_transaction(Transaction::withdraw_all(1, 3))
+            .await
+            .unwrap();
+        // Nothing left to withdraw
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::withdraw_all(1, 4))
+                .await,
+            Err(AccountingError::NoFundsAvailable)
+        ));
+
+        assert_eq!(
+            ledger.read().await.get(&3).unwrap().amount,
+            Some(dec!(2.2222))
+        );
+        assert!(!ledger.read().await.contains_key(&4));
+        let accounts_output = accounts.read().await;
+        assert_eq!(
+            &Account::new(1u16, dec!(0), dec!(0), dec!(0)),
+            accounts_output.get(&1).unwrap()
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_compliance_block() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        let mut processor = processor.with_compliance(ComplianceChecker::new(vec![Box::new(
+            MaxTransactionAmount { limit: dec!(10) },
+        )]));
+        processor
+            .process_transaction(Transaction::deposit(1, 1, dec!(10)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::deposit(1, 2, dec!(11)))
+                .await,
+            Err(AccountingError::ComplianceBlock(_))
+        ));
+
+        // The blocked deposit is neither applied nor recorded in the ledger
+        assert!(!ledger.read().await.contains_key(&2));
+        let accounts_output = accounts.read().await;
+        assert_eq!(
+            &Account::new(1u16, dec!(10), dec!(0), dec!(10)),
+            accounts_output.get(&1).unwrap()
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_account_created_events() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let events = Arc::new(EventBus::new(16));
+        let mut subscriber = events.subscribe();
+        let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        let processor = processor.with_events(events.clone());
+        let processor: JoinHandle<TransactionProcessor> =
+            tokio::spawn(async move { processor.process().await });
+        sender.send(Transaction::deposit(1, 1, dec!(1.5))).unwrap();
+        sender.send(Transaction::deposit(1, 2, dec!(3))).unwrap();
+        // Rejected dispute for a client that has never deposited anything
+        sender.send(Transaction::dispute(2, 10)).unwrap();
+        sender.send(Transaction::deposit(2, 3, dec!(1))).unwrap();
+
+        drop(sender);
+        processor.await.unwrap();
+
+        assert_eq!(
+            subscriber.try_recv().unwrap(),
+            LedgerEvent::AccountCreated {
+                client: 1,
+                first_tx: 1
+            }
+        );
+        assert_eq!(
+            subscriber.try_recv().unwrap(),
+            LedgerEvent::AccountCreated {
+                client: 2,
+                first_tx: 3
+            }
+        );
+        assert!(subscriber.try_recv().is_err());
+        assert_eq!(events.accounts_created(), 2);
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_sequence_numbers_across_shards() {
+        let sequence = Arc::new(AtomicU64::new(0));
+        let mut shards = Vec::new();
+        let mut handles = Vec::new();
+        for shard in 0..2u16 {
+            let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+            let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+            let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+            let processor = processor.with_sequence(Some(sequence.clone()));
+            handles.push(tokio::spawn(async move { processor.process().await }));
+            for i in 0..50 {
+                let tx_id = shard as u32 * 1000 + i;
+                sender
+                    .send(Transaction::deposit(shard + 1, tx_id, dec!(1)))
+                    .unwrap();
+            }
+            // Rejected, so it doesn't get a number
+            sender
+                .send(Transaction::withdrawal(
+                    shard + 1,
+                    shard as u32 * 1000 + 50,
+                    dec!(100),
+                ))
+                .unwrap();
+            shards.push((ledger, accounts));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let mut all = BTreeSet::new();
+        for (ledger, accounts) in shards {
+            let ledger = ledger.read().await;
+            let seqs: Vec<Option<u64>> = ledger.values().map(|t| t.seq).collect();
+            assert_eq!(seqs.iter().filter(|seq| seq.is_some()).count(), 50);
+            // Each shard applies its transactions in tx id order, so their numbers increase with it
+            let seqs: Vec<u64> = seqs.into_iter().flatten().collect();
+            assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", seqs);
+            all.extend(seqs);
+            assert_invariants(&*accounts.read().await);
+        }
+        // Unique across shards, and without gaps here as nothing else used the sequence
+        assert_eq!(all, (1..=100).collect());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_processors_racing_on_shared_ledger() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let mut shards = Vec::new();
+        let mut handles = Vec::new();
+        for client in 1..=2u16 {
+            let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+            let (processor, sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+            handles.push(tokio::spawn(async move { processor.process().await }));
+            // Both processors use the same tx ids, only one deposit of each is recorded
+            for tx_id in 0..500 {
+                sender
+                    .send(Transaction::deposit(client, tx_id, dec!(1)))
+                    .unwrap();
+            }
+            // Disputes of the other client's deposits are rejected
+            for tx_id in 0..500 {
+                sender.send(Transaction::dispute(client, tx_id)).unwrap();
+            }
+            shards.push((client, accounts));
+        }
+        let mut applied = 0;
+        for handle in handles {
+            applied += handle.await.unwrap().stats().applied;
+        }
+
+        let ledger = ledger.read().await;
+        assert_eq!(ledger.len(), 500);
+        assert_eq!(applied, 1000);
+        for (client, accounts) in shards {
+            let accounts = accounts.read().await;
+            assert_invariants(&accounts);
+            // Every recorded deposit is in the balance of its client, and disputed once
+            let recorded = ledger.values().filter(|t| t.client_id == client).count();
+            assert!(ledger
+                .values()
+                .all(|t| t.dispute_state == DisputeState::Disputed));
+            let account = &accounts[&client];
+            assert_eq!(account.held(), Amount::from(recorded));
+            assert_eq!(account.available(), dec!(0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sequence_numbers_in_events() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let events = Arc::new(EventBus::new(16));
+        let mut subscriber = events.subscribe();
+        let (processor, _sender) = TransactionProcessor::new(Default::default(), accounts.clone());
+        let mut processor = processor
+            .with_events(events)
+            .with_sequence(Some(Arc::new(AtomicU64::new(41))));
+        for tx in [
+            Transaction::deposit(1, 1, dec!(5)),
+            Transaction::withdrawal(1, 2, dec!(50)),
+            Transaction::dispute(1, 1),
+        ] {
+            let _ = processor.process_transaction(tx).await;
+        }
+        let mut accepted = Vec::new();
+        while let Ok(event) = subscriber.try_recv() {
+            if let LedgerEvent::TransactionAccepted { tx, seq, .. } = event {
+                accepted.push((tx, seq));
+            }
+        }
+        assert_eq!(accepted, vec![(Some(1), 42), (Some(1), 43)]);
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_account_created_once_across_processors() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let events = Arc::new(EventBus::new(16));
+        let mut subscriber = events.subscribe();
+        for tx_id in 1..=2 {
+            let (processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+            let mut processor = processor.with_events(events.clone());
+            processor
+                .process_transaction(Transaction::deposit(1, tx_id, dec!(1)))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            subscriber.try_recv().unwrap(),
+            LedgerEvent::AccountCreated {
+                client: 1,
+                first_tx: 1
+            }
+        );
+        assert!(subscriber.try_recv().is_err());
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_watch_account_snapshots() {
+        let events = Arc::new(EventBus::new(16));
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, _sender) = TransactionProcessor::new(Default::default(), accounts.clone());
+        let mut processor = processor.with_events(events.clone());
+        let mut receiver = events.watch(1, None);
+        let mut snapshots = Vec::new();
+        for tx in [
+            Transaction::deposit(1, 1, dec!(5)),
+            Transaction::deposit(2, 2, dec!(1)),
+            // Rejected, so no snapshot
+            Transaction::withdrawal(1, 3, dec!(10)),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+        ] {
+            let _ = processor.process_transaction(tx).await;
+            if receiver.has_changed().unwrap() {
+                let snapshot = receiver.borrow_and_update();
+                snapshots.push((snapshot.available, snapshot.held, snapshot.locked));
+            }
+        }
+        assert_eq!(
+            snapshots,
+            vec![
+                (dec!(5), dec!(0), false),
+                (dec!(0), dec!(5), false),
+                (dec!(0), dec!(0), true),
+            ]
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_process_csv_file() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test_input.csv");
+        let stats = processor.process_csv_file(&path).await.unwrap();
+
+        assert_eq!(
+            stats,
+            ProcessingStats {
+                rejected: 2,
+                filtered: 0,
+                // The chargeback of tx 1 comes before its dispute
+                applied: 9,
+                failed: 3,
+                skipped_bytes: 0,
+                oversized: 0,
+                volume_limit_exceeded: 0,
+                // Timings vary from run to run
+                latency: stats.latency,
+                slow_transactions: 0,
+                withdrawals_suspended: 0,
+                volumes: VolumeAggregates {
+                    deposits: Volume {
+                        count: 4,
+                        total: dec!(1006.4566)
+                    },
+                    withdrawals: Volume {
+                        count: 1,
+                        total: dec!(1.5)
+                    },
+                    chargebacks: Volume {
+                        count: 1,
+                        total: dec!(999.9999)
+                    },
+                    net_flow: dec!(4.9567),
+                },
+            }
+        );
+        assert!(
+            LedgerState::new(&*accounts.read().await, &*ledger.read().await)
+                .reconcile_held()
+                .is_empty()
+        );
+        let accounts_output = accounts.read().await;
+        assert_eq!(
+            accounts_output.get(&1).unwrap(),
+            &Account::new(1u16, dec!(1.5), dec!(0), dec!(1.5))
+        );
+        assert!(accounts_output.get(&3).unwrap().locked);
+
+        let (processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        assert!(matches!(
+            processor
+                .process_csv_file(std::path::Path::new("does/not/exist.csv"))
+                .await,
+            Err(AccountingError::Input(..))
+        ));
+        assert_invariants(&*accounts.read().await);
+    }
+
+    fn deposit_volume_processor(
+        accounts: Arc<RwLock<BTreeMap<ClientID, Account>>>,
+        limit: DepositVolumeLimit,
+    ) -> (TransactionProcessor, broadcast::Receiver<LedgerEvent>) {
+        let events = Arc::new(EventBus::new(16));
+        let subscriber = events.subscribe();
+        let (processor, _sender) = TransactionProcessor::new(Default::default(), accounts);
+        let processor = processor
+            .with_events(events)
+            .with_deposit_volume_limit(Some(limit));
+        (processor, subscriber)
+    }
+
+    #[tokio::test]
+    async fn test_deposit_volume_limit_flags() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, mut subscriber) = deposit_volume_processor(
+            accounts.clone(),
+            DepositVolumeLimit {
+                limit: dec!(10),
+                enforce: false,
+            },
+        );
+        // Reaching the limit exactly doesn't exceed it
+        for (tx_id, amount) in [(1, dec!(4)), (2, dec!(6))] {
+            processor
+                .process_transaction(Transaction::deposit(1, tx_id, amount))
+                .await
+                .unwrap();
+        }
+        assert!(!accounts.read().await[&1].volume_flagged());
+
+        // Flagged accounts keep processing
+        for (tx_id, amount) in [(3, dec!(0.0001)), (4, dec!(5))] {
+            processor
+                .process_transaction(Transaction::deposit(1, tx_id, amount))
+                .await
+                .unwrap();
+        }
+        let account = accounts.read().await[&1].clone();
+        assert!(account.volume_flagged());
+        assert_eq!(account.total(), dec!(15.0001));
+        assert_eq!(processor.stats().volume_limit_exceeded, 1);
+
+        assert!(matches!(
+            subscriber.try_recv().unwrap(),
+            LedgerEvent::AccountCreated { client: 1, .. }
+        ));
+        assert_eq!(
+            subscriber.try_recv().unwrap(),
+            LedgerEvent::DepositVolumeExceeded { client: 1, tx: 3 }
+        );
+        assert!(subscriber.try_recv().is_err());
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_deposit_volume_limit_enforced() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _subscriber) = deposit_volume_processor(
+            accounts.clone(),
+            DepositVolumeLimit {
+                limit: dec!(10),
+                enforce: true,
+            },
+        );
+        for (tx_id, amount) in [(1, dec!(10)), (2, dec!(3))] {
+            processor
+                .process_transaction(Transaction::deposit(1, tx_id, amount))
+                .await
+                .unwrap();
+        }
+        // The deposit crossing the limit went through, later ones are rejected
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::deposit(1, 3, dec!(1)))
+                .await,
+            Err(AccountingError::DepositVolumeExceeded)
+        ));
+        // Other clients are unaffected
+        processor
+            .process_transaction(Transaction::deposit(2, 4, dec!(10)))
+            .await
+            .unwrap();
+
+        let accounts_output = accounts.read().await;
+        assert_eq!(accounts_output[&1].total(), dec!(13));
+        assert!(accounts_output[&1].volume_flagged);
+        assert!(!accounts_output[&2].volume_flagged);
+        assert_eq!(processor.stats().volume_limit_exceeded, 1);
+        assert_invariants(&*accounts.read().await);
+    }
+
+    // Stands in for a slow storage backend by delaying one transaction
+    struct Delay {
+        tx_id: TxID,
+        delay: Duration,
+    }
+
+    impl ComplianceRule for Delay {
+        fn check(&self, tx: &Transaction, _account: &Account) -> ComplianceResult {
+            if tx.tx_id() == Some(self.tx_id) {
+                std::thread::sleep(self.delay);
+            }
+            ComplianceResult::pass()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_denylist() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let events = Arc::new(EventBus::new(16));
+        let mut subscriber = events.subscribe();
+        let policy = Arc::new(RwLock::new(WithdrawalPolicy::Deny(BTreeSet::from([1]))));
+        let (processor, _sender) = TransactionProcessor::new(ledger, accounts.clone());
+        let mut processor = processor
+            .with_events(events)
+            .with_withdrawal_policy(policy.clone());
+
+        for tx in [
+            Transaction::deposit(1, 1, dec!(10)),
+            Transaction::deposit(2, 2, dec!(10)),
+            Transaction::withdrawal(2, 3, dec!(1)),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+        }
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::withdrawal(1, 4, dec!(1)))
+                .await,
+            Err(AccountingError::WithdrawalsSuspended)
+        ));
+        // Deposits and disputes of the client still go through, the account isn't locked
+        processor
+            .process_transaction(Transaction::deposit(1, 5, dec!(2)))
+            .await
+            .unwrap();
+        processor
+            .process_transaction(Transaction::dispute(1, 5))
+            .await
+            .unwrap();
+
+        // Client 2 is added to the list mid-stream
+        policy.write().await.clients_mut().insert(2);
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::withdraw_all(2, 6))
+                .await,
+            Err(AccountingError::WithdrawalsSuspended)
+        ));
+
+        assert_eq!(processor.stats().withdrawals_suspended, 2);
+        let suspended: Vec<LedgerEvent> = std::iter::from_fn(|| subscriber.try_recv().ok())
+            .filter(|event| matches!(event, LedgerEvent::WithdrawalSuspended { .. }))
+            .collect();
+        assert_eq!(
+            suspended,
+            vec![
+                LedgerEvent::WithdrawalSuspended { client: 1, tx: 4 },
+                LedgerEvent::WithdrawalSuspended { client: 2, tx: 6 },
+            ]
+        );
+        let accounts_output = accounts.read().await;
+        assert_eq!(
+            accounts_output.get(&1),
+            Some(&Account::new(1, dec!(10), dec!(2), dec!(12)))
+        );
+        assert_eq!(
+            accounts_output.get(&2),
+            Some(&Account::new(2, dec!(9), dec!(0), dec!(9)))
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    // Charges back each of the given deposits of client 1, returns the chargeback and lock events
+    async fn charge_back(
+        policy: ChargebackLockPolicy,
+        deposits: &[TxID],
+    ) -> (Account, Vec<LedgerEvent>) {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let events = Arc::new(EventBus::new(64));
+        let mut subscriber = events.subscribe();
+        let (processor, _sender) = TransactionProcessor::new(Default::default(), accounts.clone());
+        let mut processor = processor
+            .with_events(events)
+            .with_chargeback_lock_policy(policy);
+        for tx_id in 1..=3 {
+            processor
+                .process_transaction(Transaction::deposit(1, tx_id, dec!(5)))
+                .await
+                .unwrap();
+        }
+        for tx_id in deposits {
+            processor
+                .process_transaction(Transaction::dispute(1, *tx_id))
+                .await
+                .unwrap();
+            processor
+                .process_transaction(Transaction::chargeback(1, *tx_id))
+                .await
+                .unwrap();
+        }
+        let events = std::iter::from_fn(|| subscriber.try_recv().ok())
+            .filter(|event| {
+                matches!(
+                    event,
+                    LedgerEvent::ChargebackApplied { .. } | LedgerEvent::AccountLocked { .. }
+                )
+            })
+            .collect();
+        assert_invariants(&*accounts.read().await);
+        let account = accounts.read().await[&1].clone();
+        (account, events)
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_locks_by_default() {
+        let (account, events) = charge_back(ChargebackLockPolicy::default(), &[1]).await;
+        assert!(account.locked);
+        assert_eq!(
+            events,
+            vec![
+                LedgerEvent::ChargebackApplied { client: 1, tx: 1 },
+                LedgerEvent::AccountLocked { client: 1, tx: 1 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_lock_threshold() {
+        let policy = ChargebackLockPolicy::Threshold { count: 2 };
+        // The first chargeback leaves the account open, so it still takes deposits
+        let (account, events) = charge_back(policy, &[1]).await;
+        assert!(!account.locked);
+        assert_eq!(account.chargebacks(), 1);
+        assert_eq!(account.total(), dec!(10));
+        assert_eq!(
+            events,
+            vec![LedgerEvent::ChargebackApplied { client: 1, tx: 1 }]
+        );
+
+        // The second one locks it, later chargebacks don't lock it again
+        let (account, events) = charge_back(policy, &[1, 2, 3]).await;
+        assert!(account.locked);
+        assert_eq!(account.chargebacks(), 3);
+        assert_eq!(
+            events,
+            vec![
+                LedgerEvent::ChargebackApplied { client: 1, tx: 1 },
+                LedgerEvent::ChargebackApplied { client: 1, tx: 2 },
+                LedgerEvent::AccountLocked { client: 1, tx: 2 },
+                LedgerEvent::ChargebackApplied { client: 1, tx: 3 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_allowlist() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, _sender) = TransactionProcessor::new(ledger, accounts.clone());
+        let mut processor = processor.with_withdrawal_policy(Arc::new(RwLock::new(
+            WithdrawalPolicy::Allow(BTreeSet::from([2])),
+        )));
+        for tx in [
+            Transaction::deposit(1, 1, dec!(10)),
+            Transaction::deposit(2, 2, dec!(10)),
+            Transaction::withdrawal(2, 3, dec!(1)),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+        }
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::withdrawal(1, 4, dec!(1)))
+                .await,
+            Err(AccountingError::WithdrawalsSuspended)
+        ));
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_slow_transactions() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, sender) = TransactionProcessor::new(ledger, accounts.clone());
+        let processor = processor
+            .with_compliance(ComplianceChecker::new(vec![Box::new(Delay {
+                tx_id: 2,
+                delay: Duration::from_millis(40),
+            })]))
+            .with_slow_tx_threshold(Some(Duration::from_millis(20)));
+        for tx_id in 1..=3 {
+            sender
+                .send(Transaction::deposit(1, tx_id, dec!(1)))
+                .unwrap();
+        }
+        drop(sender);
+        let stats = processor.process().await.stats();
+
+        assert_eq!(stats.slow_transactions, 1);
+        assert_eq!(stats.latency.count(), 3);
+        // The delayed transaction lands in a bucket of at least 32ms, the others well below
+        let slow: u64 = stats
+            .latency
+            .buckets()
+            .filter(|(lower, _)| *lower >= Duration::from_micros(1 << 15))
+            .map(|(_, count)| count)
+            .sum();
+        assert_eq!(slow, 1);
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_two_phase_withdrawal_settle() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        let mut processor = processor.with_two_phase_withdrawals(true);
+        processor
+            .process_transaction(Transaction::deposit(1, 1, dec!(10)))
+            .await
+            .unwrap();
+        processor
+            .process_transaction(Transaction::withdrawal(1, 2, dec!(4)))
+            .await
+            .unwrap();
+        {
+            let accounts_output = accounts.read().await;
+            let account = accounts_output.get(&1).unwrap();
+            assert_eq!(
+                (account.available(), account.pending_out(), account.total()),
+                (dec!(6), dec!(4), dec!(10))
+            );
+            assert!(ledger.read().await.get(&2).unwrap().pending_settlement);
+        }
+
+        processor
+            .process_transaction(Transaction::settle(1, 2))
+            .await
+            .unwrap();
+        // Settling twice must not take the funds out of the account again
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::settle(1, 2))
+                .await,
+            Err(AccountingError::SettlementNotPending)
+        ));
+        // Neither can a deposit be settled
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::settle(1, 1))
+                .await,
+            Err(AccountingError::SettlementNotPending)
+        ));
+
+        let accounts_output = accounts.read().await;
+        let account = accounts_output.get(&1).unwrap();
+        assert_eq!(account, &Account::new(1u16, dec!(6), dec!(0), dec!(6)));
+        assert!(!ledger.read().await.get(&2).unwrap().pending_settlement);
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_two_phase_withdrawal_cancel() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        let mut processor = processor.with_two_phase_withdrawals(true);
+        processor
+            .process_transaction(Transaction::deposit(1, 1, dec!(10)))
+            .await
+            .unwrap();
+        processor
+            .process_transaction(Transaction::withdrawal(1, 2, dec!(4)))
+            .await
+            .unwrap();
+        // Pending withdrawals can't be disputed, only cancelled
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::dispute(1, 2))
+                .await,
+            Err(AccountingError::DisputePendingWithdrawal)
+        ));
+        processor
+            .process_transaction(Transaction::cancel(1, 2))
+            .await
+            .unwrap();
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::settle(1, 2))
+                .await,
+            Err(AccountingError::SettlementNotPending)
+        ));
+
+        let accounts_output = accounts.read().await;
+        let account = accounts_output.get(&1).unwrap();
+        assert_eq!(account, &Account::new(1u16, dec!(10), dec!(0), dec!(10)));
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_settle_and_cancel_of_another_client() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        let mut processor = processor.with_two_phase_withdrawals(true);
+        for tx in [
+            Transaction::deposit(1, 1, dec!(10)),
+            Transaction::deposit(2, 2, dec!(100)),
+            Transaction::withdrawal(1, 3, dec!(10)),
+            Transaction::withdrawal(2, 4, dec!(50)),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+        }
+        // Client 2 can neither release nor take out the withdrawal of client 1
+        for tx in [Transaction::cancel(2, 3), Transaction::settle(2, 3)] {
+            assert!(matches!(
+                processor.process_transaction(tx).await,
+                Err(AccountingError::SettlementClientMismatch)
+            ));
+        }
+        assert!(ledger.read().await.get(&3).unwrap().pending_settlement);
+        {
+            let accounts_output = accounts.read().await;
+            let (first, second) = (&accounts_output[&1], &accounts_output[&2]);
+            assert_eq!(
+                (first.available(), first.pending_out(), first.total()),
+                (dec!(0), dec!(10), dec!(10))
+            );
+            assert_eq!(
+                (second.available(), second.pending_out(), second.total()),
+                (dec!(50), dec!(50), dec!(100))
+            );
+        }
+
+        // The withdrawal is still pending, so its own client can settle it
+        processor
+            .process_transaction(Transaction::settle(1, 3))
+            .await
+            .unwrap();
+        processor
+            .process_transaction(Transaction::cancel(2, 4))
+            .await
+            .unwrap();
+        let accounts_output = accounts.read().await;
+        assert_eq!(
+            accounts_output[&1],
+            Account::new(1u16, dec!(0), dec!(0), dec!(0))
+        );
+        assert_eq!(
+            accounts_output[&2],
+            Account::new(2u16, dec!(100), dec!(0), dec!(100))
+        );
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_settle_and_cancel_of_unknown_transaction() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, sender) = TransactionProcessor::new(Default::default(), accounts.clone());
+        let processor = processor.with_two_phase_withdrawals(true);
+        for tx in [
+            Transaction::deposit(1, 1, dec!(10)),
+            Transaction::withdrawal(1, 2, dec!(4)),
+            // A withdrawal that was never recorded can't be settled or cancelled
+            Transaction::settle(1, 3),
+            Transaction::cancel(1, 4),
+        ] {
+            sender.send(tx).unwrap();
+        }
+        drop(sender);
+        let stats = processor.process().await.stats();
+
+        assert_eq!((stats.applied, stats.failed), (2, 2));
+        assert_eq!(
+            accounts.read().await[&1].pending_out(),
+            dec!(4),
+            "the pending withdrawal is left alone"
+        );
+        assert_invariants(&*accounts.read().await);
+
+        let (mut processor, _sender) =
+            TransactionProcessor::new(Default::default(), Default::default());
+        for tx in [Transaction::settle(1, 3), Transaction::cancel(1, 3)] {
+            assert!(matches!(
+                processor.process_transaction(tx).await,
+                Err(AccountingError::TransactionNotFound(3))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bounded_channel_backpressure() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, sender) = TransactionProcessorBuilder::new()
+            .channel_capacity(Some(2))
+            .build(Default::default(), accounts.clone());
+        assert!(matches!(sender, TransactionSender::Bounded(_)));
+        for tx_id in 1..=2 {
+            sender
+                .send(Transaction::deposit(1, tx_id, dec!(1)))
+                .await
+                .unwrap();
+        }
+        let processor = {
+            // The channel is full until the processor starts taking transactions out of it
+            let third = sender.send(Transaction::deposit(1, 3, dec!(1)));
+            tokio::pin!(third);
+            assert!(tokio::time::timeout(Duration::from_millis(50), &mut third)
+                .await
+                .is_err());
+            let processor = tokio::spawn(processor.process());
+            third.await.unwrap();
+            processor
+        };
+        drop(sender);
+        let processor = processor.await.unwrap();
+        assert_eq!(processor.stats().applied, 3);
+        assert_eq!(accounts.read().await[&1].total(), dec!(3));
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, sender) = TransactionProcessorBuilder::new()
+            .idle_timeout(Duration::from_millis(50))
+            .build(Default::default(), accounts.clone());
+        assert!(matches!(sender, TransactionSender::Unbounded(_)));
+        let processor = tokio::spawn(processor.process());
+        sender
+            .send(Transaction::deposit(1, 1, dec!(1)))
+            .await
+            .unwrap();
+        // Returns although the sender is still around
+        let processor = tokio::time::timeout(Duration::from_secs(5), processor)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(processor.stats().applied, 1);
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_volume_aggregates() {
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (processor, _sender) = TransactionProcessor::new(Default::default(), accounts.clone());
+        let mut processor = processor.with_two_phase_withdrawals(true);
+        for tx in [
+            Transaction::deposit(1, 1, dec!(10)),
+            Transaction::deposit(1, 2, dec!(5)),
+            Transaction::withdrawal(1, 3, dec!(4)),
+            Transaction::settle(1, 3),
+            // Cancelled, so the funds never leave the account
+            Transaction::withdrawal(1, 4, dec!(1)),
+            Transaction::cancel(1, 4),
+            Transaction::dispute(1, 2),
+            Transaction::chargeback(1, 2),
+            Transaction::dispute(1, 3),
+            Transaction::chargeback(1, 3),
+        ] {
+            processor.process_transaction(tx).await.unwrap();
+        }
+        // Failed transactions aren't counted
+        assert!(processor
+            .process_transaction(Transaction::chargeback(1, 1))
+            .await
+            .is_err());
+
+        let volumes = processor.stats().volumes;
+        assert_eq!(
+            (volumes.deposits.count, volumes.deposits.total),
+            (2, dec!(15))
+        );
+        assert_eq!(
+            (volumes.withdrawals.count, volumes.withdrawals.total),
+            (1, dec!(4))
+        );
+        assert_eq!(
+            (volumes.chargebacks.count, volumes.chargebacks.total),
+            (2, dec!(9))
+        );
+        // The charged back deposit leaves the account, the charged back withdrawal returns to it
+        assert_eq!(volumes.net_flow, dec!(10));
+        assert_eq!(accounts.read().await[&1].total(), volumes.net_flow);
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[tokio::test]
+    async fn test_single_phase_withdrawal_by_default() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        processor
+            .process_transaction(Transaction::deposit(1, 1, dec!(10)))
+            .await
+            .unwrap();
+        processor
+            .process_transaction(Transaction::withdrawal(1, 2, dec!(4)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::settle(1, 2))
+                .await,
+            Err(AccountingError::SettlementNotPending)
+        ));
+
+        let accounts_output = accounts.read().await;
+        let account = accounts_output.get(&1).unwrap();
+        assert_eq!(account, &Account::new(1u16, dec!(6), dec!(0), dec!(6)));
+        assert_invariants(&*accounts.read().await);
+    }
+
+    #[cfg(feature = "multi-currency")]
+    #[tokio::test]
+    async fn test_multi_currency_balances() {
+        let ledger: Arc<RwLock<BTreeMap<TxID, TransactionData>>> = Default::default();
+        let accounts: Arc<RwLock<BTreeMap<ClientID, Account>>> = Default::default();
+        let (mut processor, _sender) = TransactionProcessor::new(ledger.clone(), accounts.clone());
+        let usd = Some(CurrencyCode(*b"USD"));
+        let eur = Some(CurrencyCode(*b"EUR"));
+        for (tx_id, amount, currency) in [(1, dec!(10), usd), (2, dec!(5), None), (3, dec!(3), eur)]
+        {
+            processor
+                .process_transaction(Transaction::deposit(1, tx_id, amount).in_currency(currency))
+                .await
+                .unwrap();
+        }
+        // Only 3 EUR are available even though the account holds more in other currencies
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::withdrawal(1, 4, dec!(4)).in_currency(eur))
+                .await,
+            Err(AccountingError::Withdrawal)
+        ));
+        assert!(matches!(
+            processor
+                .process_transaction(Transaction::dispute(1, 1).in_currency(eur))
+                .await,
+            Err(AccountingError::CurrencyMismatch)
+        ));
+        // Dispute rows without a currency follow the disputed transaction
+        processor
+            .process_transaction(Transaction::dispute(1, 1))
+            .await
+            .unwrap();
+
+        let accounts_output = accounts.read().await;
+        let account = accounts_output.get(&1).unwrap();
+        assert_eq!((account.available(), account.total()), (dec!(5), dec!(5)));
+        assert_eq!(
+            account.balances.get(&CurrencyCode(*b"USD")),
+            Some(&CurrencyBalance {
+                available: dec!(0),
+                held: dec!(10),
+                total: dec!(10),
+                pending_out: dec!(0)
+            })
+        );
+        assert_eq!(
+            account.balances.get(&CurrencyCode(*b"EUR")),
+            Some(&CurrencyBalance {
+                available: dec!(3),
+                held: dec!(0),
+                total: dec!(3),
+                pending_out: dec!(0)
+            })
         );
+        assert_invariants(&*accounts.read().await);
     }
 }
@@ -0,0 +1,102 @@
+// Runs the binary on a named pipe, which is read as a stream while another process writes to it
+#![cfg(all(unix, feature = "serde"))]
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+const INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+dispute, 1, 1,
+withdrawal, 1, 4, 1.5
+withdrawal, 2, 5, 3.0
+resolve, 1, 1,
+";
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("transactron_fifo_{}_{}", name, std::process::id()))
+}
+
+// The report and the warnings written to stderr
+fn run(path: &Path) -> (String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_transactron"))
+        .arg(path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+// Writes the input to a new fifo a few lines at a time, so the reader has to wait for more data
+fn run_fifo(name: &str, input: &'static str) -> (String, String) {
+    let fifo = temp_path(name);
+    let _ = std::fs::remove_file(&fifo);
+    assert!(Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .unwrap()
+        .success());
+    let writer = {
+        let fifo = fifo.clone();
+        thread::spawn(move || {
+            // Blocks until the reader has opened the other end
+            let mut pipe = std::fs::OpenOptions::new().write(true).open(fifo).unwrap();
+            for chunk in input.as_bytes().chunks(16) {
+                pipe.write_all(chunk).unwrap();
+                thread::sleep(Duration::from_millis(5));
+            }
+        })
+    };
+    let output = run(&fifo);
+    writer.join().unwrap();
+    std::fs::remove_file(&fifo).unwrap();
+    output
+}
+
+#[test]
+fn test_fifo_matches_regular_file() {
+    let file = temp_path("regular.csv");
+    std::fs::write(&file, INPUT).unwrap();
+    let (expected, _) = run(&file);
+    std::fs::remove_file(&file).unwrap();
+
+    assert_eq!(run_fifo("plain", INPUT).0, expected);
+}
+
+#[test]
+fn test_fifo_rejects_corrupted_rows_without_seeking() {
+    // Three bad rows in a row trigger a resync, which works from the bytes already read rather than
+    // seeking back in the fifo
+    let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+\u{0}\u{1}garbage
+more garbage
+and more
+deposit, 1, 2, 2.0
+";
+    let (output, warnings) = run_fifo("corrupted", input);
+    assert_eq!(
+        output,
+        "client,available,held,total,locked
+1,3.0000,0.0000,3.0000,false
+"
+    );
+    assert!(
+        warnings.contains("3 records rejected with unknown_transaction_type"),
+        "{}",
+        warnings
+    );
+    assert!(!warnings.contains("seek"), "{}", warnings);
+    assert!(
+        warnings.contains("skipped corrupted input at bytes"),
+        "{}",
+        warnings
+    );
+}
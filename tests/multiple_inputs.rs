@@ -0,0 +1,62 @@
+// Runs the binary with several input files, which share the ledger and the accounts
+#![cfg(feature = "serde")]
+
+use std::path::PathBuf;
+use std::process::Command;
+
+fn write_input(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "transactron_multiple_inputs_{}_{}.csv",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn run(args: &[&PathBuf]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_transactron"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_dispute_references_deposit_of_earlier_file() {
+    let first = write_input(
+        "first",
+        "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 3.0
+",
+    );
+    let second = write_input(
+        "second",
+        "type, client, tx, amount
+deposit, 1, 3, 1.0
+dispute, 1, 1,
+withdrawal, 2, 4, 1.0
+",
+    );
+
+    assert_eq!(
+        run(&[&first, &second]),
+        "client,available,held,total,locked
+1,1.0000,5.0000,6.0000,false
+2,2.0000,0.0000,2.0000,false
+"
+    );
+    // In the other order the dispute comes before the deposit it references and is rejected
+    assert_eq!(
+        run(&[&second, &first]),
+        "client,available,held,total,locked
+1,6.0000,0.0000,6.0000,false
+2,3.0000,0.0000,3.0000,false
+"
+    );
+
+    std::fs::remove_file(first).unwrap();
+    std::fs::remove_file(second).unwrap();
+}
@@ -0,0 +1,72 @@
+// Runs the binary with each --output-format and compares the reports
+#![cfg(feature = "serde")]
+
+use std::path::Path;
+use std::process::Command;
+
+const INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+deposit, 3, 5, 4.25
+dispute, 3, 5,
+deposit, 4, 6, 3.0
+dispute, 4, 6,
+chargeback, 4, 6,
+";
+
+fn run(path: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_transactron"))
+        .arg(path)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// The csv rows as json objects, with the same field names and values as the json report
+fn csv_as_json(csv: &str) -> Vec<serde_json::Value> {
+    let mut lines = csv.lines();
+    let header: Vec<&str> = lines.next().unwrap().split(',').collect();
+    lines
+        .map(|line| {
+            let fields = header.iter().zip(line.split(',')).map(|(name, value)| {
+                let value = match *name {
+                    "client" => serde_json::json!(value.parse::<u16>().unwrap()),
+                    "locked" => serde_json::json!(value.parse::<bool>().unwrap()),
+                    _ => serde_json::json!(value),
+                };
+                (name.to_string(), value)
+            });
+            serde_json::Value::Object(fields.collect())
+        })
+        .collect()
+}
+
+#[test]
+fn test_json_output_matches_csv() {
+    let path = std::env::temp_dir().join(format!(
+        "transactron_output_format_{}.csv",
+        std::process::id()
+    ));
+    std::fs::write(&path, INPUT).unwrap();
+    let expected = csv_as_json(&run(&path, &[]));
+    assert_eq!(expected.len(), 4);
+
+    let json: serde_json::Value =
+        serde_json::from_str(&run(&path, &["--output-format", "json"])).unwrap();
+    assert_eq!(json, serde_json::Value::Array(expected.clone()));
+
+    let json_lines: Vec<serde_json::Value> = run(&path, &["--output-format", "jsonl"])
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(json_lines, expected);
+
+    // Spot check a disputed and a locked account
+    assert_eq!(json[2]["held"], "4.2500");
+    assert_eq!(json[3]["locked"], true);
+    std::fs::remove_file(&path).unwrap();
+}
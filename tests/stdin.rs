@@ -0,0 +1,63 @@
+// Runs the binary with the csv piped to stdin instead of a path
+#![cfg(feature = "serde")]
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+fn run_with_stdin(args: &[&str], input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_transactron"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+const INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+withdrawal, 2, 5, 3.0
+";
+
+const EXPECTED: &str = "client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false
+";
+
+#[test]
+fn test_reads_stdin_without_a_path() {
+    assert_eq!(run_with_stdin(&[], INPUT), EXPECTED);
+}
+
+#[test]
+fn test_reads_stdin_with_dash() {
+    assert_eq!(run_with_stdin(&["-"], INPUT), EXPECTED);
+}
+
+#[test]
+fn test_unknown_flag_is_an_error() {
+    let output = Command::new(env!("CARGO_BIN_EXE_transactron"))
+        .args(["--two-phase-withdrawls"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("unknown flag --two-phase-withdrawls"),
+        "{}",
+        stderr
+    );
+}